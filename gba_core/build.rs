@@ -61,7 +61,7 @@ fn decode_arm_entry(inst: u32) -> String {
                 (0b0000, 0b00 | 0b10) => format!("arm_exec_mrs::<{R}>", R = inst.bit(22)),
                 (0b0000, 0b01 | 0b11) => format!("arm_exec_msr::<{R}, false>", R = inst.bit(22)),
                 (0b0001, 0b01) => format!("arm_exec_branch_exchange"),
-                _ => "arm_unimplemented".to_string(),
+                _ => "arm_undefined".to_string(),
             }
         }
         0b001 if (inst.bit_range(20..25) & 0b11011) == 0b10010 => {
@@ -72,6 +72,10 @@ fn decode_arm_entry(inst: u32) -> String {
             // Branch, Branch-and-link.
             format!("arm_exec_branch::<{LINK}>", LINK = inst.bit(24))
         }
+        0b011 if inst.bit(4) => {
+            // Architecturally undefined instruction.
+            "arm_undefined".to_string()
+        }
         0b010 | 0b011 => {
             // Load and Store word or unsigned byte.
             format!(
@@ -100,7 +104,7 @@ fn decode_arm_entry(inst: u32) -> String {
             // Software interrupt.
             "arm_exec_swi".to_string()
         }
-        _ => "arm_unimplemented".to_string(),
+        _ => "arm_undefined".to_string(),
     }
 }
 
@@ -181,7 +185,7 @@ fn decode_thumb_entry(inst: u16) -> String {
         let middle = inst.bit_range(8..12);
         match middle {
             // Undefined instruction.
-            0b1110 => "thumb_unimplemented".to_string(),
+            0b1110 => "thumb_undefined".to_string(),
             // THUMB.17: software interrupt
             0b1111 => "thumb_exec_swi".to_string(),
             // THUMB.16: conditional branch
@@ -204,7 +208,7 @@ fn decode_thumb_entry(inst: u16) -> String {
         // THUMB.19: branch and link
         format!("thumb_exec_branch_link::<{SUFFIX}>", SUFFIX = inst.bit(11))
     } else {
-        "thumb_unimplemented".to_string()
+        "thumb_undefined".to_string()
     }
 }
 
@@ -0,0 +1,57 @@
+//! Compare the speed of the regular CPU interpreter and the cached interpreter.
+//!
+//! Usage: cargo run --release --example cpu_benchmark -- <BIOS> <ROM> [FRAMES]
+//!
+//! Runs the ROM headless (skipping the BIOS) for the given number of frames with each
+//! interpreter, and checks that both end up in the same state.
+
+use std::{env, fs, time::Instant};
+
+use gba_core::{Gba, Rom};
+
+/// Run the ROM for the given number of frames, returning the final state and the time taken.
+fn run(bios: &[u8], rom_data: &[u8], frames: usize, cached: bool) -> (Vec<u8>, f64) {
+    let mut gba = Gba::builder(bios.into(), Rom::new(rom_data))
+        .skip_bios(true)
+        .cached_interpreter(cached)
+        .build();
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        gba.emulate_frame(false);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    (gba.save_state(), elapsed)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <BIOS> <ROM> [FRAMES]", args[0]);
+        std::process::exit(1);
+    }
+    let bios = fs::read(&args[1]).expect("failed to read BIOS");
+    let rom_data = fs::read(&args[2]).expect("failed to read ROM");
+    let frames = args
+        .get(3)
+        .map(|frames| frames.parse().expect("invalid frame count"))
+        .unwrap_or(3600);
+
+    let (regular_state, regular_time) = run(&bios, &rom_data, frames, false);
+    let (cached_state, cached_time) = run(&bios, &rom_data, frames, true);
+
+    for (name, time) in [("regular", regular_time), ("cached", cached_time)] {
+        println!(
+            "{:>8}: {:.3} s ({:.1} frames/s)",
+            name,
+            time,
+            frames as f64 / time
+        );
+    }
+    println!("Speedup: {:.2}x", regular_time / cached_time);
+
+    if regular_state != cached_state {
+        eprintln!("Error: the interpreters ended in different states");
+        std::process::exit(1);
+    }
+}
@@ -2,10 +2,18 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::{cpu::CpuExecutionState, io::WaitControl, Addr, Gba, Memory};
+use crate::{
+    cpu::CpuExecutionState,
+    io::{MemoryControl, WaitControl},
+    ppu::vram_offset,
+    Addr, Gba, Memory,
+};
 
 const BIOS_SIZE: u32 = 0x4000;
 
+/// Number of halfwords the Game Pak prefetch buffer holds.
+const PREFETCH_CAPACITY: usize = 8;
+
 /// State for the system memory bus.
 #[derive(Serialize, Deserialize)]
 pub struct Bus {
@@ -14,8 +22,52 @@ pub struct Bus {
     wait_s32: [usize; 16],
     wait_n32: [usize; 16],
 
-    /// Last successfully loaded 32-bit data from BIOS.
-    bios_last_load: u32,
+    /// Last opcode fetched from the BIOS (returned by BIOS reads from outside the BIOS).
+    bios_last_fetch: u32,
+
+    /// Game Pak prefetch buffer state.
+    prefetch: Prefetch,
+
+    /// Last value put on the bus by a data access (from the CPU or DMA).
+    data_value: u32,
+
+    /// Whether DMA is using the bus.
+    dma_running: bool,
+
+    /// The PC when the last DMA finished. The instruction the CPU executes next still sees
+    /// the last value DMA put on the bus.
+    dma_end_pc: Option<u32>,
+}
+
+/// Offset of the last data value in [`Bus`], for native code.
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub(crate) const BUS_DATA_VALUE: usize = std::mem::offset_of!(Bus, data_value);
+
+/// State of the Game Pak prefetch buffer.
+///
+/// While the Game Pak bus is otherwise idle, the buffer fetches the halfwords following the
+/// last code fetched from ROM. Code fetches that hit the buffer take a single cycle.
+///
+/// This hasn't been checked against timing test ROMs yet (see the `timing_test_rom` test).
+#[derive(Default, Serialize, Deserialize)]
+struct Prefetch {
+    /// Whether prefetching is enabled (in WAITCNT).
+    enabled: bool,
+
+    /// Whether the buffer is running. It stops on Game Pak data accesses.
+    active: bool,
+
+    /// Address of the first halfword in the buffer (the next code fetch it can serve).
+    head: Addr,
+
+    /// Number of halfwords in the buffer.
+    count: usize,
+
+    /// Cycles until the halfword currently being fetched arrives.
+    countdown: usize,
+
+    /// Whether DMA has the bus. The buffer can't fetch then (but keeps what it has).
+    paused: bool,
 }
 
 /// Memory access types.
@@ -62,7 +114,11 @@ impl Bus {
             wait_n16: [1; 16],
             wait_s32: [1; 16],
             wait_n32: [1; 16],
-            bios_last_load: 0,
+            bios_last_fetch: 0,
+            prefetch: Prefetch::default(),
+            data_value: 0,
+            dma_running: false,
+            dma_end_pc: None,
         };
 
         bus.wait_s16[REGION_BIOS as usize] = 1;
@@ -80,6 +136,7 @@ impl Bus {
         bus.wait_s32[REGION_IO as usize] = 1;
         bus.wait_n32[REGION_IO as usize] = 1;
 
+        // VRAM and palette have a 16-bit bus: 32-bit accesses take two cycles.
         bus.wait_s16[REGION_VRAM as usize] = 1;
         bus.wait_n16[REGION_VRAM as usize] = 1;
         bus.wait_s32[REGION_VRAM as usize] = 2;
@@ -90,20 +147,32 @@ impl Bus {
         bus.wait_s32[REGION_OAM as usize] = 1;
         bus.wait_n32[REGION_OAM as usize] = 1;
 
-        bus.wait_s16[REGION_EWRAM as usize] = 3;
-        bus.wait_n16[REGION_EWRAM as usize] = 3;
-        bus.wait_s32[REGION_EWRAM as usize] = 6;
-        bus.wait_n32[REGION_EWRAM as usize] = 6;
-
         bus.wait_s16[REGION_PALETTE as usize] = 1;
         bus.wait_n16[REGION_PALETTE as usize] = 1;
         bus.wait_s32[REGION_PALETTE as usize] = 2;
         bus.wait_n32[REGION_PALETTE as usize] = 2;
 
         bus.update_waitcnt(WaitControl(0));
+        bus.update_memcnt(MemoryControl::default());
         bus
     }
 
+    /// Set the state as if the BIOS had run and jumped to the cartridge.
+    pub fn skip_bios(&mut self) {
+        // The opcode after the BIOS's jump to the cartridge.
+        self.bios_last_fetch = 0xE129_F000;
+    }
+
+    /// Update cycle timing tables after the internal memory control register is updated.
+    pub(crate) fn update_memcnt(&mut self, memcnt: MemoryControl) {
+        // EWRAM has a 16-bit bus.
+        let cycles = 1 + memcnt.ewram_waitstates();
+        self.wait_s16[REGION_EWRAM as usize] = cycles;
+        self.wait_n16[REGION_EWRAM as usize] = cycles;
+        self.wait_s32[REGION_EWRAM as usize] = 2 * cycles;
+        self.wait_n32[REGION_EWRAM as usize] = 2 * cycles;
+    }
+
     /// Update cycle timing tables after WAITCNT is updated.
     pub(crate) fn update_waitcnt(&mut self, waitcnt: WaitControl) {
         let ws0_n = [4, 3, 2, 8][waitcnt.ws0_nonsequential() as usize];
@@ -114,16 +183,12 @@ impl Bus {
         let ws2_s = [8, 1][waitcnt.ws2_sequential() as usize];
 
         let sram = [4, 3, 2, 8][waitcnt.sram() as usize];
-        let wait_n;
-        let wait_s;
-        if waitcnt.prefetch() {
-            // Approximate prefetch buffer by removing all waits.
-            // TODO: more accurate handling of prefetch buffer.
-            wait_n = [0, 0, 0];
-            wait_s = [0, 0, 0];
-        } else {
-            wait_n = [ws0_n, ws1_n, ws2_n];
-            wait_s = [ws0_s, ws1_s, ws2_s];
+        let wait_n = [ws0_n, ws1_n, ws2_n];
+        let wait_s = [ws0_s, ws1_s, ws2_s];
+
+        self.prefetch.enabled = waitcnt.prefetch();
+        if !self.prefetch.enabled {
+            self.prefetch.active = false;
         }
 
         for region in REGION_CART_WS0_A..=REGION_CART_WS2_B {
@@ -142,12 +207,164 @@ impl Bus {
     }
 }
 
+/// Whether the region is Game Pak ROM.
+#[inline(always)]
+fn is_rom_region(region: u32) -> bool {
+    (REGION_CART_WS0_A..=REGION_CART_WS2_B).contains(&region)
+}
+
 impl Gba {
-    /// Add cycles for the memory read.
-    fn add_cycles(&mut self, region: u32, size: MemoryAccessSize, access: MemoryAccessType) {
+    /// Add cycles for a memory access (not a code fetch).
+    fn add_cycles(&mut self, addr: Addr, size: MemoryAccessSize, access: MemoryAccessType) {
+        let region = region_from_address(addr);
+        let mut cycles = self.access_cycles(region, size, access);
+        if (REGION_PALETTE..=REGION_OAM).contains(&region) && self.ppu_memory_busy(addr) {
+            // Wait for the PPU to finish its access.
+            // VRAM and palette have a 16-bit bus, so 32-bit accesses collide twice.
+            cycles += match (region, size) {
+                (REGION_PALETTE | REGION_VRAM, MemoryAccessSize::Mem32) => 2,
+                _ => 1,
+            };
+        }
+        self.scheduler.update(cycles);
+        if is_rom_region(region) {
+            // Non-sequential ROM data accesses move the Game Pak bus elsewhere, which
+            // discards the buffer. Sequential ones continue a burst that already did.
+            if access == MemoryAccessType::NonSequential {
+                self.bus.prefetch.active = false;
+            }
+        } else if !(REGION_SRAM..=REGION_CART_UNUSED).contains(&region) {
+            // SRAM is also on the Game Pak bus, so only other accesses let the buffer fetch.
+            self.bus_idle_cycles(cycles);
+        }
+    }
+
+    /// Set whether DMA has the bus. While it does, the prefetch buffer doesn't fetch.
+    pub(crate) fn bus_set_dma_running(&mut self, running: bool) {
+        self.bus.prefetch.paused = running;
+        self.bus.dma_running = running;
+        if !running {
+            self.bus.dma_end_pc = Some(self.cpu.pc);
+        }
+    }
+
+    /// Note an instruction fetch. Once the CPU fetches past the instruction it was at when
+    /// DMA finished, the DMA's value is no longer on the bus.
+    fn bus_note_fetch(&mut self) {
+        if self.bus.dma_end_pc != Some(self.cpu.pc) {
+            self.bus.dma_end_pc = None;
+        }
+    }
+
+    /// Let the prefetch buffer run for cycles in which the Game Pak bus isn't being used
+    /// (e.g. accesses to other regions, or internal cycles).
+    pub(crate) fn bus_idle_cycles(&mut self, mut cycles: usize) {
+        let prefetch = &mut self.bus.prefetch;
+        if !prefetch.active || prefetch.paused {
+            return;
+        }
+        while cycles > 0 && prefetch.count < PREFETCH_CAPACITY {
+            if cycles < prefetch.countdown {
+                prefetch.countdown -= cycles;
+                return;
+            }
+            // The next halfword arrives, start fetching the one after.
+            cycles -= prefetch.countdown;
+            prefetch.count += 1;
+            let next = prefetch.head + 2 * (prefetch.count as u32);
+            prefetch.countdown = self.bus.wait_s16[region_from_address(next) as usize];
+        }
+    }
+
+    /// Add cycles for a code fetch of the given size from ROM, going through the prefetch buffer.
+    fn prefetch_fetch(&mut self, addr: Addr, size: MemoryAccessSize, access: MemoryAccessType) {
+        let halfwords = match size {
+            MemoryAccessSize::Mem32 => 2,
+            _ => 1,
+        };
+        let region = region_from_address(addr);
+        let prefetch = &self.bus.prefetch;
+        if !(prefetch.active && prefetch.head == addr) {
+            // Miss: do a regular access, then start prefetching the following code.
+            let cycles = self.access_cycles(region, size, access);
+            self.scheduler.update(cycles);
+            let next = addr + 2 * halfwords;
+            self.bus.prefetch = Prefetch {
+                enabled: true,
+                active: true,
+                head: next,
+                count: 0,
+                countdown: self.bus.wait_s16[region_from_address(next) as usize],
+                paused: false,
+            };
+            return;
+        }
+
+        for _ in 0..halfwords {
+            let waiting = self.bus.prefetch.count == 0;
+            if waiting {
+                // Wait for the halfword that's currently being fetched.
+                let cycles = self.bus.prefetch.countdown;
+                self.scheduler.update(cycles);
+                self.bus_idle_cycles(cycles);
+            }
+
+            // Take the halfword out of the buffer.
+            let prefetch = &mut self.bus.prefetch;
+            if prefetch.count == PREFETCH_CAPACITY {
+                // The buffer was full, so it starts fetching again.
+                let next = prefetch.head + 2 * (PREFETCH_CAPACITY as u32);
+                prefetch.countdown = self.bus.wait_s16[region_from_address(next) as usize];
+            }
+            prefetch.count -= 1;
+            prefetch.head += 2;
+
+            if !waiting {
+                // Buffered reads take a single cycle.
+                self.scheduler.update(1);
+                self.bus_idle_cycles(1);
+            }
+        }
+    }
+
+    /// Get the cycles for 16-bit and 32-bit EWRAM data accesses (which the recompiler
+    /// does inline).
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    pub(crate) fn bus_ewram_cycles(&self) -> [usize; 2] {
+        [
+            self.access_cycles(
+                REGION_EWRAM,
+                MemoryAccessSize::Mem16,
+                MemoryAccessType::NonSequential,
+            ),
+            self.access_cycles(
+                REGION_EWRAM,
+                MemoryAccessSize::Mem32,
+                MemoryAccessType::NonSequential,
+            ),
+        ]
+    }
+
+    /// Get an upper bound on the cycles of a sequential code fetch from each of the three
+    /// ROM waitstate regions, with or without the prefetch buffer.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    pub(crate) fn bus_rom_fetch_cycles_bound(&self) -> [usize; 3] {
+        [REGION_CART_WS0_A, REGION_CART_WS1_A, REGION_CART_WS2_A].map(|region| {
+            let region = region as usize;
+            self.bus.wait_n32[region] + self.bus.wait_s32[region]
+        })
+    }
+
+    /// Get the number of cycles for a memory access (without the prefetch buffer).
+    #[inline(always)]
+    fn access_cycles(
+        &self,
+        region: u32,
+        size: MemoryAccessSize,
+        access: MemoryAccessType,
+    ) -> usize {
         use MemoryAccessSize::*;
         use MemoryAccessType::*;
-        // TODO: OAM/Palette/VRAM have "plus 1 cycle if GBA access video mem at same time".
         let table = match (size, access) {
             (Mem8 | Mem16, Sequential) => &self.bus.wait_s16,
             (Mem8 | Mem16, NonSequential) => &self.bus.wait_n16,
@@ -162,74 +379,161 @@ impl Gba {
             size,
             access
         );
-        self.scheduler.update(cycles);
+        cycles
+    }
+
+    /// Fetch a 32 bit instruction.
+    pub(crate) fn cpu_fetch32(&mut self, addr: Addr, access: MemoryAccessType) -> u32 {
+        self.bus_note_fetch();
+        let region = region_from_address(addr);
+        if self.bus.prefetch.enabled && is_rom_region(region) {
+            self.prefetch_fetch(addr, MemoryAccessSize::Mem32, access);
+            self.cart_read_32(addr)
+        } else if region == REGION_BIOS && addr <= (BIOS_SIZE - 4) {
+            self.add_cycles(addr, MemoryAccessSize::Mem32, access);
+            self.bus.bios_last_fetch = self.bios_rom.read_32(addr);
+            self.bus.bios_last_fetch
+        } else {
+            // Fetches leave the value of the last data access on the bus (see `unused_load`).
+            let data_value = self.bus.data_value;
+            let value = self.cpu_load32(addr, access);
+            self.bus.data_value = data_value;
+            value
+        }
+    }
+
+    /// Fetch a 16 bit instruction.
+    pub(crate) fn cpu_fetch16(&mut self, addr: Addr, access: MemoryAccessType) -> u16 {
+        self.bus_note_fetch();
+        let region = region_from_address(addr);
+        if self.bus.prefetch.enabled && is_rom_region(region) {
+            self.prefetch_fetch(addr, MemoryAccessSize::Mem16, access);
+            self.cart_read_16(addr)
+        } else if region == REGION_BIOS && addr <= (BIOS_SIZE - 2) {
+            self.add_cycles(addr, MemoryAccessSize::Mem16, access);
+            self.bus.bios_last_fetch = self.bios_rom.read_32(addr & !3);
+            self.bios_rom.read_16(addr)
+        } else {
+            // Fetches leave the value of the last data access on the bus (see `unused_load`).
+            let data_value = self.bus.data_value;
+            let value = self.cpu_load16(addr, access);
+            self.bus.data_value = data_value;
+            value
+        }
+    }
+
+    /// Add the cycles for fetching a 32 bit instruction whose value is already known
+    /// (from the block cache). Only valid for ROM and work RAM.
+    pub(crate) fn cpu_fetch32_cached(&mut self, addr: Addr, access: MemoryAccessType, value: u32) {
+        self.bus_note_fetch();
+        let region = region_from_address(addr);
+        if self.bus.prefetch.enabled && is_rom_region(region) {
+            self.prefetch_fetch(addr, MemoryAccessSize::Mem32, access);
+        } else {
+            self.add_cycles(addr, MemoryAccessSize::Mem32, access);
+            self.idle_on_load(addr, value);
+        }
+    }
+
+    /// Add the cycles for fetching a 16 bit instruction whose value is already known
+    /// (from the block cache). Only valid for ROM and work RAM.
+    pub(crate) fn cpu_fetch16_cached(&mut self, addr: Addr, access: MemoryAccessType, value: u16) {
+        self.bus_note_fetch();
+        let region = region_from_address(addr);
+        if self.bus.prefetch.enabled && is_rom_region(region) {
+            self.prefetch_fetch(addr, MemoryAccessSize::Mem16, access);
+        } else {
+            self.add_cycles(addr, MemoryAccessSize::Mem16, access);
+            self.idle_on_load(addr, value as u32);
+        }
     }
 
     /// Read a 32 bit value from the bus.
     pub(crate) fn cpu_load32(&mut self, addr: Addr, access: MemoryAccessType) -> u32 {
         let region = region_from_address(addr);
-        self.add_cycles(region, MemoryAccessSize::Mem32, access);
+        self.add_cycles(addr, MemoryAccessSize::Mem32, access);
 
-        match region {
+        let value = match region {
             REGION_BIOS if addr <= (BIOS_SIZE - 4) => self.bios_load(addr),
-            REGION_EWRAM => self.ewram.read_32(addr & 0x3FFFF),
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => self.ewram.read_32(addr & 0x3FFFF),
             REGION_IWRAM => self.iwram.read_32(addr & 0x7FFF),
             REGION_IO => self.io_read_32(addr),
-            REGION_VRAM => self.ppu.vram.read_32(addr & 0x1FFFF), // TODO wrap better?
+            REGION_VRAM => self.ppu.vram.read_32(vram_offset(addr)),
             REGION_PALETTE => self.ppu.palette.read_32(addr & 0x3FF),
             REGION_OAM => self.ppu.oam.read_32(addr & 0x3FF),
             REGION_CART_WS0_A..=REGION_CART_UNUSED => self.cart_read_32(addr),
             _ => self.unused_load(addr),
-        }
+        };
+        self.bus.data_value = value;
+        self.idle_on_load(addr, value);
+        value
     }
 
     /// Read a 16 bit value from the bus.
     pub(crate) fn cpu_load16(&mut self, addr: Addr, access: MemoryAccessType) -> u16 {
         let region = region_from_address(addr);
-        self.add_cycles(region, MemoryAccessSize::Mem16, access);
+        self.add_cycles(addr, MemoryAccessSize::Mem16, access);
 
-        match region {
+        let value = match region {
             REGION_BIOS if addr <= (BIOS_SIZE - 2) => self.bios_load(addr) as u16,
-            REGION_EWRAM => self.ewram.read_16(addr & 0x3FFFF),
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => self.ewram.read_16(addr & 0x3FFFF),
             REGION_IWRAM => self.iwram.read_16(addr & 0x7FFF),
             REGION_IO => self.io_read_16(addr),
-            REGION_VRAM => self.ppu.vram.read_16(addr & 0x1FFFF), // TODO wrap better?
+            REGION_VRAM => self.ppu.vram.read_16(vram_offset(addr)),
             REGION_PALETTE => self.ppu.palette.read_16(addr & 0x3FF),
             REGION_OAM => self.ppu.oam.read_16(addr & 0x3FF),
             REGION_CART_WS0_A..=REGION_CART_UNUSED => self.cart_read_16(addr),
             _ => self.unused_load(addr) as u16,
-        }
+        };
+        self.bus.data_value = value as u32 * 0x0001_0001;
+        self.idle_on_load(addr, value as u32);
+        value
     }
 
     /// Read an 8 bit value from the bus.
     pub(crate) fn cpu_load8(&mut self, addr: Addr, access: MemoryAccessType) -> u8 {
         let region = region_from_address(addr);
-        self.add_cycles(region, MemoryAccessSize::Mem8, access);
+        self.add_cycles(addr, MemoryAccessSize::Mem8, access);
 
-        match region {
+        let value = match region {
             REGION_BIOS if addr <= (BIOS_SIZE - 1) => self.bios_load(addr) as u8,
-            REGION_EWRAM => self.ewram.read_8(addr & 0x3FFFF),
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => self.ewram.read_8(addr & 0x3FFFF),
             REGION_IWRAM => self.iwram.read_8(addr & 0x7FFF),
             REGION_IO => self.io_read_8(addr),
-            REGION_VRAM => self.ppu.vram.read_8(addr & 0x1FFFF), // TODO wrap better?
+            REGION_VRAM => self.ppu.vram.read_8(vram_offset(addr)),
             REGION_PALETTE => self.ppu.palette.read_8(addr & 0x3FF),
             REGION_OAM => self.ppu.oam.read_8(addr & 0x3FF),
             REGION_CART_WS0_A..=REGION_CART_UNUSED => self.cart_read_8(addr),
             _ => self.unused_load(addr) as u8,
-        }
+        };
+        self.bus.data_value = value as u32 * 0x0101_0101;
+        self.idle_on_load(addr, value as u32);
+        value
     }
 
     /// Store a 32 bit value to the bus.
     pub(crate) fn cpu_store32(&mut self, addr: Addr, data: u32, access: MemoryAccessType) {
         let region = region_from_address(addr);
-        self.add_cycles(region, MemoryAccessSize::Mem32, access);
+        self.add_cycles(addr, MemoryAccessSize::Mem32, access);
+        self.bus.data_value = data;
+        self.idle_on_store();
+        if (REGION_PALETTE..=REGION_OAM).contains(&region) {
+            self.ppu_catch_up();
+        }
 
         match region {
             REGION_BIOS => {}
-            REGION_EWRAM => self.ewram.write_32(addr & 0x3FFFF, data),
-            REGION_IWRAM => self.iwram.write_32(addr & 0x7FFF, data),
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => {
+                self.ewram.write_32(addr & 0x3FFFF, data);
+                self.cpu_cache_notify_write(addr);
+            }
+            REGION_EWRAM => {} // EWRAM is disabled.
+            REGION_IWRAM => {
+                self.iwram.write_32(addr & 0x7FFF, data);
+                self.cpu_cache_notify_write(addr);
+            }
             REGION_IO => self.io_write_32(addr, data),
-            REGION_VRAM => self.ppu.vram.write_32(addr & 0x1FFFF, data), // TODO wrap better?
+            REGION_VRAM => self.ppu.vram.write_32(vram_offset(addr), data),
             REGION_PALETTE => self.ppu.palette.write_32(addr & 0x3FF, data),
             REGION_OAM => self.ppu.oam.write_32(addr & 0x3FF, data),
             REGION_CART_WS0_A..=REGION_CART_UNUSED => self.cart_write_32(addr, data),
@@ -245,14 +549,26 @@ impl Gba {
     /// Store a 16 bit value to the bus.
     pub(crate) fn cpu_store16(&mut self, addr: Addr, data: u16, access: MemoryAccessType) {
         let region = region_from_address(addr);
-        self.add_cycles(region, MemoryAccessSize::Mem16, access);
+        self.add_cycles(addr, MemoryAccessSize::Mem16, access);
+        self.bus.data_value = data as u32 * 0x0001_0001;
+        self.idle_on_store();
+        if (REGION_PALETTE..=REGION_OAM).contains(&region) {
+            self.ppu_catch_up();
+        }
 
         match region {
             REGION_BIOS => {}
-            REGION_EWRAM => self.ewram.write_16(addr & 0x3FFFF, data),
-            REGION_IWRAM => self.iwram.write_16(addr & 0x7FFF, data),
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => {
+                self.ewram.write_16(addr & 0x3FFFF, data);
+                self.cpu_cache_notify_write(addr);
+            }
+            REGION_EWRAM => {} // EWRAM is disabled.
+            REGION_IWRAM => {
+                self.iwram.write_16(addr & 0x7FFF, data);
+                self.cpu_cache_notify_write(addr);
+            }
             REGION_IO => self.io_write_16(addr, data),
-            REGION_VRAM => self.ppu.vram.write_16(addr & 0x1FFFF, data), // TODO wrap better?
+            REGION_VRAM => self.ppu.vram.write_16(vram_offset(addr), data),
             REGION_PALETTE => self.ppu.palette.write_16(addr & 0x3FF, data),
             REGION_OAM => self.ppu.oam.write_16(addr & 0x3FF, data),
             REGION_CART_WS0_A..=REGION_CART_UNUSED => self.cart_write_16(addr, data),
@@ -265,21 +581,34 @@ impl Gba {
     /// Store an 8 bit value to the bus.
     pub(crate) fn cpu_store8(&mut self, addr: Addr, data: u8, access: MemoryAccessType) {
         let region = region_from_address(addr);
-        self.add_cycles(region, MemoryAccessSize::Mem8, access);
+        self.add_cycles(addr, MemoryAccessSize::Mem8, access);
+        self.bus.data_value = data as u32 * 0x0101_0101;
+        self.idle_on_store();
+        if (REGION_PALETTE..=REGION_OAM).contains(&region) {
+            self.ppu_catch_up();
+        }
 
         match region {
             REGION_BIOS => {}
-            REGION_EWRAM => self.ewram.write_8(addr & 0x3FFFF, data),
-            REGION_IWRAM => self.iwram.write_8(addr & 0x7FFF, data),
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => {
+                self.ewram.write_8(addr & 0x3FFFF, data);
+                self.cpu_cache_notify_write(addr);
+            }
+            REGION_EWRAM => {} // EWRAM is disabled.
+            REGION_IWRAM => {
+                self.iwram.write_8(addr & 0x7FFF, data);
+                self.cpu_cache_notify_write(addr);
+            }
             REGION_IO => self.io_write_8(addr, data),
-            REGION_VRAM => {
-                // Byte writes to VRAM do a 16-bit write (same byte duplicated).
-                let addr = (addr & 0x1FFFE) as usize;
-                self.ppu.vram[addr] = data;
-                self.ppu.vram[addr | 1] = data;
+            REGION_VRAM => self.ppu_vram_write_8(addr, data),
+            REGION_PALETTE => {
+                // Byte writes to palette RAM do a 16-bit write (same byte duplicated).
+                let data = u16::from_le_bytes([data, data]);
+                self.ppu.palette.write_16(addr & 0x3FE, data);
+            }
+            REGION_OAM => {
+                // Byte writes to OAM are ignored.
             }
-            REGION_PALETTE => self.ppu.palette.write_8(addr & 0x3FF, data),
-            REGION_OAM => self.ppu.oam.write_8(addr & 0x3FF, data),
             REGION_CART_WS0_A..=REGION_CART_UNUSED => self.cart_write_8(addr, data),
             _ => {
                 eprintln!("Bad memory store (8 bit) at {:X}, data {:X}", addr, data);
@@ -287,18 +616,25 @@ impl Gba {
         }
     }
 
-    /// Load from the BIOS region,
+    /// Load from the BIOS region.
+    ///
+    /// The BIOS can only be read while executing inside it. Otherwise, reads return
+    /// the last opcode fetched from the BIOS.
     fn bios_load(&mut self, address: Addr) -> u32 {
+        let shift = (address & 3) << 3;
         if self.cpu.pc < BIOS_SIZE {
-            self.bus.bios_last_load = self.bios_rom.read_32(address & !3);
+            self.bios_rom.read_32(address & !3) >> shift
+        } else {
+            self.bus.bios_last_fetch >> shift
         }
-        let shift = (address & 3) << 3;
-        self.bus.bios_last_load >> shift
     }
 
     /// Do a read of unused memory (open bus).
     fn unused_load(&mut self, addr: Addr) -> u32 {
-        eprintln!("Bad load at PC={:08X}, ADDR={:08X}", self.cpu.pc, addr);
+        if self.bus.dma_running || self.bus.dma_end_pc == Some(self.cpu.pc) {
+            // DMA's last value is still on the bus.
+            return self.bus.data_value >> ((addr & 3) << 3);
+        }
         let raw = match self.cpu.cpsr.execution_state {
             CpuExecutionState::Arm => {
                 // ARM returns last fetched opcode.
@@ -311,9 +647,15 @@ impl Gba {
                 let (lo, hi) = match region_from_address(self.cpu.pc) {
                     REGION_BIOS | REGION_OAM => {
                         if self.cpu.pc & 3 == 0 {
-                            // 4-byte aligned:
-                            // XXX: GBATEK says LSW = [$+4], MSW = [$+6], but we don't have [$+6].
-                            (pipe1, pipe1)
+                            // 4-byte aligned: LSW = [$+4], MSW = [$+6].
+                            // [$+6] is the halfword after the one that was just fetched.
+                            let next = self.cpu.pc + 2;
+                            let pipe2 = if region_from_address(next) == REGION_BIOS {
+                                self.bios_rom.read_16(next & (BIOS_SIZE - 1))
+                            } else {
+                                self.ppu.oam.read_16(next & 0x3FF)
+                            };
+                            (pipe1, pipe2 as u32)
                         } else {
                             (pipe0, pipe1)
                         }
@@ -336,3 +678,272 @@ impl Gba {
         raw >> ((addr & 3) << 3)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{REG_DISPCNT, REG_WAITCNT};
+
+    /// Address of some code in ROM.
+    const ROM_CODE: Addr = 0x0800_0100;
+
+    /// A GBA with the prefetch buffer enabled, and 3/1 waitstates for WS0 (so
+    /// non-sequential 16-bit accesses take 4 cycles and sequential ones take 2).
+    fn gba_with_prefetch() -> Gba {
+        let mut gba = Gba::new_for_test(&[0; 0x400]);
+        gba.io_write_16(REG_WAITCNT, 0x4000 | (0b01 << 2) | (1 << 4));
+        gba
+    }
+
+    /// Run something, and return how many cycles it took.
+    fn cycles(gba: &mut Gba, f: impl FnOnce(&mut Gba)) -> usize {
+        let start = gba.scheduler.timestamp();
+        f(gba);
+        gba.scheduler.timestamp() - start
+    }
+
+    /// Fetch some code, then let the buffer fill for `idle` cycles.
+    fn fetch_and_fill(gba: &mut Gba, idle: usize) {
+        let taken = cycles(gba, |gba| {
+            gba.cpu_fetch16(ROM_CODE, MemoryAccessType::NonSequential);
+        });
+        assert_eq!(taken, 4);
+        gba.bus_idle_cycles(idle);
+    }
+
+    #[test]
+    fn fills_while_idle() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 10);
+        assert_eq!(gba.bus.prefetch.count, 5);
+        for i in 1..=5 {
+            let taken = cycles(&mut gba, |gba| {
+                gba.cpu_fetch16(ROM_CODE + 2 * i, MemoryAccessType::Sequential);
+            });
+            assert_eq!(taken, 1);
+        }
+    }
+
+    #[test]
+    fn fills_during_other_accesses() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 0);
+        // 32-bit EWRAM accesses take 6 cycles, enough for 3 halfwords.
+        gba.cpu_load32(0x0200_0000, MemoryAccessType::NonSequential);
+        assert_eq!(gba.bus.prefetch.count, 3);
+    }
+
+    #[test]
+    fn waits_for_the_halfword_being_fetched() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 1);
+        assert_eq!(gba.bus.prefetch.count, 0);
+        let taken = cycles(&mut gba, |gba| {
+            gba.cpu_fetch16(ROM_CODE + 2, MemoryAccessType::Sequential);
+        });
+        assert_eq!(taken, 1);
+    }
+
+    #[test]
+    fn branch_discards() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 10);
+        let taken = cycles(&mut gba, |gba| {
+            gba.cpu_fetch16(ROM_CODE + 0x40, MemoryAccessType::NonSequential);
+        });
+        assert_eq!(taken, 4);
+        assert_eq!(gba.bus.prefetch.head, ROM_CODE + 0x42);
+        assert_eq!(gba.bus.prefetch.count, 0);
+    }
+
+    #[test]
+    fn nonsequential_rom_data_access_discards() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 10);
+        gba.cpu_load16(0x0800_0300, MemoryAccessType::NonSequential);
+        assert!(!gba.bus.prefetch.active);
+        // The next fetch is a regular sequential access.
+        let taken = cycles(&mut gba, |gba| {
+            gba.cpu_fetch16(ROM_CODE + 2, MemoryAccessType::Sequential);
+        });
+        assert_eq!(taken, 2);
+    }
+
+    #[test]
+    fn sequential_rom_data_access_keeps_buffer() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 10);
+        gba.cpu_load16(0x0800_0302, MemoryAccessType::Sequential);
+        assert!(gba.bus.prefetch.active);
+        assert_eq!(gba.bus.prefetch.count, 5);
+    }
+
+    #[test]
+    fn sram_access_keeps_buffer_without_filling() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 0);
+        gba.cpu_load8(0x0E00_0000, MemoryAccessType::NonSequential);
+        assert!(gba.bus.prefetch.active);
+        assert_eq!(gba.bus.prefetch.count, 0);
+        assert_eq!(gba.bus.prefetch.countdown, 2);
+    }
+
+    /// Run an immediate 16-bit DMA 3 transfer of `count` halfwords.
+    fn run_dma(gba: &mut Gba, src: u32, dest: u32, count: u16) {
+        // Channel 3's registers start at 0x24 (relative to 0x0400_00B0).
+        gba.dma_reg_write(0x24, src as u16);
+        gba.dma_reg_write(0x26, (src >> 16) as u16);
+        gba.dma_reg_write(0x28, dest as u16);
+        gba.dma_reg_write(0x2A, (dest >> 16) as u16);
+        gba.dma_reg_write(0x2C, count);
+        gba.dma_reg_write(0x2E, 0x8000);
+        gba.dma_activate_channel(3);
+        while gba.dma_active() {
+            gba.dma_step();
+        }
+    }
+
+    #[test]
+    fn dma_pauses_buffer() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 4);
+        assert_eq!(gba.bus.prefetch.count, 2);
+        let taken = cycles(&mut gba, |gba| run_dma(gba, 0x0300_0000, 0x0300_0100, 8));
+        assert!(taken >= 16);
+        assert!(gba.bus.prefetch.active);
+        assert_eq!(gba.bus.prefetch.count, 2);
+        // It runs again once the CPU has the bus back.
+        gba.bus_idle_cycles(2);
+        assert_eq!(gba.bus.prefetch.count, 3);
+    }
+
+    #[test]
+    fn dma_from_rom_discards_buffer() {
+        let mut gba = gba_with_prefetch();
+        fetch_and_fill(&mut gba, 4);
+        run_dma(&mut gba, 0x0800_0300, 0x0300_0100, 8);
+        assert!(!gba.bus.prefetch.active);
+    }
+
+    /// Run a timing test ROM headlessly, and compare its final screen with a capture from
+    /// hardware or a trusted emulator. It runs only if `GBA_TEST_ROM` is set:
+    ///
+    /// - `GBA_TEST_ROM`: a test ROM that runs without input (e.g. NanoBoyAdvance's
+    ///   hw-test prefetch ROMs, or a build of mGBA's suite that runs the timing tests).
+    /// - `GBA_TEST_EXPECTED`: PNG of the screen the ROM should end on.
+    /// - `GBA_TEST_BIOS`: BIOS to boot through (optional; skips the BIOS if unset).
+    /// - `GBA_TEST_FRAMES`: frames to run for (default 600).
+    ///
+    /// Colors are compared at 15-bit precision, since emulators expand them to 24 bits
+    /// differently.
+    /// Unmapped memory.
+    const UNMAPPED: Addr = 0x1000_0000;
+
+    #[test]
+    fn unused_load_after_dma_returns_dma_value() {
+        let mut gba = Gba::new_for_test(&[0; 0x400]);
+        gba.cpu_store16(0x0300_0000, 0xBEEF, MemoryAccessType::NonSequential);
+        run_dma(&mut gba, 0x0300_0000, 0x0300_0100, 1);
+        // The instruction the CPU runs after DMA sees the DMA's value.
+        gba.cpu_fetch32(gba.cpu.pc, MemoryAccessType::Sequential);
+        let value = gba.cpu_load32(UNMAPPED, MemoryAccessType::NonSequential);
+        assert_eq!(value, 0xBEEF_BEEF);
+        assert_eq!(
+            gba.cpu_load8(UNMAPPED + 1, MemoryAccessType::NonSequential),
+            0xBE
+        );
+    }
+
+    #[test]
+    fn unused_load_returns_prefetched_opcode_once_cpu_moves_on_after_dma() {
+        let mut rom = vec![0; 0x400];
+        // Distinct opcodes (mov r0, rN), before the header.
+        for (i, word) in rom[..0xA0].chunks_exact_mut(4).enumerate() {
+            word.copy_from_slice(&(0xE1A0_0000u32 + i as u32).to_le_bytes());
+        }
+        let mut gba = Gba::new_for_test(&rom);
+        gba.cpu_store16(0x0300_0000, 0xBEEF, MemoryAccessType::NonSequential);
+        run_dma(&mut gba, 0x0300_0000, 0x0300_0100, 1);
+        gba.cpu_step();
+        gba.cpu_step();
+        let value = gba.cpu_load32(UNMAPPED, MemoryAccessType::NonSequential);
+        assert_eq!(value, gba.cpu_pipeline_get_prefetch());
+        assert_ne!(value, 0xBEEF_BEEF);
+    }
+
+    #[test]
+    fn video_memory_waits_only_for_enabled_layers() {
+        let mut gba = Gba::new_for_test(&[0; 0x400]);
+        let load16 = |gba: &mut Gba, addr| {
+            cycles(gba, |gba| {
+                gba.cpu_load16(addr, MemoryAccessType::NonSequential);
+            })
+        };
+        const BG_VRAM: Addr = 0x0600_0000;
+        const OBJ_VRAM: Addr = 0x0601_0000;
+        const PALETTE: Addr = 0x0500_0000;
+        const OAM: Addr = 0x0700_0000;
+
+        // In H-Draw, with no layers enabled, nothing is fetched.
+        gba.io_write_16(REG_DISPCNT, 0);
+        for addr in [BG_VRAM, OBJ_VRAM, PALETTE, OAM] {
+            assert_eq!(load16(&mut gba, addr), 1);
+        }
+
+        // BG0 only.
+        gba.io_write_16(REG_DISPCNT, 1 << 8);
+        assert_eq!(load16(&mut gba, BG_VRAM), 2);
+        assert_eq!(load16(&mut gba, PALETTE), 2);
+        assert_eq!(load16(&mut gba, OBJ_VRAM), 1);
+        assert_eq!(load16(&mut gba, OAM), 1);
+
+        // OBJ only.
+        gba.io_write_16(REG_DISPCNT, 1 << 12);
+        assert_eq!(load16(&mut gba, BG_VRAM), 1);
+        assert_eq!(load16(&mut gba, PALETTE), 1);
+        assert_eq!(load16(&mut gba, OBJ_VRAM), 2);
+        assert_eq!(load16(&mut gba, OAM), 2);
+    }
+
+    #[test]
+    fn timing_test_rom() {
+        use crate::test_images::{read_png, write_png};
+        use std::path::Path;
+
+        let rom_path = match std::env::var_os("GBA_TEST_ROM") {
+            Some(path) => path,
+            None => return,
+        };
+        let expected_path = std::env::var_os("GBA_TEST_EXPECTED")
+            .expect("GBA_TEST_EXPECTED should be the expected final screen");
+        let frames = std::env::var("GBA_TEST_FRAMES").map_or(600, |f| f.parse().unwrap());
+
+        let rom = std::fs::read(&rom_path).unwrap();
+        let mut gba = match std::env::var_os("GBA_TEST_BIOS") {
+            Some(bios_path) => {
+                let bios = std::fs::read(bios_path).unwrap().into_boxed_slice();
+                Gba::builder(bios, crate::Rom::new(&rom)).build()
+            }
+            None => Gba::new_for_test(&rom),
+        };
+        for _ in 0..frames {
+            gba.emulate_frame(true);
+        }
+
+        let (expected, width, height) = read_png(Path::new(&expected_path));
+        assert_eq!((width, height), (crate::WIDTH, crate::HEIGHT));
+        let actual = gba.framebuffer();
+        let mismatches = (0..expected.len())
+            .filter(|&i| (actual[i] >> 3) & 0x1F_1F1F != (expected[i] >> 3) & 0x1F_1F1F)
+            .count();
+        if mismatches > 0 {
+            let actual_path = Path::new(&expected_path).with_extension("actual.png");
+            write_png(&actual_path, actual, width, height);
+            panic!(
+                "{} pixels differ from the expected screen; see {}",
+                mismatches,
+                actual_path.display()
+            );
+        }
+    }
+}
@@ -41,6 +41,10 @@ pub struct EepromBackup {
     request_type: RequestType,
     /// Current address we're reading / writing.
     address: usize,
+
+    /// Bits received after a command while the size is still unknown.
+    /// They're replayed once we can tell how long the address is.
+    pending_bits: Vec<bool>,
 }
 
 #[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -75,11 +79,42 @@ impl EepromBackup {
             request_type: RequestType::Read,
             address: 0,
             read_bits: 0,
+            pending_bits: Vec::new(),
         }
     }
 
     /// Read a bit from EEPROM.
     pub fn read(&mut self, buffer: &mut BackupBuffer) -> u16 {
+        if self.size.is_none() && !self.pending_bits.is_empty() {
+            // The game finished sending a request before we could detect the size,
+            // so the request's length tells us how long the address was.
+            let size = match (self.request_type, self.pending_bits.len()) {
+                // Read request: address + 1.
+                (RequestType::Read, 7) => Some(EepromSize::Eeprom512),
+                (RequestType::Read, 15) => Some(EepromSize::Eeprom8K),
+                // Write request: address + 64 + 1.
+                (RequestType::Write, 71) => Some(EepromSize::Eeprom512),
+                (RequestType::Write, 79) => Some(EepromSize::Eeprom8K),
+                _ => None,
+            };
+            let size = match size {
+                Some(size) => {
+                    eprintln!("EEPROM: detected {:?} from request length", size);
+                    size
+                }
+                None => {
+                    eprintln!(
+                        "EEPROM: unexpected {}-bit request, assuming {:?}",
+                        self.pending_bits.len(),
+                        EepromSize::Eeprom8K
+                    );
+                    EepromSize::Eeprom8K
+                }
+            };
+            self.size = Some(size);
+            self.replay_pending_bits(buffer);
+        }
+
         if self.state == State::Reading {
             self.read_bits += 1;
             if self.read_bits <= 4 {
@@ -116,18 +151,35 @@ impl EepromBackup {
     /// Takes a reference to the DMA engine state so that we can attempt
     /// to autodetect EEPROM size based on the DMA transfer count.
     pub fn write(&mut self, value: u16, dma: &Dma, buffer: &mut BackupBuffer) {
-        let size = self.size.get_or_insert_with(|| {
-            // Try to detect the size of the EEPROM.
-            match Self::detect_size(dma) {
-                Some(size) => {
-                    eprintln!("EEPROM: detected {:?}", size);
-                    size
-                }
-                None => panic!("Failed to detect EEPROM size!"),
+        if self.size.is_none() {
+            self.size = Self::detect_size(dma, buffer);
+            if self.size.is_some() {
+                self.replay_pending_bits(buffer);
             }
-        });
+        }
 
         let input = (value & 0x1) as u64;
+        if self.size.is_none() && self.state == State::GetAddress {
+            // We don't know how long the address is yet. Hold on to the bits.
+            if self.pending_bits.is_empty() {
+                eprintln!("EEPROM: couldn't detect size, waiting for the end of the request");
+            }
+            self.pending_bits.push(input == 1);
+            return;
+        }
+        self.write_bit(input, buffer);
+    }
+
+    /// Feed the bits received while the size was unknown through the state machine.
+    fn replay_pending_bits(&mut self, buffer: &mut BackupBuffer) {
+        let bits = std::mem::take(&mut self.pending_bits);
+        for bit in bits {
+            self.write_bit(bit as u64, buffer);
+        }
+    }
+
+    /// Handle a single serial bit. The size must be known if we're getting an address.
+    fn write_bit(&mut self, input: u64, buffer: &mut BackupBuffer) {
         self.serial_buffer = self.serial_buffer.wrapping_shl(1);
         self.serial_buffer |= input;
         self.serial_transferred += 1;
@@ -152,7 +204,8 @@ impl EepromBackup {
             }
             State::Waiting => {}
             State::GetAddress => {
-                if self.serial_transferred == size.address_bits() {
+                let address_bits = self.size.map_or(usize::MAX, |size| size.address_bits());
+                if self.serial_transferred == address_bits {
                     // Addressing is in chunks of 8 bytes.
                     // For the 8KB one, we ignore the top 4 bits of the address.
                     self.address = ((self.serial_buffer as usize) * 8) & 0x1FFF;
@@ -191,8 +244,28 @@ impl EepromBackup {
         }
     }
 
+    /// Attempt to detect the size of EEPROM, first based on the size of the save file,
+    /// then based on DMA 3's current transfer count.
+    fn detect_size(dma: &Dma, buffer: &BackupBuffer) -> Option<EepromSize> {
+        let size = match buffer.storage.len() {
+            512 => Some(EepromSize::Eeprom512),
+            0x2000 => Some(EepromSize::Eeprom8K),
+            _ => None,
+        };
+        if let Some(size) = size {
+            eprintln!("EEPROM: detected {:?} from save file size", size);
+            return Some(size);
+        }
+
+        let size = Self::detect_size_dma(dma);
+        if let Some(size) = size {
+            eprintln!("EEPROM: detected {:?} from DMA length", size);
+        }
+        size
+    }
+
     /// Attempt to detect the size of EEPROM based on DMA 3's current transfer count.
-    fn detect_size(dma: &crate::Dma) -> Option<EepromSize> {
+    fn detect_size_dma(dma: &Dma) -> Option<EepromSize> {
         match dma.transfer_size(3) {
             // Read request for 512B: 2 + 6 + 1
             Some(9) => Some(EepromSize::Eeprom512),
@@ -1,19 +1,15 @@
 use serde::{Deserialize, Serialize};
 
+use crate::scheduler::{Event, Scheduler};
+
 use super::BackupBuffer;
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FlashSize {
     Flash64K,
     Flash128K,
 }
 
-/// Panasonic 64KiB Flash ID
-const FLASH_64K_ID: [u8; 2] = [0x32, 0x1B];
-
-/// Sanyo 128KiB Flash ID
-const FLASH_128K_ID: [u8; 2] = [0x62, 0x13];
-
 impl FlashSize {
     fn bytes(self) -> usize {
         match self {
@@ -22,21 +18,115 @@ impl FlashSize {
         }
     }
 
-    fn id(self) -> [u8; 2] {
+    fn banks(self) -> u8 {
         match self {
-            FlashSize::Flash64K => FLASH_64K_ID,
-            FlashSize::Flash128K => FLASH_128K_ID,
+            FlashSize::Flash64K => 1,
+            FlashSize::Flash128K => 2,
         }
     }
+}
 
-    fn banks(self) -> u8 {
+/// A specific Flash chip model.
+///
+/// Chips differ in their ID, how writes work, and how long operations take.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlashChip {
+    /// Panasonic MN63F805MNP, 64KiB.
+    Panasonic64K,
+    /// SST 39VF512, 64KiB.
+    Sst64K,
+    /// Macronix MX29L512, 64KiB.
+    Macronix64K,
+    /// Atmel AT29LV512, 64KiB. Writes 128-byte pages instead of single bytes.
+    Atmel64K,
+    /// Sanyo LE26FV10N1TS, 128KiB.
+    Sanyo128K,
+    /// Macronix MX29L010, 128KiB.
+    Macronix128K,
+}
+
+/// System clock frequency (2^24 Hz).
+const CLOCK_HZ: usize = 16_777_216;
+
+/// Convert a time in microseconds to cycles.
+fn us_to_cycles(us: usize) -> usize {
+    us * CLOCK_HZ / 1_000_000
+}
+
+impl FlashChip {
+    /// The chip used when only the size of the Flash is known.
+    pub fn default_for_size(size: FlashSize) -> FlashChip {
+        match size {
+            FlashSize::Flash64K => FlashChip::Panasonic64K,
+            FlashSize::Flash128K => FlashChip::Sanyo128K,
+        }
+    }
+
+    pub fn size(self) -> FlashSize {
         match self {
-            FlashSize::Flash64K => 1,
-            FlashSize::Flash128K => 2,
+            FlashChip::Panasonic64K
+            | FlashChip::Sst64K
+            | FlashChip::Macronix64K
+            | FlashChip::Atmel64K => FlashSize::Flash64K,
+            FlashChip::Sanyo128K | FlashChip::Macronix128K => FlashSize::Flash128K,
         }
     }
+
+    /// Manufacturer and device ID, as read in chip identification mode.
+    fn id(self) -> [u8; 2] {
+        match self {
+            FlashChip::Panasonic64K => [0x32, 0x1B],
+            FlashChip::Sst64K => [0xBF, 0xD4],
+            FlashChip::Macronix64K => [0xC2, 0x1C],
+            FlashChip::Atmel64K => [0x1F, 0x3D],
+            FlashChip::Sanyo128K => [0x62, 0x13],
+            FlashChip::Macronix128K => [0xC2, 0x09],
+        }
+    }
+
+    /// Whether this chip programs 128-byte pages rather than single bytes.
+    fn page_write(self) -> bool {
+        self == FlashChip::Atmel64K
+    }
+
+    /// Typical time to program a byte (or a page, for Atmel) in cycles.
+    fn program_cycles(self) -> usize {
+        us_to_cycles(match self {
+            FlashChip::Panasonic64K => 20,
+            FlashChip::Sst64K => 14,
+            FlashChip::Macronix64K | FlashChip::Macronix128K => 30,
+            FlashChip::Atmel64K => 10_000,
+            FlashChip::Sanyo128K => 30,
+        })
+    }
+
+    /// Typical time to erase a 4KiB sector in cycles.
+    fn sector_erase_cycles(self) -> usize {
+        us_to_cycles(match self {
+            FlashChip::Panasonic64K => 25_000,
+            FlashChip::Sst64K => 18_000,
+            FlashChip::Macronix64K | FlashChip::Macronix128K => 60_000,
+            // Atmel chips don't have sector erase (it's part of the page write).
+            FlashChip::Atmel64K => 0,
+            FlashChip::Sanyo128K => 40_000,
+        })
+    }
+
+    /// Typical time to erase the whole chip in cycles.
+    fn chip_erase_cycles(self) -> usize {
+        us_to_cycles(match self {
+            FlashChip::Panasonic64K => 40_000,
+            FlashChip::Sst64K => 70_000,
+            FlashChip::Macronix64K | FlashChip::Macronix128K => 150_000,
+            FlashChip::Atmel64K => 20_000,
+            FlashChip::Sanyo128K => 60_000,
+        })
+    }
 }
 
+/// Size of an Atmel page.
+const ATMEL_PAGE_SIZE: usize = 128;
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 enum CommandState {
     Ready,
@@ -44,12 +134,23 @@ enum CommandState {
     Setup2,
     BankSwap,
     WriteByte,
+    /// Atmel page write, with the number of bytes written so far.
+    WritePage(usize),
+}
+
+/// An in-progress program or erase operation.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+struct BusyState {
+    /// The value the polled byte will have when the operation completes.
+    target: u8,
+    /// Toggle bit (DQ6), flips on every read while busy.
+    toggle: bool,
 }
 
 /// A flash backup.
 #[derive(Serialize, Deserialize)]
 pub struct FlashBackup {
-    size: FlashSize,
+    chip: FlashChip,
 
     /// Current Flash command state.
     command: CommandState,
@@ -62,31 +163,71 @@ pub struct FlashBackup {
 
     /// Whether the next command will be an erase command.
     erase_mode: bool,
+
+    /// The operation the chip is busy with (or None if it's ready).
+    busy: Option<BusyState>,
 }
 
 impl FlashBackup {
-    pub fn new(size: FlashSize) -> FlashBackup {
+    pub fn new(chip: FlashChip) -> FlashBackup {
         FlashBackup {
-            size,
+            chip,
             command: CommandState::Ready,
             chip_identification: false,
             bank: 0,
             erase_mode: false,
+            busy: None,
         }
     }
 
+    /// The chip model being emulated.
+    pub fn chip(&self) -> FlashChip {
+        self.chip
+    }
+
     pub fn read_8(&mut self, addr: u32, buffer: &mut BackupBuffer) -> u8 {
-        if self.chip_identification && addr < 2 {
-            self.size.id()[addr as usize]
+        if let Some(busy) = &mut self.busy {
+            // Status polling: DQ7 is the complement of the final value, DQ6 toggles.
+            busy.toggle = !busy.toggle;
+            (!busy.target & 0x80) | ((busy.toggle as u8) << 6)
+        } else if self.chip_identification && addr < 2 {
+            self.chip.id()[addr as usize]
         } else {
             let offset = self.address(addr & 0xFFFF);
             buffer.read(offset)
         }
     }
 
-    pub fn write_8(&mut self, addr: u32, data: u8, buffer: &mut BackupBuffer) {
+    pub fn write_8(
+        &mut self,
+        addr: u32,
+        data: u8,
+        buffer: &mut BackupBuffer,
+        scheduler: &mut Scheduler,
+    ) {
+        if self.busy.is_some() {
+            // The chip ignores commands until the current operation completes.
+            return;
+        }
+
         use CommandState::*;
         match (self.command, addr, data) {
+            (WritePage(written), address, data) => {
+                let page = self.address(address & 0xFFFF) & !(ATMEL_PAGE_SIZE - 1);
+                if written == 0 {
+                    // Bytes that aren't loaded in a page write end up erased.
+                    for i in page..(page + ATMEL_PAGE_SIZE) {
+                        buffer.write(i, 0xFF);
+                    }
+                }
+                buffer.write(self.address(address & 0xFFFF), data);
+                if written + 1 == ATMEL_PAGE_SIZE {
+                    self.command = Ready;
+                    self.start_busy(data, self.chip.program_cycles(), scheduler);
+                } else {
+                    self.command = WritePage(written + 1);
+                }
+            }
             (Ready, 0x5555, 0xAA) => self.command = Setup1,
             (Setup1, 0x2AAA, 0x55) => self.command = Setup2,
             (Setup2, 0x5555, 0x90) => {
@@ -101,14 +242,23 @@ impl FlashBackup {
             }
             (Setup2, 0x5555, 0xB0) => self.command = BankSwap,
             (BankSwap, 0x0000, bank) => {
-                self.bank = bank % self.size.banks();
+                self.bank = bank % self.chip.size().banks();
                 self.command = Ready;
             }
-            (Setup2, 0x5555, 0xA0) => self.command = WriteByte,
+            (Setup2, 0x5555, 0xA0) => {
+                self.command = if self.chip.page_write() {
+                    WritePage(0)
+                } else {
+                    WriteByte
+                };
+            }
             (WriteByte, address, data) => {
                 let offset = self.address(address & 0xFFFF);
-                buffer.write(offset, data);
+                // Programming can only clear bits.
+                let value = buffer.read(offset) & data;
+                buffer.write(offset, value);
                 self.command = Ready;
+                self.start_busy(value, self.chip.program_cycles(), scheduler);
             }
             (Setup2, 0x5555, 0x80) => {
                 // Prepare to erase.
@@ -118,20 +268,22 @@ impl FlashBackup {
             (Setup2, 0x5555, 0x10) => {
                 // Erase entire chip.
                 if self.erase_mode {
-                    for i in 0..self.size.bytes() {
+                    for i in 0..self.chip.size().bytes() {
                         buffer.write(i, 0xFF);
                     }
+                    self.start_busy(0xFF, self.chip.chip_erase_cycles(), scheduler);
                 }
                 self.command = Ready;
                 self.erase_mode = false;
             }
-            (Setup2, addr, 0x30) => {
+            (Setup2, addr, 0x30) if !self.chip.page_write() => {
                 // Erase 4KB sector.
                 if self.erase_mode {
                     let sector = self.address(addr & 0xF000);
                     for i in sector..(sector + 4 * 1024) {
                         buffer.write(i, 0xFF);
                     }
+                    self.start_busy(0xFF, self.chip.sector_erase_cycles(), scheduler);
                 }
                 self.command = Ready;
                 self.erase_mode = false;
@@ -149,6 +301,20 @@ impl FlashBackup {
         }
     }
 
+    /// Called when the scheduled program/erase operation is complete.
+    pub fn on_operation_complete(&mut self) {
+        self.busy = None;
+    }
+
+    /// Mark the chip as busy for the given number of cycles.
+    fn start_busy(&mut self, target: u8, cycles: usize, scheduler: &mut Scheduler) {
+        self.busy = Some(BusyState {
+            target,
+            toggle: false,
+        });
+        scheduler.push_event(Event::FlashOperationComplete, cycles);
+    }
+
     /// Translate an address to the chip address given the bank.
     fn address(&self, addr: u32) -> usize {
         ((self.bank as usize) * 64 * 1024) + (addr as usize)
@@ -1,13 +1,15 @@
+use std::io;
+
 use crate::Rom;
 
 mod eeprom;
 mod flash;
 
 pub use eeprom::{EepromBackup, EepromSize};
-pub use flash::{FlashBackup, FlashSize};
+pub use flash::{FlashBackup, FlashChip, FlashSize};
 use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 
 pub enum BackupType {
     /// No backup
@@ -53,6 +55,40 @@ impl BackupType {
         }
         BackupType::None
     }
+
+    /// The size of a save file for this backup type, in bytes.
+    ///
+    /// Returns None if there's no backup, or the size isn't known.
+    pub fn save_size(self) -> Option<usize> {
+        match self {
+            BackupType::None | BackupType::EepromAuto => None,
+            BackupType::Eeprom512 => Some(512),
+            BackupType::Eeprom8K => Some(8 * 1024),
+            BackupType::Sram => Some(32 * 1024),
+            BackupType::Flash64K => Some(64 * 1024),
+            BackupType::Flash128K => Some(128 * 1024),
+        }
+    }
+
+    /// Guess the backup type from the size of a save file.
+    pub fn from_save_size(size: usize) -> Option<BackupType> {
+        match size {
+            512 => Some(BackupType::Eeprom512),
+            0x2000 => Some(BackupType::Eeprom8K),
+            0x8000 => Some(BackupType::Sram),
+            0x10000 => Some(BackupType::Flash64K),
+            0x20000 => Some(BackupType::Flash128K),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an EEPROM backup type.
+    pub fn is_eeprom(self) -> bool {
+        matches!(
+            self,
+            BackupType::EepromAuto | BackupType::Eeprom512 | BackupType::Eeprom8K
+        )
+    }
 }
 
 /// Backing storage for the cartridge backup.
@@ -64,7 +100,7 @@ pub trait BackupFile {
     fn read(&mut self, offset: usize, buffer: &mut [u8]);
 
     /// Write bytes from the given buffer at the offset.
-    fn write(&mut self, offset: usize, data: &[u8]);
+    fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()>;
 }
 
 /// In-memory buffer for the backup file.
@@ -96,10 +132,14 @@ impl BackupBuffer {
     }
 
     /// Persist any unwritten data to the file.
-    pub fn save(&self, file: &mut dyn BackupFile) {
+    ///
+    /// The buffer stays dirty if the write fails, so it'll be retried later.
+    pub fn save(&mut self, file: &mut dyn BackupFile) -> io::Result<()> {
         if self.dirty {
-            file.write(0, &self.storage);
+            file.write(0, &self.storage)?;
+            self.dirty = false;
         }
+        Ok(())
     }
 
     /// Load from the backup file.
@@ -121,15 +161,21 @@ pub enum Backup {
 
 impl Backup {
     /// Construct a new backup state from a backup type.
-    pub fn new(backup_type: BackupType) -> Backup {
+    ///
+    /// `flash_chip` selects the Flash chip model, and overrides the size given by the type.
+    pub fn new(backup_type: BackupType, flash_chip: Option<FlashChip>) -> Backup {
+        let flash = |size| {
+            let chip = flash_chip.unwrap_or_else(|| FlashChip::default_for_size(size));
+            Backup::Flash(FlashBackup::new(chip))
+        };
         match backup_type {
             BackupType::None => Backup::None,
             BackupType::Sram => Backup::Sram,
             BackupType::EepromAuto => Backup::Eeprom(EepromBackup::new(None)),
             BackupType::Eeprom512 => Backup::Eeprom(EepromBackup::new(Some(EepromSize::Eeprom512))),
             BackupType::Eeprom8K => Backup::Eeprom(EepromBackup::new(Some(EepromSize::Eeprom8K))),
-            BackupType::Flash64K => Backup::Flash(FlashBackup::new(FlashSize::Flash64K)),
-            BackupType::Flash128K => Backup::Flash(FlashBackup::new(FlashSize::Flash128K)),
+            BackupType::Flash64K => flash(FlashSize::Flash64K),
+            BackupType::Flash128K => flash(FlashSize::Flash128K),
         }
     }
 }
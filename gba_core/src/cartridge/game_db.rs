@@ -1,25 +1,32 @@
-use super::{BackupType, GpioType};
+use super::{BackupType, FlashChip, GpioType};
 
 #[derive(Copy, Clone, Debug)]
 pub struct DatabaseEntry {
     pub game_code: &'static str,
     pub backup_type: BackupType,
     pub gpio_type: Option<GpioType>,
+    pub flash_chip: Option<FlashChip>,
 }
 
-macro_rules! entry {
-    ($code:literal, $backup_type:ident, None) => {
-        DatabaseEntry {
-            game_code: $code,
-            backup_type: BackupType::$backup_type,
-            gpio_type: None,
-        }
+macro_rules! optional {
+    ($type:ident, None) => {
+        None
     };
+    ($type:ident, $value:ident) => {
+        Some($type::$value)
+    };
+}
+
+macro_rules! entry {
     ($code:literal, $backup_type:ident, $gpio_type:ident) => {
+        entry!($code, $backup_type, $gpio_type, None)
+    };
+    ($code:literal, $backup_type:ident, $gpio_type:ident, $flash_chip:ident) => {
         DatabaseEntry {
             game_code: $code,
             backup_type: BackupType::$backup_type,
-            gpio_type: Some(GpioType::$gpio_type),
+            gpio_type: optional!(GpioType, $gpio_type),
+            flash_chip: optional!(FlashChip, $flash_chip),
         }
     };
 }
@@ -30,8 +37,8 @@ static DATABASE: &[DatabaseEntry] = &[
     entry!("AXVE", Flash128K, Rtc),  // Pokemon - Ruby Version (USA, Europe)
     entry!("AXPE", Flash128K, Rtc),  // Pokemon - Sapphire Version (USA, Europe)
     entry!("BPEE", Flash128K, Rtc),  // Pokemon - Emerald Version (USA, Europe)
-    entry!("BPRE", Flash128K, None), // Pokemon - Fire Red Version (USA, Europe)
-    entry!("BPGE", Flash128K, None), // Pokemon - Leaf Green Version (USA, Europe)
+    entry!("BPRE", Flash128K, None, Macronix128K), // Pokemon - Fire Red Version (USA, Europe)
+    entry!("BPGE", Flash128K, None, Macronix128K), // Pokemon - Leaf Green Version (USA, Europe)
 ];
 
 pub fn lookup(game_code: &str) -> Option<DatabaseEntry> {
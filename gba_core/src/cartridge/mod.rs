@@ -3,7 +3,7 @@ mod game_db;
 mod gpio;
 mod rom;
 
-pub use backup::{BackupFile, BackupType};
+pub use backup::{BackupFile, BackupType, FlashChip};
 pub use rom::Rom;
 use serde::{Deserialize, Serialize};
 
@@ -28,14 +28,23 @@ pub struct Cartridge {
 }
 
 impl Cartridge {
-    pub fn new(rom: &Rom, backup_type: Option<BackupType>) -> Cartridge {
+    pub fn new(
+        rom: &Rom,
+        backup_type: Option<BackupType>,
+        flash_chip: Option<FlashChip>,
+    ) -> Cartridge {
         let entry = game_db::lookup(&rom.game_code);
         let backup_type = backup_type
             .or(entry.map(|e| e.backup_type))
             .unwrap_or_else(|| BackupType::detect(&rom));
+        let flash_chip = flash_chip.or(entry.and_then(|e| e.flash_chip));
         let gpio_type = entry.and_then(|e| e.gpio_type);
 
+        let backup = Backup::new(backup_type, flash_chip);
         eprintln!("Cartridge: using backup type {:?}", backup_type);
+        if let Backup::Flash(flash) = &backup {
+            eprintln!("Cartridge: using Flash chip {:?}", flash.chip());
+        }
         eprintln!("Cartridge: using GPIO {:?}", gpio_type);
         let eeprom_mask = if rom.data.len() > 0x0100_0000 {
             // Above 16 MiB.
@@ -44,7 +53,7 @@ impl Cartridge {
             0x0100_0000
         };
         Cartridge {
-            backup: Backup::new(backup_type),
+            backup,
             backup_buffer: BackupBuffer::default(),
             eeprom_mask,
             gpio: gpio_type.map(|kind| Gpio::new(kind)),
@@ -96,7 +105,7 @@ impl Gba {
                     backup_buffer.write((addr & 0x7FFF) as usize, value);
                 }
                 Backup::Flash(flash) => {
-                    flash.write_8(addr & 0xFFFF, value, backup_buffer);
+                    flash.write_8(addr & 0xFFFF, value, backup_buffer, &mut self.scheduler);
                 }
                 _ => {}
             },
@@ -104,6 +113,13 @@ impl Gba {
         }
     }
 
+    /// Handle a scheduler event for a completed Flash program/erase operation.
+    pub(crate) fn cart_on_flash_operation_complete(&mut self) {
+        if let Backup::Flash(flash) = &mut self.cartridge.backup {
+            flash.on_operation_complete();
+        }
+    }
+
     pub(crate) fn cart_read_16(&mut self, addr: u32) -> u16 {
         // Check if we're reading from EEPROM.
         if self.cartridge.is_eeprom(addr) {
@@ -39,12 +39,12 @@ use bit::BitIndex;
 */
 
 /// A function that can execute an ARM instruction.
-type ArmHandler = fn(&mut Gba, inst: u32) -> InstructionResult;
+pub(super) type ArmHandler = fn(&mut Gba, inst: u32) -> InstructionResult;
 
-/// Dummy unimplemented / invalid ARM instruction.
-fn arm_unimplemented(_s: &mut Gba, inst: u32) -> InstructionResult {
+/// Undefined ARM instruction (including coprocessor instructions, since there aren't any).
+fn arm_undefined(s: &mut Gba, inst: u32) -> InstructionResult {
     eprintln!(
-        "Unknown ARM instruction: {:08x} / {:04b}[{:04b} {:04b}]{:04b}_{:04b}_{:04b}[{:04b}]{:04b}",
+        "Undefined ARM instruction: {:08x} / {:04b}[{:04b} {:04b}]{:04b}_{:04b}_{:04b}[{:04b}]{:04b}",
         inst,
         (inst >> 28) & 0xf,
         (inst >> 24) & 0xf,
@@ -55,7 +55,9 @@ fn arm_unimplemented(_s: &mut Gba, inst: u32) -> InstructionResult {
         (inst >> 4) & 0xf,
         (inst >> 0) & 0xf,
     );
-    InstructionResult::Normal
+    let return_address = s.cpu_arm_pc() + 4;
+    s.cpu_exception(ExceptionType::Undefined, return_address);
+    InstructionResult::Branch
 }
 
 /// Branch, branch-and-link.
@@ -195,9 +197,9 @@ fn arm_exec_ldr_str_word_byte<
     let reg_d = inst.bit_range(12..16) as usize; // load/store register
     let reg_n = inst.bit_range(16..20) as usize; // addressing register
 
-    if !PREINDEX && WRITEBACK {
-        todo!("Handle LDR/STR [T] instructions");
-    }
+    // With post-indexing, W=1 means LDRT/STRT/LDRBT/STRBT: the access is done as if in
+    // User mode. There's no memory protection on the GBA, so that's the same as a normal
+    // access (and writeback always happens with post-indexing anyway).
 
     let offset = if !IMMEDIATE {
         // IMMEDIATE=0 actually means... use the immediate.
@@ -639,6 +641,13 @@ fn arm_exec_swi(s: &mut Gba, _inst: u32) -> InstructionResult {
 // Include look-up table for instruction handlers.
 include!(concat!(env!("OUT_DIR"), "/arm_table.rs"));
 
+/// Decode an ARM instruction into its condition and handler.
+#[inline(always)]
+pub(super) fn arm_decode(inst: u32) -> (Condition, ArmHandler) {
+    let key = (((inst >> 16) & 0xff0) | ((inst >> 4) & 0xf)) as usize;
+    (inst.bit_range(28..32).into(), ARM_HANDLERS[key])
+}
+
 impl Gba {
     /// Get the program counter of the *currently executing ARM instruction*.
     pub fn cpu_arm_pc(&self) -> u32 {
@@ -648,10 +657,9 @@ impl Gba {
 
     /// Execute the given ARM instruction.
     pub(super) fn cpu_execute_arm(&mut self, inst: u32) -> InstructionResult {
-        let condition: Condition = inst.bit_range(28..32).into();
+        let (condition, handler) = arm_decode(inst);
         if condition.evaluate(self) {
-            let key = (((inst >> 16) & 0xff0) | ((inst >> 4) & 0xf)) as usize;
-            (ARM_HANDLERS[key])(self, inst)
+            handler(self, inst)
         } else {
             InstructionResult::Normal
         }
@@ -0,0 +1,426 @@
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+use std::cell::{Cell, OnceCell};
+use std::{collections::HashMap, rc::Rc};
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+use super::jit::{JitBlock, JitState, JIT_THRESHOLD};
+use super::{
+    arm::{self, ArmHandler},
+    cond::Condition,
+    thumb::{self, ThumbHandler},
+    CpuExecutionState, InstructionResult, MemoryAccessType,
+};
+use crate::{
+    bus::{region_from_address, REGION_CART_WS0_A, REGION_CART_WS1_B, REGION_EWRAM, REGION_IWRAM},
+    io::CpuPowerState,
+    Gba, Memory,
+};
+
+/// Maximum number of instructions in a block (not counting the two fetched ahead).
+const MAX_BLOCK_SIZE: usize = 32;
+
+/// Size of the pages that code in RAM is tracked in, for invalidation.
+pub(super) const PAGE_SHIFT: u32 = 8;
+
+/// Number of pages: EWRAM, then IWRAM.
+const PAGE_COUNT: usize = (256 * 1024 + 32 * 1024) >> PAGE_SHIFT;
+
+/// A cached ARM instruction.
+#[derive(Clone, Copy)]
+struct ArmOp {
+    inst: u32,
+    condition: Condition,
+    handler: ArmHandler,
+}
+
+/// A cached Thumb instruction.
+#[derive(Clone, Copy)]
+struct ThumbOp {
+    inst: u16,
+    handler: ThumbHandler,
+}
+
+/// Decoded instructions of a block.
+enum Ops {
+    Arm(Box<[ArmOp]>),
+    Thumb(Box<[ThumbOp]>),
+}
+
+/// A decoded block of consecutive instructions.
+///
+/// The last two instructions are only there to fill the pipeline, so they're never
+/// executed from this block.
+struct Block {
+    /// Address of the first instruction.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    start: u32,
+
+    ops: Ops,
+
+    /// Number of times the block has run.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    hits: Cell<u32>,
+
+    /// Native code, once the block is hot (or None if it couldn't be compiled).
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    jit: OnceCell<Option<JitBlock>>,
+}
+
+/// Cache of decoded blocks of code.
+///
+/// This avoids decoding instructions and going through the general instruction fetch path
+/// on every step. The timing and pipeline contents are exactly the same as the regular
+/// interpreter, which is still used for anything that can't be cached.
+///
+/// Only ROM and work RAM code is cached. Writes to work RAM invalidate any blocks in
+/// the same page.
+#[derive(Default)]
+pub struct BlockCache {
+    /// Whether the cache is used.
+    pub enabled: bool,
+
+    /// Blocks by start address (with bit 0 set for Thumb code).
+    blocks: HashMap<u32, Rc<Block>>,
+
+    /// The keys of the blocks in each page of work RAM.
+    pages: Vec<Vec<u32>>,
+
+    /// Whether each page of work RAM has any blocks (for the native code, which can't
+    /// look at `pages`).
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    code_pages: Vec<bool>,
+
+    /// Set when blocks are invalidated, so the running block can stop.
+    invalidated: bool,
+}
+
+impl BlockCache {
+    /// Remove all blocks.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.iter_mut().for_each(|keys| keys.clear());
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        self.code_pages.fill(false);
+        self.invalidated = true;
+    }
+
+    /// Allocate the page tables, the first time they're needed.
+    fn init_pages(&mut self) {
+        if self.pages.is_empty() {
+            self.pages = vec![Vec::new(); PAGE_COUNT];
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            {
+                self.code_pages = vec![false; PAGE_COUNT];
+            }
+        }
+    }
+}
+
+/// Get the work RAM page of an address, or None if it's not in work RAM.
+#[inline(always)]
+fn ram_page(addr: u32) -> Option<usize> {
+    match region_from_address(addr) {
+        REGION_EWRAM => Some(((addr & 0x3FFFF) >> PAGE_SHIFT) as usize),
+        REGION_IWRAM => Some(((256 * 1024 + (addr & 0x7FFF)) >> PAGE_SHIFT) as usize),
+        _ => None,
+    }
+}
+
+impl Gba {
+    /// Invalidate cached code after a write to work RAM.
+    #[inline(always)]
+    pub(crate) fn cpu_cache_notify_write(&mut self, addr: u32) {
+        let cache = &mut self.cpu_cache;
+        if let Some(page) = ram_page(addr) {
+            match cache.pages.get_mut(page) {
+                Some(keys) if !keys.is_empty() => {
+                    for key in keys.drain(..) {
+                        cache.blocks.remove(&key);
+                    }
+                    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+                    {
+                        cache.code_pages[page] = false;
+                    }
+                    cache.invalidated = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Read an instruction for the cache, without side effects.
+    ///
+    /// Returns None if the address can't be cached.
+    fn cpu_cache_read(&mut self, addr: u32, size: u32) -> Option<u32> {
+        match region_from_address(addr) {
+            REGION_EWRAM if self.io.memcnt.ewram_enabled() => Some(match size {
+                2 => self.ewram.read_16(addr & 0x3FFFF) as u32,
+                _ => self.ewram.read_32(addr & 0x3FFFF),
+            }),
+            REGION_IWRAM => Some(match size {
+                2 => self.iwram.read_16(addr & 0x7FFF) as u32,
+                _ => self.iwram.read_32(addr & 0x7FFF),
+            }),
+            REGION_CART_WS0_A..=REGION_CART_WS1_B => {
+                let offset = (addr & 0x01FF_FFFF) as usize;
+                // Avoid GPIO (and reading past the end of the ROM).
+                if (0xC4..=0xC9).contains(&offset) || offset + 4 > self.cart_rom.data.len() {
+                    return None;
+                }
+                Some(match size {
+                    2 => self.cart_rom.data.read_16(offset as u32) as u32,
+                    _ => self.cart_rom.data.read_32(offset as u32),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Decode a block starting at the given address.
+    fn cpu_cache_build(&mut self, start: u32, thumb: bool) -> Option<Rc<Block>> {
+        let size = if thumb { 2 } else { 4 };
+        let mut insts = Vec::with_capacity(MAX_BLOCK_SIZE + 2);
+        for i in 0..(MAX_BLOCK_SIZE as u32 + 2) {
+            let addr = start + i * size;
+            // Stop at the end of the region (or where work RAM mirrors back to its start).
+            if region_from_address(addr) != region_from_address(start)
+                || ram_page(addr) < ram_page(start)
+            {
+                break;
+            }
+            match self.cpu_cache_read(addr, size) {
+                Some(inst) => insts.push(inst),
+                None => break,
+            }
+        }
+        if insts.len() < 3 {
+            return None;
+        }
+
+        let ops = if thumb {
+            Ops::Thumb(
+                insts
+                    .iter()
+                    .map(|&inst| ThumbOp {
+                        inst: inst as u16,
+                        handler: thumb::thumb_decode(inst as u16),
+                    })
+                    .collect(),
+            )
+        } else {
+            Ops::Arm(
+                insts
+                    .iter()
+                    .map(|&inst| {
+                        let (condition, handler) = arm::arm_decode(inst);
+                        ArmOp {
+                            inst,
+                            condition,
+                            handler,
+                        }
+                    })
+                    .collect(),
+            )
+        };
+
+        // Track which pages of work RAM the block came from.
+        let key = start | (thumb as u32);
+        let end = start + (insts.len() as u32 - 1) * size;
+        if let Some(first) = ram_page(start) {
+            let cache = &mut self.cpu_cache;
+            cache.init_pages();
+            for page in first..=ram_page(end).unwrap_or(first) {
+                cache.pages[page].push(key);
+                #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+                {
+                    cache.code_pages[page] = true;
+                }
+            }
+        }
+
+        let block = Rc::new(Block {
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            start,
+            ops,
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            hits: Cell::new(0),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            jit: OnceCell::new(),
+        });
+        self.cpu_cache.blocks.insert(key, block.clone());
+        Some(block)
+    }
+
+    /// Run instructions from the block cache, until a branch, the end of the block, or
+    /// something that the main loop has to handle (an event, IRQ, DMA, halt, or idle loop).
+    ///
+    /// Returns false without doing anything if the current code can't be cached.
+    pub(crate) fn cpu_run_cached(&mut self) -> bool {
+        let thumb = self.cpu.cpsr.execution_state == CpuExecutionState::Thumb;
+        let start = if thumb {
+            self.cpu_thumb_pc()
+        } else {
+            self.cpu_arm_pc()
+        };
+        let key = start | (thumb as u32);
+        let block = match self.cpu_cache.blocks.get(&key) {
+            Some(block) => block.clone(),
+            None => match self.cpu_cache_build(start, thumb) {
+                Some(block) => block,
+                None => return false,
+            },
+        };
+
+        self.cpu_cache.invalidated = false;
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        if self.cpu_run_jit(&block) {
+            return true;
+        }
+        match &block.ops {
+            Ops::Thumb(ops) => self.cpu_run_block_thumb(ops),
+            Ops::Arm(ops) => self.cpu_run_block_arm(ops),
+        }
+        true
+    }
+
+    /// Run the native code for a block, compiling it if it's become hot.
+    ///
+    /// Returns false if the block should run in the interpreter instead.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    fn cpu_run_jit(&mut self, block: &Block) -> bool {
+        let hits = block.hits.get() + 1;
+        block.hits.set(hits);
+        if hits < JIT_THRESHOLD {
+            return false;
+        }
+
+        // The native code assumes the pipeline holds the block's first instructions, and
+        // that the next fetch is sequential.
+        let (first, thumb) = match &block.ops {
+            Ops::Arm(ops) => ([ops[0].inst, ops[1].inst], false),
+            Ops::Thumb(ops) => ([ops[0].inst as u32, ops[1].inst as u32], true),
+        };
+        let ready = self.cpu.pipeline == first
+            && self.cpu.next_fetch_access == MemoryAccessType::Sequential;
+        let jit = block.jit.get_or_init(|| {
+            let insts: Vec<u32> = match &block.ops {
+                Ops::Arm(ops) => ops.iter().map(|op| op.inst).collect(),
+                Ops::Thumb(ops) => ops.iter().map(|op| op.inst as u32).collect(),
+            };
+            JitBlock::compile(block.start, &insts, thumb)
+        });
+        match jit {
+            Some(jit) if ready => {
+                // The page table is updated while the block runs, so it can't be borrowed.
+                self.cpu_cache.init_pages();
+                let code_pages = self.cpu_cache.code_pages.as_ptr();
+                let state = JitState::new(self, code_pages);
+                jit.run(self, &state);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether native code should stop after an instruction that called into the
+    /// emulator. The native code checks for events itself.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    pub(super) fn cpu_jit_should_exit(&self) -> bool {
+        self.cpu_cache.invalidated
+            || self.io.power_state != CpuPowerState::Normal
+            || self.dma_active()
+            || self.interrupt_pending()
+    }
+
+    /// Number of blocks that have been compiled to native code.
+    #[cfg(all(test, feature = "jit"))]
+    pub(super) fn cpu_cache_compiled_blocks(&self) -> usize {
+        self.cpu_cache
+            .blocks
+            .values()
+            .filter(|block| matches!(block.jit.get(), Some(Some(_))))
+            .count()
+    }
+
+    /// Whether the cached block should stop running, to go back to the main loop.
+    #[inline(always)]
+    pub(super) fn cpu_cache_should_stop(&self) -> bool {
+        self.scheduler.timestamp() >= self.scheduler.peek_deadline().unwrap()
+            || self.cpu_cache.invalidated
+            || self.idle_loop.idle
+            || self.io.power_state != CpuPowerState::Normal
+            || self.dma_active()
+            || self.interrupt_pending()
+    }
+
+    /// Run a Thumb block. Same as `cpu_step`, but with instructions from the cache.
+    fn cpu_run_block_thumb(&mut self, ops: &[ThumbOp]) {
+        for i in 0..(ops.len() - 2) {
+            let inst_addr = self.cpu_thumb_pc();
+            let inst = self.cpu.pipeline[0] as u16;
+            self.cpu.pipeline[0] = self.cpu.pipeline[1];
+
+            let next = ops[i + 2].inst;
+            self.cpu_fetch16_cached(self.cpu.pc, self.cpu.next_fetch_access, next);
+            self.cpu.pipeline[1] = next as u32;
+
+            // The pipeline may hold an old version of the instruction.
+            let result = if inst == ops[i].inst {
+                (ops[i].handler)(self, inst)
+            } else {
+                self.cpu_execute_thumb(inst)
+            };
+            match result {
+                InstructionResult::Normal => {
+                    self.cpu.pc += 2;
+                    self.cpu.next_fetch_access = MemoryAccessType::Sequential;
+                }
+                InstructionResult::Branch => {
+                    self.idle_on_branch(inst_addr);
+                    return;
+                }
+            }
+
+            if self.cpu_cache_should_stop() {
+                return;
+            }
+        }
+    }
+
+    /// Run an ARM block. Same as `cpu_step`, but with instructions from the cache.
+    fn cpu_run_block_arm(&mut self, ops: &[ArmOp]) {
+        for i in 0..(ops.len() - 2) {
+            let inst_addr = self.cpu_arm_pc();
+            let inst = self.cpu.pipeline[0];
+            self.cpu.pipeline[0] = self.cpu.pipeline[1];
+
+            let next = ops[i + 2].inst;
+            self.cpu_fetch32_cached(self.cpu.pc, self.cpu.next_fetch_access, next);
+            self.cpu.pipeline[1] = next;
+
+            // The pipeline may hold an old version of the instruction.
+            let op = &ops[i];
+            let result = if inst != op.inst {
+                self.cpu_execute_arm(inst)
+            } else if op.condition.evaluate(self) {
+                (op.handler)(self, inst)
+            } else {
+                InstructionResult::Normal
+            };
+            match result {
+                InstructionResult::Normal => {
+                    self.cpu.pc += 4;
+                    self.cpu.next_fetch_access = MemoryAccessType::Sequential;
+                }
+                InstructionResult::Branch => {
+                    self.idle_on_branch(inst_addr);
+                    return;
+                }
+            }
+
+            if self.cpu_cache_should_stop() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,2298 @@
+//! Dynamic recompiler for x86-64 Linux (enabled with the `jit` feature).
+//!
+//! Hot blocks from the block cache are translated to native code. Data processing
+//! instructions with an immediate shift, single loads and stores, and branches are
+//! translated directly. Everything else (multiplies, register shifts, PSR transfers, block
+//! transfers, writes to the PC, ...) calls its interpreter handler.
+//!
+//! Registers and flags stay in [`Gba`], so the native code and the interpreter can hand
+//! over at any instruction. Cycles are counted in a register, and only given to the
+//! scheduler before something that can observe them (a call into the emulator) and at the
+//! end of the block. Code fetches from ROM still go through the prefetch buffer then.
+//!
+//! A block exits early when it branches, when a store or interpreted instruction needs
+//! the main loop (invalidated code, a write to I/O, DMA, an IRQ, or halting), or when the
+//! next scheduler event may be due. After each instruction, the time (with an upper bound
+//! for ROM fetches that haven't been timed yet) is compared with the next event's
+//! deadline, and the block syncs and exits if it has been reached. So it never runs past
+//! an event, and the interpreters take over to stop at the exact instruction.
+//!
+//! Loads and stores to work RAM are done inline. Stores check whether the page holds
+//! cached code, and go through the bus if it does (to invalidate it).
+//!
+//! The native code belongs to its block, so it's freed when the block is invalidated.
+
+use std::arch::asm;
+use std::mem::{offset_of, take};
+
+use super::cache::PAGE_SHIFT;
+use super::{CpuExecutionState, InstructionResult, MemoryAccessType};
+use crate::bus::{
+    region_from_address, BUS_DATA_VALUE, REGION_CART_WS0_A, REGION_EWRAM, REGION_IO, REGION_IWRAM,
+};
+use crate::scheduler::{SCHEDULER_NEXT_DEADLINE, SCHEDULER_TIME};
+use crate::Gba;
+
+/// Number of times a block has to run before it's compiled.
+pub const JIT_THRESHOLD: u32 = 64;
+
+// Fields of `Gba` that the native code accesses directly.
+const GPR: i32 = offset_of!(Gba, cpu.gpr) as i32;
+const PC: i32 = offset_of!(Gba, cpu.pc) as i32;
+const PIPELINE: i32 = offset_of!(Gba, cpu.pipeline) as i32;
+const FLAG_N: i32 = offset_of!(Gba, cpu.cpsr.cond_flag_n) as i32;
+const FLAG_Z: i32 = offset_of!(Gba, cpu.cpsr.cond_flag_z) as i32;
+const FLAG_C: i32 = offset_of!(Gba, cpu.cpsr.cond_flag_c) as i32;
+const FLAG_V: i32 = offset_of!(Gba, cpu.cpsr.cond_flag_v) as i32;
+const IDLE_READS: i32 = offset_of!(Gba, idle_loop.reads) as i32;
+const IDLE_WROTE: i32 = offset_of!(Gba, idle_loop.wrote) as i32;
+const DATA_VALUE: i32 = (offset_of!(Gba, bus) + BUS_DATA_VALUE) as i32;
+const TIME: i32 = (offset_of!(Gba, scheduler) + SCHEDULER_TIME) as i32;
+const NEXT_DEADLINE: i32 = (offset_of!(Gba, scheduler) + SCHEDULER_NEXT_DEADLINE) as i32;
+
+/// Offset of a general purpose register (r0-r14).
+fn reg_offset(reg: u32) -> i32 {
+    GPR + 4 * reg as i32
+}
+
+/// Memory that the native code accesses directly. This can move (e.g. when a save state
+/// is loaded), so it's passed in on every run.
+#[repr(C)]
+pub struct JitState {
+    iwram: *mut u8,
+
+    /// Null while EWRAM is disabled.
+    ewram: *mut u8,
+
+    /// Whether each page of work RAM holds cached code.
+    code_pages: *const bool,
+
+    /// Cycles for 16-bit and 32-bit EWRAM accesses.
+    ewram_cycles: [usize; 2],
+
+    /// Upper bound on the cycles of a code fetch from each ROM waitstate region.
+    rom_fetch_cycles: [usize; 3],
+}
+
+const STATE_IWRAM: i32 = offset_of!(JitState, iwram) as i32;
+const STATE_EWRAM: i32 = offset_of!(JitState, ewram) as i32;
+const STATE_CODE_PAGES: i32 = offset_of!(JitState, code_pages) as i32;
+const STATE_EWRAM_CYCLES: i32 = offset_of!(JitState, ewram_cycles) as i32;
+const STATE_ROM_FETCH_CYCLES: i32 = offset_of!(JitState, rom_fetch_cycles) as i32;
+
+impl JitState {
+    /// `code_pages` must point to an entry for each page of work RAM.
+    pub fn new(gba: &mut Gba, code_pages: *const bool) -> JitState {
+        JitState {
+            iwram: gba.iwram.as_mut_ptr(),
+            ewram: if gba.io.memcnt.ewram_enabled() {
+                gba.ewram.as_mut_ptr()
+            } else {
+                std::ptr::null_mut()
+            },
+            code_pages,
+            ewram_cycles: gba.bus_ewram_cycles(),
+            rom_fetch_cycles: gba.bus_rom_fetch_cycles_bound(),
+        }
+    }
+}
+
+/// Native code for a block.
+pub struct JitBlock {
+    code: ExecutableMemory,
+}
+
+impl JitBlock {
+    /// Run the block. Returns after a branch, at the end of the block, or when the main
+    /// loop has something to handle.
+    pub fn run(&self, gba: &mut Gba, state: &JitState) {
+        let function: extern "C" fn(*mut Gba, *const JitState) =
+            unsafe { std::mem::transmute(self.code.ptr) };
+        function(gba, state);
+    }
+
+    /// Compile a block of instructions (Thumb ones zero extended), starting at `start`.
+    /// The last two instructions are only fetched.
+    pub fn compile(start: u32, insts: &[u32], thumb: bool) -> Option<JitBlock> {
+        let code = Compiler::new(start, insts, thumb).compile();
+        ExecutableMemory::new(&code).map(|code| JitBlock { code })
+    }
+}
+
+// Calls from the native code into the emulator. Before any of these, the pending cycles
+// have been given to the scheduler, and the PC and pipeline are as the interpreter would
+// have them while executing the instruction.
+
+/// Give `cycles` idle cycles to the scheduler, then do the timing for `fetches` code
+/// fetches from ROM, starting at `fetch_addr` (with the values at `values`).
+extern "C" fn jit_sync(
+    gba: &mut Gba,
+    cycles: usize,
+    fetch_addr: u32,
+    fetches: u32,
+    values: *const u32,
+) {
+    if cycles > 0 {
+        gba.scheduler.update(cycles);
+        gba.bus_idle_cycles(cycles);
+    }
+    let thumb = gba.cpu.cpsr.execution_state == CpuExecutionState::Thumb;
+    for i in 0..fetches {
+        let value = unsafe { values.add(i as usize).read_unaligned() };
+        if thumb {
+            gba.cpu_fetch16_cached(
+                fetch_addr + 2 * i,
+                MemoryAccessType::Sequential,
+                value as u16,
+            );
+        } else {
+            gba.cpu_fetch32_cached(fetch_addr + 4 * i, MemoryAccessType::Sequential, value);
+        }
+    }
+    gba.cpu.next_fetch_access = MemoryAccessType::Sequential;
+}
+
+extern "C" fn jit_load32(gba: &mut Gba, addr: u32) -> u32 {
+    gba.cpu_load32(addr, MemoryAccessType::NonSequential)
+}
+
+extern "C" fn jit_load16(gba: &mut Gba, addr: u32) -> u32 {
+    gba.cpu_load16(addr, MemoryAccessType::NonSequential) as u32
+}
+
+extern "C" fn jit_load8(gba: &mut Gba, addr: u32) -> u32 {
+    gba.cpu_load8(addr, MemoryAccessType::NonSequential) as u32
+}
+
+/// Whether the block has to exit after a store through the bus.
+fn jit_store_should_exit(gba: &Gba, addr: u32) -> bool {
+    region_from_address(addr) == REGION_IO || gba.cpu_jit_should_exit()
+}
+
+extern "C" fn jit_store32(gba: &mut Gba, addr: u32, value: u32) -> bool {
+    gba.cpu_store32(addr, value, MemoryAccessType::NonSequential);
+    jit_store_should_exit(gba, addr)
+}
+
+extern "C" fn jit_store16(gba: &mut Gba, addr: u32, value: u32) -> bool {
+    gba.cpu_store16(addr, value as u16, MemoryAccessType::NonSequential);
+    jit_store_should_exit(gba, addr)
+}
+
+extern "C" fn jit_store8(gba: &mut Gba, addr: u32, value: u32) -> bool {
+    gba.cpu_store8(addr, value as u8, MemoryAccessType::NonSequential);
+    jit_store_should_exit(gba, addr)
+}
+
+/// Execute an instruction with the interpreter, and finish it like `cpu_step`.
+/// Returns true if the block has to exit (it branched, or the main loop has something
+/// to handle).
+extern "C" fn jit_interpret(gba: &mut Gba, inst: u32, inst_addr: u32) -> bool {
+    let (result, size) = match gba.cpu.cpsr.execution_state {
+        CpuExecutionState::Thumb => (gba.cpu_execute_thumb(inst as u16), 2),
+        CpuExecutionState::Arm => (gba.cpu_execute_arm(inst), 4),
+    };
+    match result {
+        InstructionResult::Normal => {
+            gba.cpu.pc += size;
+            gba.cpu.next_fetch_access = MemoryAccessType::Sequential;
+            gba.cpu_jit_should_exit()
+        }
+        InstructionResult::Branch => {
+            gba.idle_on_branch(inst_addr);
+            true
+        }
+    }
+}
+
+/// Branch to `target` from the instruction at `inst_addr`.
+extern "C" fn jit_branch(gba: &mut Gba, target: u32, inst_addr: u32) {
+    gba.cpu_jump(target);
+    gba.idle_on_branch(inst_addr);
+}
+
+/// Where a block's code is. This decides how its fetches are timed.
+#[derive(Clone, Copy, PartialEq)]
+enum CodeRegion {
+    Iwram,
+    Ewram,
+    Rom,
+}
+
+/// How an instruction is compiled.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// Inline, and doesn't take any cycles besides its fetch.
+    Alu,
+    Load,
+    Store,
+    Branch,
+    Interpret,
+}
+
+/// Size of a memory access.
+#[derive(Clone, Copy, PartialEq)]
+enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    /// Mask to align an address for the access.
+    fn align_mask(self) -> u32 {
+        match self {
+            Width::Byte => !0,
+            Width::Half => !1,
+            Width::Word => !3,
+        }
+    }
+
+    /// Offset of the EWRAM access cycles in [`JitState`].
+    fn ewram_cycles(self) -> i32 {
+        match self {
+            Width::Word => STATE_EWRAM_CYCLES + 8,
+            _ => STATE_EWRAM_CYCLES,
+        }
+    }
+}
+
+/// What happens to a loaded value, like the interpreter does for misaligned and signed
+/// loads.
+#[derive(Clone, Copy, PartialEq)]
+enum Extend {
+    /// Zero extend, and rotate misaligned words and halfwords.
+    Rotate,
+    /// Sign extend (shifting misaligned halfwords instead).
+    Signed,
+}
+
+/// Where the shifter's carry out ends up.
+#[derive(Clone, Copy)]
+enum ShiftCarry {
+    /// The C flag is unchanged.
+    Unchanged,
+    Const(bool),
+    /// In dl.
+    Dl,
+}
+
+/// Classify an ARM instruction (other than ones with the never condition).
+fn arm_kind(inst: u32) -> Kind {
+    let reg_n = (inst >> 16) & 0xF;
+    let reg_d = (inst >> 12) & 0xF;
+    let reg_m = inst & 0xF;
+    let pre_index = inst & (1 << 24) != 0;
+    let write_back = inst & (1 << 21) != 0 || !pre_index;
+    let load = inst & (1 << 20) != 0;
+    let transfer = |register_offset: bool| {
+        if reg_d == 15 && load || reg_n == 15 && write_back || register_offset && reg_m == 15 {
+            Kind::Interpret
+        } else if load {
+            Kind::Load
+        } else {
+            Kind::Store
+        }
+    };
+    match (inst >> 25) & 0b111 {
+        // Halfword and signed transfers (the rest of this space is multiplies and swaps).
+        0b000 if inst & 0x90 == 0x90 => {
+            let op = (inst >> 5) & 0b11;
+            if op == 0 || (op != 1 && !load) || reg_d == 15 {
+                Kind::Interpret
+            } else {
+                transfer(inst & (1 << 22) == 0)
+            }
+        }
+        // Data processing, except for the PSR transfers and BX in the test opcodes.
+        0b000 | 0b001 if (inst >> 23) & 0b11 != 0b10 || inst & (1 << 20) != 0 => {
+            let register_shift = inst & (1 << 25) == 0 && inst & (1 << 4) != 0;
+            if reg_d == 15 || register_shift {
+                Kind::Interpret
+            } else {
+                Kind::Alu
+            }
+        }
+        0b010 => transfer(false),
+        0b011 if inst & (1 << 4) == 0 => transfer(true),
+        0b101 => Kind::Branch,
+        _ => Kind::Interpret,
+    }
+}
+
+/// Classify a Thumb instruction.
+fn thumb_kind(inst: u16) -> Kind {
+    let load = inst & (1 << 11) != 0;
+    let load_or_store = if load { Kind::Load } else { Kind::Store };
+    match inst >> 11 {
+        // Move shifted register, add/subtract, and immediate operations.
+        0b00000..=0b00111 => Kind::Alu,
+        // ALU operations, except for register shifts and multiplies.
+        0b01000 if inst & (1 << 10) == 0 => match (inst >> 6) & 0xF {
+            2 | 3 | 4 | 7 | 13 => Kind::Interpret,
+            _ => Kind::Alu,
+        },
+        // Hi register operations, except for writing the PC and BX.
+        0b01000 => {
+            let op = (inst >> 8) & 0b11;
+            let reg_d = (inst & 0b111) | ((inst >> 4) & 0b1000);
+            if op == 3 || (op != 1 && reg_d == 15) {
+                Kind::Interpret
+            } else {
+                Kind::Alu
+            }
+        }
+        // PC relative load.
+        0b01001 => Kind::Load,
+        // Load/store with register offset, or sign extended.
+        0b01010 | 0b01011 => match (inst >> 9) & 0b111 {
+            0..=2 => Kind::Store,
+            _ => Kind::Load,
+        },
+        // Load/store with immediate offset, halfwords, and SP relative.
+        0b01100..=0b10011 => load_or_store,
+        // Load address.
+        0b10100 | 0b10101 => Kind::Alu,
+        // Add offset to SP.
+        0b10110 if inst >> 8 == 0b1011_0000 => Kind::Alu,
+        // Conditional branch (the last two conditions are undefined and SWI).
+        0b11010 | 0b11011 if (inst >> 8) & 0xF < 0b1110 => Kind::Branch,
+        // Unconditional branch, and the second half of BL.
+        0b11100 | 0b11111 => Kind::Branch,
+        // First half of BL.
+        0b11110 => Kind::Alu,
+        _ => Kind::Interpret,
+    }
+}
+
+// Registers used by the native code. rbx holds the `Gba`, r13 the `JitState`, and r12 the
+// cycles that haven't been given to the scheduler yet. r14 and rbp hold the address and
+// value of a memory access across calls, and r15b is set when the block has to exit after
+// the current instruction.
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSP: u8 = 4;
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R8: u8 = 8;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// Condition codes.
+const CC_O: u8 = 0x0;
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_S: u8 = 0x8;
+
+// Opcodes of register to register ALU operations.
+const OP_ADD: u8 = 0x01;
+const OP_OR: u8 = 0x09;
+const OP_ADC: u8 = 0x11;
+const OP_SBB: u8 = 0x19;
+const OP_AND: u8 = 0x21;
+const OP_SUB: u8 = 0x29;
+const OP_XOR: u8 = 0x31;
+const OP_TEST: u8 = 0x85;
+
+// Opcode extensions of ALU operations with an immediate.
+const EXT_ADD: u8 = 0;
+const EXT_AND: u8 = 4;
+const EXT_SUB: u8 = 5;
+const EXT_XOR: u8 = 6;
+const EXT_CMP: u8 = 7;
+
+// Opcode extensions of shifts.
+const EXT_ROL: u8 = 0;
+const EXT_ROR: u8 = 1;
+const EXT_RCR: u8 = 3;
+const EXT_SHL: u8 = 4;
+const EXT_SHR: u8 = 5;
+const EXT_SAR: u8 = 7;
+
+/// A position in the code, which jumps can target before it's known.
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+/// Minimal x86-64 assembler for the code we generate.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+
+    /// Positions of the labels, once bound.
+    labels: Vec<Option<usize>>,
+
+    /// rel32 displacements to fill in: position, label, and addend.
+    fixups: Vec<(usize, Label, i32)>,
+}
+
+impl Assembler {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// A rel32 displacement to a label (plus `addend` bytes).
+    fn rel32(&mut self, label: Label, addend: i32) {
+        self.fixups.push((self.code.len(), label, addend));
+        self.u32(0);
+    }
+
+    /// A REX prefix, if needed. `force` is for byte registers 4-7 (spl-dil, not ah-bh).
+    fn rex(&mut self, wide: bool, reg: u8, index: u8, base: u8, force: bool) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3;
+        if rex != 0x40 || force {
+            self.bytes(&[rex]);
+        }
+    }
+
+    /// An instruction with a register operand.
+    fn op_rr(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, 0, rm, false);
+        self.bytes(opcode);
+        self.bytes(&[0xC0 | (reg & 7) << 3 | rm & 7]);
+    }
+
+    /// An instruction with a `[base + disp32]` operand.
+    fn op_rm(&mut self, wide: bool, opcode: &[u8], reg: u8, base: u8, disp: i32) {
+        self.rex(wide, reg, 0, base, false);
+        self.bytes(opcode);
+        self.bytes(&[0x80 | (reg & 7) << 3 | base & 7]);
+        if base & 7 == RSP {
+            self.bytes(&[0x24]);
+        }
+        self.u32(disp as u32);
+    }
+
+    /// An instruction with a `[base + index]` operand (base can't be rbp or r13).
+    fn op_rx(&mut self, opcode: &[u8], reg: u8, base: u8, index: u8, force: bool) {
+        self.rex(false, reg, index, base, force);
+        self.bytes(opcode);
+        self.bytes(&[0x04 | (reg & 7) << 3, (index & 7) << 3 | base & 7]);
+    }
+
+    fn mov_rr(&mut self, dest: u8, src: u8) {
+        self.op_rr(false, &[0x89], src, dest);
+    }
+
+    fn mov64_rr(&mut self, dest: u8, src: u8) {
+        self.op_rr(true, &[0x89], src, dest);
+    }
+
+    fn mov_ri(&mut self, dest: u8, value: u32) {
+        self.rex(false, 0, 0, dest, false);
+        self.bytes(&[0xB8 | dest & 7]);
+        self.u32(value);
+    }
+
+    fn load(&mut self, dest: u8, base: u8, disp: i32) {
+        self.op_rm(false, &[0x8B], dest, base, disp);
+    }
+
+    fn load64(&mut self, dest: u8, base: u8, disp: i32) {
+        self.op_rm(true, &[0x8B], dest, base, disp);
+    }
+
+    fn load8(&mut self, dest: u8, base: u8, disp: i32) {
+        self.op_rm(false, &[0x8A], dest, base, disp);
+    }
+
+    fn store(&mut self, base: u8, disp: i32, src: u8) {
+        self.op_rm(false, &[0x89], src, base, disp);
+    }
+
+    fn store8(&mut self, base: u8, disp: i32, src: u8) {
+        self.op_rm(false, &[0x88], src, base, disp);
+    }
+
+    fn store_i(&mut self, base: u8, disp: i32, value: u32) {
+        self.op_rm(false, &[0xC7], 0, base, disp);
+        self.u32(value);
+    }
+
+    fn store8_i(&mut self, base: u8, disp: i32, value: u8) {
+        self.op_rm(false, &[0xC6], 0, base, disp);
+        self.bytes(&[value]);
+    }
+
+    /// Load from `[base + index]`, zero extended.
+    fn load_x(&mut self, width: Width, dest: u8, base: u8, index: u8) {
+        match width {
+            Width::Byte => self.op_rx(&[0x0F, 0xB6], dest, base, index, false),
+            Width::Half => self.op_rx(&[0x0F, 0xB7], dest, base, index, false),
+            Width::Word => self.op_rx(&[0x8B], dest, base, index, false),
+        }
+    }
+
+    /// Store to `[base + index]`.
+    fn store_x(&mut self, width: Width, base: u8, index: u8, src: u8) {
+        match width {
+            Width::Byte => self.op_rx(&[0x88], src, base, index, src >= 4),
+            Width::Half => {
+                self.bytes(&[0x66]);
+                self.op_rx(&[0x89], src, base, index, false);
+            }
+            Width::Word => self.op_rx(&[0x89], src, base, index, false),
+        }
+    }
+
+    /// `cmp byte [base + index], 0`.
+    fn cmp8_x_zero(&mut self, base: u8, index: u8) {
+        self.op_rx(&[0x80], EXT_CMP, base, index, false);
+        self.bytes(&[0]);
+    }
+
+    /// `cmp byte [base + disp32], value`.
+    fn cmp8_mi(&mut self, base: u8, disp: i32, value: u8) {
+        self.op_rm(false, &[0x80], EXT_CMP, base, disp);
+        self.bytes(&[value]);
+    }
+
+    /// `cmp reg8, [base + disp32]`.
+    fn cmp8_rm(&mut self, reg: u8, base: u8, disp: i32) {
+        self.op_rm(false, &[0x3A], reg, base, disp);
+    }
+
+    /// A register to register ALU operation, like `add dest, src`.
+    fn alu(&mut self, opcode: u8, dest: u8, src: u8) {
+        self.op_rr(false, &[opcode], src, dest);
+    }
+
+    fn alu64(&mut self, opcode: u8, dest: u8, src: u8) {
+        self.op_rr(true, &[opcode], src, dest);
+    }
+
+    /// `or dest8, src8`.
+    fn or8(&mut self, dest: u8, src: u8) {
+        self.op_rr(false, &[0x08], src, dest);
+    }
+
+    /// `test reg8, reg8`.
+    fn test8(&mut self, reg: u8) {
+        self.op_rr(false, &[0x84], reg, reg);
+    }
+
+    /// An ALU operation with an immediate, like `add dest, value`.
+    fn alu_i(&mut self, ext: u8, dest: u8, value: u32) {
+        self.op_rr(false, &[0x81], ext, dest);
+        self.u32(value);
+    }
+
+    fn alu64_i(&mut self, ext: u8, dest: u8, value: u32) {
+        self.op_rr(true, &[0x81], ext, dest);
+        self.u32(value);
+    }
+
+    /// An ALU operation on memory with an immediate, like `xor dword [base + disp32], value`.
+    fn alu_mi(&mut self, ext: u8, base: u8, disp: i32, value: u32) {
+        self.op_rm(false, &[0x81], ext, base, disp);
+        self.u32(value);
+    }
+
+    /// `add dest, qword [base + disp32]`.
+    fn add64_rm(&mut self, dest: u8, base: u8, disp: i32) {
+        self.op_rm(true, &[0x03], dest, base, disp);
+    }
+
+    /// `cmp reg, qword [base + disp32]`.
+    fn cmp64_rm(&mut self, reg: u8, base: u8, disp: i32) {
+        self.op_rm(true, &[0x3B], reg, base, disp);
+    }
+
+    /// `imul dest, qword [base + disp32], value`.
+    fn imul64_rmi(&mut self, dest: u8, base: u8, disp: i32, value: u32) {
+        self.op_rm(true, &[0x69], dest, base, disp);
+        self.u32(value);
+    }
+
+    /// `imul dest, src, value`.
+    fn imul_rri(&mut self, dest: u8, src: u8, value: u32) {
+        self.op_rr(false, &[0x69], dest, src);
+        self.u32(value);
+    }
+
+    fn shift_i(&mut self, ext: u8, dest: u8, amount: u32) {
+        self.op_rr(false, &[0xC1], ext, dest);
+        self.bytes(&[amount as u8]);
+    }
+
+    /// Shift `dword [base + disp32]`.
+    fn shift_mi(&mut self, ext: u8, base: u8, disp: i32, amount: u32) {
+        self.op_rm(false, &[0xC1], ext, base, disp);
+        self.bytes(&[amount as u8]);
+    }
+
+    /// Shift by cl.
+    fn shift_cl(&mut self, ext: u8, dest: u8) {
+        self.op_rr(false, &[0xD3], ext, dest);
+    }
+
+    fn not(&mut self, dest: u8) {
+        self.op_rr(false, &[0xF7], 2, dest);
+    }
+
+    /// `bt reg, bit`: CF = the bit.
+    fn bt(&mut self, reg: u8, bit: u32) {
+        self.op_rr(false, &[0x0F, 0xBA], 4, reg);
+        self.bytes(&[bit as u8]);
+    }
+
+    fn cmc(&mut self) {
+        self.bytes(&[0xF5]);
+    }
+
+    /// `movsx dest, src8`.
+    fn movsx8(&mut self, dest: u8, src: u8) {
+        self.op_rr(false, &[0x0F, 0xBE], dest, src);
+    }
+
+    /// `movsx dest, src16`.
+    fn movsx16(&mut self, dest: u8, src: u8) {
+        self.op_rr(false, &[0x0F, 0xBF], dest, src);
+    }
+
+    fn setcc(&mut self, cc: u8, dest: u8) {
+        self.op_rr(false, &[0x0F, 0x90 | cc], 0, dest);
+    }
+
+    /// `setcc byte [base + disp32]`.
+    fn setcc_m(&mut self, cc: u8, base: u8, disp: i32) {
+        self.op_rm(false, &[0x0F, 0x90 | cc], 0, base, disp);
+    }
+
+    /// `lea dest, [rip + label + addend]`.
+    fn lea_rip(&mut self, dest: u8, label: Label, addend: i32) {
+        self.rex(true, dest, 0, 0, false);
+        self.bytes(&[0x8D, 0x05 | (dest & 7) << 3]);
+        self.rel32(label, addend);
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg, false);
+        self.bytes(&[0x50 | reg & 7]);
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg, false);
+        self.bytes(&[0x58 | reg & 7]);
+    }
+
+    fn jcc(&mut self, cc: u8, label: Label) {
+        self.bytes(&[0x0F, 0x80 | cc]);
+        self.rel32(label, 0);
+    }
+
+    fn jmp(&mut self, label: Label) {
+        self.bytes(&[0xE9]);
+        self.rel32(label, 0);
+    }
+
+    /// Call a function (clobbering rax).
+    fn call(&mut self, function: *const ()) {
+        self.bytes(&[0x48, 0xB8]);
+        self.bytes(&(function as u64).to_le_bytes());
+        self.bytes(&[0xFF, 0xD0]);
+    }
+
+    fn ret(&mut self) {
+        self.bytes(&[0xC3]);
+    }
+
+    /// Fill in the displacements, and return the code.
+    fn finish(mut self) -> Vec<u8> {
+        for &(position, label, addend) in &self.fixups {
+            let target = self.labels[label.0].expect("unbound label") as i64 + addend as i64;
+            let displacement = (target - (position as i64 + 4)) as i32;
+            self.code[position..(position + 4)].copy_from_slice(&displacement.to_le_bytes());
+        }
+        self.code
+    }
+}
+
+/// A jump out of the block after an instruction, with what the compiler had left to time
+/// there.
+struct Exit {
+    label: Label,
+    index: usize,
+    synced: usize,
+    pending_cycles: u32,
+    pending_reads: (u32, u32),
+}
+
+/// Translates a block to native code.
+struct Compiler<'a> {
+    asm: Assembler,
+    start: u32,
+    insts: &'a [u32],
+    thumb: bool,
+    region: CodeRegion,
+
+    /// Size of an instruction.
+    size: u32,
+
+    /// Number of instructions whose fetch has been timed (only for ROM code).
+    synced: usize,
+
+    /// Cycles for work RAM fetches that haven't been added to r12 yet.
+    pending_cycles: u32,
+
+    /// Work RAM fetches that haven't been recorded for idle loop detection yet: how many,
+    /// and what they do to the signature after rotating it (see `idle_on_load`).
+    pending_reads: (u32, u32),
+
+    /// Exits after instructions that may have to stop the block.
+    exits: Vec<Exit>,
+
+    /// The epilogue.
+    ret: Label,
+
+    /// Copy of the instructions after the code (for timing ROM fetches).
+    data: Label,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(start: u32, insts: &'a [u32], thumb: bool) -> Compiler<'a> {
+        let mut asm = Assembler::default();
+        let ret = asm.label();
+        let data = asm.label();
+        Compiler {
+            asm,
+            start,
+            insts,
+            thumb,
+            region: match region_from_address(start) {
+                REGION_IWRAM => CodeRegion::Iwram,
+                REGION_EWRAM => CodeRegion::Ewram,
+                _ => CodeRegion::Rom,
+            },
+            size: if thumb { 2 } else { 4 },
+            synced: 0,
+            pending_cycles: 0,
+            pending_reads: (0, 0),
+            exits: Vec::new(),
+            ret,
+            data,
+        }
+    }
+
+    fn compile(mut self) -> Vec<u8> {
+        let a = &mut self.asm;
+        for reg in [RBX, RBP, R12, R13, R14, R15] {
+            a.push(reg);
+        }
+        // Keep the stack aligned for calls.
+        a.alu64_i(EXT_SUB, RSP, 8);
+        a.mov64_rr(RBX, RDI);
+        a.mov64_rr(R13, RSI);
+        a.alu(OP_XOR, R12, R12);
+        a.alu(OP_XOR, R15, R15);
+
+        let count = self.insts.len() - 2;
+        let mut ended = false;
+        for index in 0..count {
+            self.fetch(index);
+            let more = if self.thumb {
+                self.thumb(index)
+            } else {
+                self.arm(index)
+            };
+            if !more {
+                ended = true;
+                break;
+            }
+            if index + 1 < count {
+                self.exit_if_event_due(index);
+            }
+        }
+        if !ended {
+            self.exit_after(count - 1);
+        }
+
+        for exit in take(&mut self.exits) {
+            self.asm.bind(exit.label);
+            self.synced = exit.synced;
+            self.pending_cycles = exit.pending_cycles;
+            self.pending_reads = exit.pending_reads;
+            self.exit_after(exit.index);
+        }
+
+        let a = &mut self.asm;
+        a.bind(self.ret);
+        a.alu64_i(EXT_ADD, RSP, 8);
+        for reg in [R15, R14, R13, R12, RBP, RBX] {
+            a.pop(reg);
+        }
+        a.ret();
+
+        if self.region == CodeRegion::Rom {
+            while a.code.len() & 3 != 0 {
+                a.bytes(&[0xCC]);
+            }
+            a.bind(self.data);
+            for &inst in self.insts {
+                a.u32(inst);
+            }
+        }
+        self.asm.finish()
+    }
+
+    /// Address of an instruction.
+    fn inst_addr(&self, index: usize) -> u32 {
+        self.start + index as u32 * self.size
+    }
+
+    /// Account for the fetch done while executing an instruction (of the one two ahead).
+    fn fetch(&mut self, index: usize) {
+        let addr = self.inst_addr(index + 2);
+        let value = self.insts[index + 2];
+        match self.region {
+            // Timed when syncing.
+            CodeRegion::Rom => return,
+            CodeRegion::Iwram => self.pending_cycles += 1,
+            CodeRegion::Ewram => {
+                let width = if self.thumb { Width::Half } else { Width::Word };
+                self.asm.add64_rm(R12, R13, width.ewram_cycles());
+            }
+        }
+        let (count, xor) = self.pending_reads;
+        self.pending_reads = (count + 1, xor.rotate_left(5) ^ addr ^ value.rotate_left(16));
+    }
+
+    /// Emit the pending work RAM fetches.
+    fn flush(&mut self) {
+        if self.pending_cycles > 0 {
+            self.asm.alu64_i(EXT_ADD, R12, self.pending_cycles);
+            self.pending_cycles = 0;
+        }
+        let (count, xor) = self.pending_reads;
+        if count > 0 {
+            let rotate = (5 * count) % 32;
+            if rotate != 0 {
+                self.asm.shift_mi(EXT_ROL, RBX, IDLE_READS, rotate);
+            }
+            self.asm.alu_mi(EXT_XOR, RBX, IDLE_READS, xor);
+            self.pending_reads = (0, 0);
+        }
+    }
+
+    /// Give the pending cycles to the scheduler. For ROM code, also time the fetches up to
+    /// the one for instruction `through`.
+    fn sync(&mut self, through: Option<usize>) {
+        self.flush();
+        let a = &mut self.asm;
+        a.mov64_rr(RDI, RBX);
+        a.mov64_rr(RSI, R12);
+        match through {
+            Some(index) if self.region == CodeRegion::Rom && index >= self.synced => {
+                let first = self.synced;
+                a.mov_ri(RDX, self.start + (first as u32 + 2) * self.size);
+                a.mov_ri(RCX, (index + 1 - first) as u32);
+                a.lea_rip(R8, self.data, 4 * (first as i32 + 2));
+                self.synced = index + 1;
+            }
+            _ => {
+                a.alu(OP_XOR, RDX, RDX);
+                a.alu(OP_XOR, RCX, RCX);
+                a.alu(OP_XOR, R8, R8);
+            }
+        }
+        a.call(jit_sync as *const ());
+        a.alu(OP_XOR, R12, R12);
+    }
+
+    /// Get ready for an instruction that takes cycles or calls into the emulator, so the
+    /// timing happens in the same order as in the interpreter.
+    fn settle(&mut self, index: usize) {
+        match self.region {
+            CodeRegion::Rom => self.sync(Some(index)),
+            _ => self.flush(),
+        }
+    }
+
+    /// Get ready to call into the emulator during instruction `index` (after `settle`).
+    fn before_call(&mut self, index: usize) {
+        if self.region != CodeRegion::Rom {
+            self.sync(None);
+        }
+        self.set_pipeline(index, false);
+    }
+
+    /// Set the PC and pipeline as they are while executing instruction `index`, or after it.
+    fn set_pipeline(&mut self, index: usize, after: bool) {
+        let pc = self.inst_addr(index + 2 + after as usize);
+        let a = &mut self.asm;
+        a.store_i(RBX, PC, pc);
+        a.store_i(RBX, PIPELINE, self.insts[index + 1]);
+        a.store_i(RBX, PIPELINE + 4, self.insts[index + 2]);
+    }
+
+    /// Leave the block after instruction `index`.
+    fn exit_after(&mut self, index: usize) {
+        self.sync(Some(index));
+        self.set_pipeline(index, true);
+        self.asm.jmp(self.ret);
+    }
+
+    /// Jumps to `label` leave the block after instruction `index`, timing what hasn't been
+    /// timed at this point.
+    fn exit_later(&mut self, label: Label, index: usize) {
+        self.exits.push(Exit {
+            label,
+            index,
+            synced: self.synced,
+            pending_cycles: self.pending_cycles,
+            pending_reads: self.pending_reads,
+        });
+    }
+
+    /// After a store through the bus, exit if it asked to.
+    fn exit_if_requested(&mut self, index: usize) {
+        let label = self.asm.label();
+        self.asm.test8(R15);
+        self.asm.jcc(CC_NE, label);
+        self.exit_later(label, index);
+    }
+
+    /// Exit after instruction `index` if the next event may be due: if the time, plus the
+    /// cycles that haven't been given to the scheduler yet, has reached its deadline. ROM
+    /// fetches are only timed when syncing, so an upper bound is used for those, and the
+    /// block may exit a little early (the interpreters take over then).
+    fn exit_if_event_due(&mut self, index: usize) {
+        let a = &mut self.asm;
+        a.load64(RAX, RBX, TIME);
+        a.alu64(OP_ADD, RAX, R12);
+        if self.pending_cycles > 0 {
+            a.alu64_i(EXT_ADD, RAX, self.pending_cycles);
+        }
+        if self.region == CodeRegion::Rom && index >= self.synced {
+            let ws = (region_from_address(self.start) - REGION_CART_WS0_A) / 2;
+            let disp = STATE_ROM_FETCH_CYCLES + 8 * ws as i32;
+            a.imul64_rmi(RCX, R13, disp, (index + 1 - self.synced) as u32);
+            a.alu64(OP_ADD, RAX, RCX);
+        }
+        a.cmp64_rm(RAX, RBX, NEXT_DEADLINE);
+        let label = a.label();
+        a.jcc(CC_AE, label);
+        self.exit_later(label, index);
+    }
+
+    /// Jump past the instruction unless the condition passes (None if it always does).
+    fn skip_unless(&mut self, cond: u32) -> Option<Label> {
+        if cond == 0b1110 {
+            return None;
+        }
+        let a = &mut self.asm;
+        let skip = a.label();
+        let run = a.label();
+        // Skip if the flag is clear (even conditions) or set (odd ones).
+        let flag = |a: &mut Assembler, flag: i32, skip_if_set: bool| {
+            a.cmp8_mi(RBX, flag, 0);
+            a.jcc(if skip_if_set { CC_NE } else { CC_E }, skip);
+        };
+        // Skip if N == V (or N != V).
+        let n_v = |a: &mut Assembler, skip_if_equal: bool| {
+            a.load8(RAX, RBX, FLAG_N);
+            a.cmp8_rm(RAX, RBX, FLAG_V);
+            a.jcc(if skip_if_equal { CC_E } else { CC_NE }, skip);
+        };
+        match cond {
+            0b0000..=0b0111 => {
+                let flags = [FLAG_Z, FLAG_C, FLAG_N, FLAG_V];
+                flag(a, flags[cond as usize / 2], cond & 1 != 0);
+            }
+            // HI: C set and Z clear.
+            0b1000 => {
+                flag(a, FLAG_C, false);
+                flag(a, FLAG_Z, true);
+            }
+            // LS: C clear or Z set.
+            0b1001 => {
+                a.cmp8_mi(RBX, FLAG_C, 0);
+                a.jcc(CC_E, run);
+                flag(a, FLAG_Z, false);
+            }
+            0b1010 => n_v(a, false),
+            0b1011 => n_v(a, true),
+            // GT: Z clear and N == V.
+            0b1100 => {
+                flag(a, FLAG_Z, true);
+                n_v(a, false);
+            }
+            // LE: Z set or N != V.
+            _ => {
+                a.cmp8_mi(RBX, FLAG_Z, 0);
+                a.jcc(CC_NE, run);
+                n_v(a, true);
+            }
+        }
+        a.bind(run);
+        Some(skip)
+    }
+
+    /// Load a register, or the value of the PC.
+    fn load_reg(&mut self, dest: u8, reg: u32, pc: u32) {
+        if reg == 15 {
+            self.asm.mov_ri(dest, pc);
+        } else {
+            self.asm.load(dest, RBX, reg_offset(reg));
+        }
+    }
+
+    fn store_reg(&mut self, reg: u32, src: u8) {
+        self.asm.store(RBX, reg_offset(reg), src);
+    }
+
+    /// Set CF to the C flag.
+    fn carry_in(&mut self) {
+        self.asm.cmp8_mi(RBX, FLAG_C, 1);
+        self.asm.cmc();
+    }
+
+    /// Shift ecx by an immediate, like `shift_by_immediate`. Returns where the carry
+    /// out is, if `need_carry`.
+    fn shift_imm(&mut self, shift_type: u32, amount: u32, need_carry: bool) -> ShiftCarry {
+        let carry = if need_carry {
+            ShiftCarry::Dl
+        } else {
+            ShiftCarry::Unchanged
+        };
+        match (shift_type, amount) {
+            (0, 0) => return ShiftCarry::Unchanged,
+            // LSR #32 and ASR #32: the carry is bit 31.
+            (1 | 2, 0) => {
+                self.asm.bt(RCX, 31);
+                if need_carry {
+                    self.asm.setcc(CC_B, RDX);
+                }
+                match shift_type {
+                    1 => self.asm.alu(OP_XOR, RCX, RCX),
+                    _ => self.asm.shift_i(EXT_SAR, RCX, 31),
+                }
+                return carry;
+            }
+            // RRX.
+            (3, 0) => {
+                self.carry_in();
+                self.asm.shift_i(EXT_RCR, RCX, 1);
+            }
+            _ => {
+                let ext = [EXT_SHL, EXT_SHR, EXT_SAR, EXT_ROR][shift_type as usize];
+                self.asm.shift_i(ext, RCX, amount);
+            }
+        }
+        if need_carry {
+            self.asm.setcc(CC_B, RDX);
+        }
+        carry
+    }
+
+    /// Do an ARM data processing operation on eax and ecx, with the result in eax.
+    fn alu_op(&mut self, opcode: u32, set_flags: bool, carry: ShiftCarry) {
+        let logical = matches!(opcode, 0 | 1 | 8 | 9 | 12..=15);
+        // SBC and RSC subtract the inverted carry, which is what sbb does with CF clear.
+        if matches!(opcode, 6 | 7) {
+            self.asm.cmp8_mi(RBX, FLAG_C, 1);
+        } else if opcode == 5 {
+            self.carry_in();
+        }
+        let a = &mut self.asm;
+        match opcode {
+            0 | 8 => a.alu(OP_AND, RAX, RCX),
+            1 | 9 => a.alu(OP_XOR, RAX, RCX),
+            2 | 10 => a.alu(OP_SUB, RAX, RCX),
+            4 | 11 => a.alu(OP_ADD, RAX, RCX),
+            5 => a.alu(OP_ADC, RAX, RCX),
+            6 => a.alu(OP_SBB, RAX, RCX),
+            3 | 7 => {
+                a.alu(if opcode == 3 { OP_SUB } else { OP_SBB }, RCX, RAX);
+                a.mov_rr(RAX, RCX);
+            }
+            12 => a.alu(OP_OR, RAX, RCX),
+            13 => a.mov_rr(RAX, RCX),
+            14 => {
+                a.not(RCX);
+                a.alu(OP_AND, RAX, RCX);
+            }
+            _ => {
+                a.mov_rr(RAX, RCX);
+                a.not(RAX);
+            }
+        }
+        if !set_flags {
+            return;
+        }
+        if logical {
+            a.alu(OP_TEST, RAX, RAX);
+            a.setcc_m(CC_S, RBX, FLAG_N);
+            a.setcc_m(CC_E, RBX, FLAG_Z);
+            match carry {
+                ShiftCarry::Unchanged => {}
+                ShiftCarry::Const(c) => a.store8_i(RBX, FLAG_C, c as u8),
+                ShiftCarry::Dl => a.store8(RBX, FLAG_C, RDX),
+            }
+        } else {
+            // The ARM carry flag is the inverse of the borrow for subtractions.
+            let subtract = matches!(opcode, 2 | 3 | 6 | 7 | 10);
+            a.setcc_m(CC_S, RBX, FLAG_N);
+            a.setcc_m(CC_E, RBX, FLAG_Z);
+            a.setcc_m(if subtract { CC_AE } else { CC_B }, RBX, FLAG_C);
+            a.setcc_m(CC_O, RBX, FLAG_V);
+        }
+    }
+
+    /// Load from the address in esi into eax (zero extended), like `cpu_load32` and co.
+    /// Keeps the unaligned address in r14d.
+    fn load(&mut self, index: usize, width: Width) {
+        let a = &mut self.asm;
+        let (ewram, slow, fast, done) = (a.label(), a.label(), a.label(), a.label());
+        a.mov_rr(R14, RSI);
+        a.alu_i(EXT_AND, RSI, width.align_mask());
+        a.mov_rr(RAX, RSI);
+        a.shift_i(EXT_SHR, RAX, 24);
+        a.alu_i(EXT_CMP, RAX, REGION_IWRAM);
+        a.jcc(CC_NE, ewram);
+        a.mov_rr(RCX, RSI);
+        a.alu_i(EXT_AND, RCX, 0x7FFF);
+        a.load64(RDX, R13, STATE_IWRAM);
+        a.load_x(width, RAX, RDX, RCX);
+        a.alu64_i(EXT_ADD, R12, 1);
+        a.jmp(fast);
+
+        a.bind(ewram);
+        a.alu_i(EXT_CMP, RAX, REGION_EWRAM);
+        a.jcc(CC_NE, slow);
+        a.load64(RDX, R13, STATE_EWRAM);
+        a.alu64(OP_TEST, RDX, RDX);
+        a.jcc(CC_E, slow);
+        a.mov_rr(RCX, RSI);
+        a.alu_i(EXT_AND, RCX, 0x3FFFF);
+        a.load_x(width, RAX, RDX, RCX);
+        a.add64_rm(R12, R13, width.ewram_cycles());
+        a.jmp(fast);
+
+        a.bind(slow);
+        self.before_call(index);
+        let a = &mut self.asm;
+        a.mov64_rr(RDI, RBX);
+        a.mov_rr(RSI, R14);
+        a.alu_i(EXT_AND, RSI, width.align_mask());
+        a.call(match width {
+            Width::Byte => jit_load8 as *const (),
+            Width::Half => jit_load16 as *const (),
+            Width::Word => jit_load32 as *const (),
+        });
+        a.jmp(done);
+
+        // Record the read for idle loop detection, like `idle_on_load`.
+        a.bind(fast);
+        self.record_data_value(width, RAX);
+        let a = &mut self.asm;
+        a.load(RCX, RBX, IDLE_READS);
+        a.shift_i(EXT_ROL, RCX, 5);
+        a.alu(OP_XOR, RCX, RSI);
+        a.mov_rr(RDX, RAX);
+        a.shift_i(EXT_ROL, RDX, 16);
+        a.alu(OP_XOR, RCX, RDX);
+        a.store(RBX, IDLE_READS, RCX);
+        a.bind(done);
+    }
+
+    /// Load from the address in esi into register `reg_d`, like the interpreter does for
+    /// load instructions (including the internal cycle).
+    fn load_into(&mut self, index: usize, width: Width, extend: Extend, reg_d: u32) {
+        self.load(index, width);
+        let a = &mut self.asm;
+        // Misaligned halfwords and words are rotated (or shifted, for signed halfwords).
+        let misalignment = |a: &mut Assembler, mask: u32| {
+            a.mov_rr(RCX, R14);
+            a.alu_i(EXT_AND, RCX, mask);
+            a.shift_i(EXT_SHL, RCX, 3);
+        };
+        match (width, extend) {
+            (Width::Byte, Extend::Rotate) => {}
+            (Width::Byte, Extend::Signed) => a.movsx8(RAX, RAX),
+            (Width::Half, Extend::Rotate) => {
+                misalignment(a, 1);
+                a.shift_cl(EXT_ROR, RAX);
+            }
+            (Width::Half, Extend::Signed) => {
+                a.movsx16(RAX, RAX);
+                misalignment(a, 1);
+                a.shift_cl(EXT_SAR, RAX);
+            }
+            (Width::Word, _) => {
+                misalignment(a, 3);
+                a.shift_cl(EXT_ROR, RAX);
+            }
+        }
+        a.alu64_i(EXT_ADD, R12, 1);
+        self.store_reg(reg_d, RAX);
+    }
+
+    /// Store the value in edi to the address in esi, like `cpu_store32` and co.
+    fn store(&mut self, index: usize, width: Width) {
+        let a = &mut self.asm;
+        let (ewram, slow, stored, done) = (a.label(), a.label(), a.label(), a.label());
+        a.mov_rr(R14, RSI);
+        a.alu_i(EXT_AND, R14, width.align_mask());
+        a.mov_rr(RBP, RDI);
+        a.mov_rr(RAX, R14);
+        a.shift_i(EXT_SHR, RAX, 24);
+        a.alu_i(EXT_CMP, RAX, REGION_IWRAM);
+        a.jcc(CC_NE, ewram);
+        a.mov_rr(RCX, R14);
+        a.alu_i(EXT_AND, RCX, 0x7FFF);
+        // Pages with cached code are handled by the bus, to invalidate it.
+        a.mov_rr(RAX, RCX);
+        a.alu_i(EXT_ADD, RAX, 256 * 1024);
+        a.shift_i(EXT_SHR, RAX, PAGE_SHIFT);
+        a.load64(RDX, R13, STATE_CODE_PAGES);
+        a.cmp8_x_zero(RDX, RAX);
+        a.jcc(CC_NE, slow);
+        a.load64(RDX, R13, STATE_IWRAM);
+        a.store_x(width, RDX, RCX, RBP);
+        a.alu64_i(EXT_ADD, R12, 1);
+        a.store8_i(RBX, IDLE_WROTE, 1);
+        a.jmp(stored);
+
+        a.bind(ewram);
+        a.alu_i(EXT_CMP, RAX, REGION_EWRAM);
+        a.jcc(CC_NE, slow);
+        a.load64(RDX, R13, STATE_EWRAM);
+        a.alu64(OP_TEST, RDX, RDX);
+        a.jcc(CC_E, slow);
+        a.mov_rr(RCX, R14);
+        a.alu_i(EXT_AND, RCX, 0x3FFFF);
+        a.mov_rr(RAX, RCX);
+        a.shift_i(EXT_SHR, RAX, PAGE_SHIFT);
+        a.load64(R8, R13, STATE_CODE_PAGES);
+        a.cmp8_x_zero(R8, RAX);
+        a.jcc(CC_NE, slow);
+        a.store_x(width, RDX, RCX, RBP);
+        a.add64_rm(R12, R13, width.ewram_cycles());
+        a.store8_i(RBX, IDLE_WROTE, 1);
+        a.jmp(stored);
+
+        a.bind(slow);
+        self.before_call(index);
+        let a = &mut self.asm;
+        a.mov64_rr(RDI, RBX);
+        a.mov_rr(RSI, R14);
+        a.mov_rr(RDX, RBP);
+        a.call(match width {
+            Width::Byte => jit_store8 as *const (),
+            Width::Half => jit_store16 as *const (),
+            Width::Word => jit_store32 as *const (),
+        });
+        a.or8(R15, RAX);
+        a.jmp(done);
+
+        a.bind(stored);
+        self.record_data_value(width, RBP);
+        self.asm.bind(done);
+    }
+
+    /// Record the value of an inline access as the last one on the bus, like `cpu_load32`
+    /// and co (clobbers ecx).
+    fn record_data_value(&mut self, width: Width, value: u8) {
+        let a = &mut self.asm;
+        a.mov_rr(RCX, value);
+        match width {
+            Width::Byte => {
+                a.alu_i(EXT_AND, RCX, 0xFF);
+                a.imul_rri(RCX, RCX, 0x0101_0101);
+            }
+            Width::Half => {
+                a.alu_i(EXT_AND, RCX, 0xFFFF);
+                a.imul_rri(RCX, RCX, 0x0001_0001);
+            }
+            Width::Word => {}
+        }
+        a.store(RBX, DATA_VALUE, RCX);
+    }
+
+    /// Run an instruction with the interpreter.
+    fn interpret(&mut self, index: usize) {
+        self.before_call(index);
+        let a = &mut self.asm;
+        a.mov64_rr(RDI, RBX);
+        a.mov_ri(RSI, self.insts[index]);
+        a.mov_ri(RDX, self.start + index as u32 * self.size);
+        a.call(jit_interpret as *const ());
+        a.test8(RAX);
+        a.jcc(CC_NE, self.ret);
+    }
+
+    /// Branch to the address in esi, and leave the block.
+    fn branch(&mut self, index: usize) {
+        let a = &mut self.asm;
+        a.mov64_rr(RDI, RBX);
+        a.mov_ri(RDX, self.start + index as u32 * self.size);
+        a.call(jit_branch as *const ());
+        a.jmp(self.ret);
+    }
+
+    /// Compile an ARM instruction. Returns false if the block ends here.
+    fn arm(&mut self, index: usize) -> bool {
+        let inst = self.insts[index];
+        let cond = inst >> 28;
+        if cond == 0b1111 {
+            // Never executed.
+            return true;
+        }
+        let kind = arm_kind(inst);
+        if kind != Kind::Alu {
+            self.settle(index);
+        }
+        let skip = self.skip_unless(cond);
+        match kind {
+            Kind::Alu => self.arm_alu(index),
+            Kind::Load | Kind::Store if (inst >> 26) & 0b11 == 0b01 => self.arm_transfer(index),
+            Kind::Load | Kind::Store => self.arm_halfword_transfer(index),
+            Kind::Branch => {
+                let addr = self.inst_addr(index);
+                self.before_call(index);
+                if inst & (1 << 24) != 0 {
+                    self.asm.store_i(RBX, reg_offset(14), addr + 4);
+                }
+                let offset = ((inst << 8) as i32 >> 6) as u32;
+                self.asm
+                    .mov_ri(RSI, addr.wrapping_add(8).wrapping_add(offset));
+                self.branch(index);
+            }
+            Kind::Interpret => self.interpret(index),
+        }
+        if let Some(skip) = skip {
+            self.asm.bind(skip);
+        }
+        if kind == Kind::Store {
+            self.exit_if_requested(index);
+        }
+        !(kind == Kind::Branch && cond == 0b1110)
+    }
+
+    /// Data processing.
+    fn arm_alu(&mut self, index: usize) {
+        let inst = self.insts[index];
+        let pc = self.inst_addr(index) + 8;
+        let opcode = (inst >> 21) & 0xF;
+        let set_flags = inst & (1 << 20) != 0;
+        let logical = matches!(opcode, 0 | 1 | 8 | 9 | 12..=15);
+        let carry = if inst & (1 << 25) != 0 {
+            let rotate = (inst >> 8) & 0xF;
+            let value = (inst & 0xFF).rotate_right(2 * rotate);
+            self.asm.mov_ri(RCX, value);
+            if rotate == 0 {
+                ShiftCarry::Unchanged
+            } else {
+                ShiftCarry::Const(value >> 31 != 0)
+            }
+        } else {
+            self.load_reg(RCX, inst & 0xF, pc);
+            self.shift_imm((inst >> 5) & 0b11, (inst >> 7) & 0x1F, set_flags && logical)
+        };
+        if !matches!(opcode, 13 | 15) {
+            self.load_reg(RAX, (inst >> 16) & 0xF, pc);
+        }
+        self.alu_op(opcode, set_flags, carry);
+        if !(8..=11).contains(&opcode) {
+            self.store_reg((inst >> 12) & 0xF, RAX);
+        }
+    }
+
+    /// Compute the address of a transfer (from the offset in ecx) into esi, and write back.
+    fn arm_address(&mut self, index: usize) {
+        let inst = self.insts[index];
+        let pc = self.inst_addr(index) + 8;
+        let reg_n = (inst >> 16) & 0xF;
+        let pre_index = inst & (1 << 24) != 0;
+        let load = inst & (1 << 20) != 0;
+        self.load_reg(RAX, reg_n, pc);
+        self.asm.mov_rr(RDX, RAX);
+        let op = if inst & (1 << 23) != 0 {
+            OP_ADD
+        } else {
+            OP_SUB
+        };
+        self.asm.alu(op, RDX, RCX);
+        self.asm.mov_rr(RSI, if pre_index { RDX } else { RAX });
+        let write_back = inst & (1 << 21) != 0 || !pre_index;
+        if write_back && !(load && (inst >> 12) & 0xF == reg_n) {
+            self.store_reg(reg_n, RDX);
+        }
+    }
+
+    /// Single data transfer (LDR, STR, LDRB, STRB).
+    fn arm_transfer(&mut self, index: usize) {
+        let inst = self.insts[index];
+        let reg_d = (inst >> 12) & 0xF;
+        if inst & (1 << 25) != 0 {
+            self.load_reg(RCX, inst & 0xF, 0);
+            self.shift_imm((inst >> 5) & 0b11, (inst >> 7) & 0x1F, false);
+        } else {
+            self.asm.mov_ri(RCX, inst & 0xFFF);
+        }
+        if inst & (1 << 20) == 0 {
+            // A stored PC is 12 bytes ahead.
+            self.load_reg(RDI, reg_d, self.inst_addr(index) + 12);
+        }
+        self.arm_address(index);
+        let width = if inst & (1 << 22) != 0 {
+            Width::Byte
+        } else {
+            Width::Word
+        };
+        if inst & (1 << 20) != 0 {
+            self.load_into(index, width, Extend::Rotate, reg_d);
+        } else {
+            self.store(index, width);
+        }
+    }
+
+    /// Halfword and signed data transfer (LDRH, STRH, LDRSB, LDRSH).
+    fn arm_halfword_transfer(&mut self, index: usize) {
+        let inst = self.insts[index];
+        let reg_d = (inst >> 12) & 0xF;
+        if inst & (1 << 22) != 0 {
+            self.asm.mov_ri(RCX, ((inst >> 4) & 0xF0) | (inst & 0xF));
+        } else {
+            self.load_reg(RCX, inst & 0xF, 0);
+        }
+        if inst & (1 << 20) == 0 {
+            self.load_reg(RDI, reg_d, 0);
+        }
+        self.arm_address(index);
+        match (inst >> 5) & 0b11 {
+            _ if inst & (1 << 20) == 0 => self.store(index, Width::Half),
+            1 => self.load_into(index, Width::Half, Extend::Rotate, reg_d),
+            2 => self.load_into(index, Width::Byte, Extend::Signed, reg_d),
+            _ => self.load_into(index, Width::Half, Extend::Signed, reg_d),
+        }
+    }
+
+    /// Compile a Thumb instruction. Returns false if the block ends here.
+    fn thumb(&mut self, index: usize) -> bool {
+        let inst = self.insts[index];
+        let kind = thumb_kind(inst as u16);
+        if kind != Kind::Alu {
+            self.settle(index);
+        }
+        match kind {
+            Kind::Alu => self.thumb_alu(index),
+            Kind::Load | Kind::Store => self.thumb_transfer(index),
+            Kind::Branch => return self.thumb_branch(index),
+            Kind::Interpret => self.interpret(index),
+        }
+        if kind == Kind::Store {
+            self.exit_if_requested(index);
+        }
+        true
+    }
+
+    fn thumb_alu(&mut self, index: usize) {
+        let inst = self.insts[index];
+        let addr = self.inst_addr(index);
+        let reg_d = inst & 0b111;
+        let reg_s = (inst >> 3) & 0b111;
+        let reg_d_hi = (inst >> 8) & 0b111;
+        match inst >> 11 {
+            // Add/subtract.
+            0b00011 => {
+                self.load_reg(RAX, reg_s, 0);
+                let operand = (inst >> 6) & 0b111;
+                if inst & (1 << 10) != 0 {
+                    self.asm.mov_ri(RCX, operand);
+                } else {
+                    self.load_reg(RCX, operand, 0);
+                }
+                let opcode = if inst & (1 << 9) != 0 { 2 } else { 4 };
+                self.alu_op(opcode, true, ShiftCarry::Unchanged);
+                self.store_reg(reg_d, RAX);
+            }
+            // Move shifted register.
+            0b00000..=0b00010 => {
+                self.load_reg(RCX, reg_s, 0);
+                let carry = self.shift_imm((inst >> 11) & 0b11, (inst >> 6) & 0x1F, true);
+                self.alu_op(13, true, carry);
+                self.store_reg(reg_d, RAX);
+            }
+            // MOV, CMP, ADD, SUB with an immediate.
+            0b00100..=0b00111 => {
+                let op = (inst >> 11) & 0b11;
+                self.load_reg(RAX, reg_d_hi, 0);
+                self.asm.mov_ri(RCX, inst & 0xFF);
+                self.alu_op([13, 10, 4, 2][op as usize], true, ShiftCarry::Unchanged);
+                if op != 1 {
+                    self.store_reg(reg_d_hi, RAX);
+                }
+            }
+            // ALU operations, which (other than NEG) have the same numbers as in ARM.
+            0b01000 if inst & (1 << 10) == 0 => {
+                let op = (inst >> 6) & 0xF;
+                self.load_reg(RCX, reg_s, 0);
+                if op == 9 {
+                    self.asm.alu(OP_XOR, RAX, RAX);
+                    self.alu_op(2, true, ShiftCarry::Unchanged);
+                } else {
+                    self.load_reg(RAX, reg_d, 0);
+                    self.alu_op(op, true, ShiftCarry::Unchanged);
+                }
+                if !matches!(op, 8 | 10 | 11) {
+                    self.store_reg(reg_d, RAX);
+                }
+            }
+            // Hi register operations.
+            0b01000 => {
+                let reg_d = reg_d | ((inst >> 4) & 0b1000);
+                let reg_s = (inst >> 3) & 0xF;
+                let pc = addr + 4;
+                match (inst >> 8) & 0b11 {
+                    0 => {
+                        self.load_reg(RAX, reg_d, pc);
+                        self.load_reg(RCX, reg_s, pc);
+                        self.asm.alu(OP_ADD, RAX, RCX);
+                        self.store_reg(reg_d, RAX);
+                    }
+                    1 => {
+                        self.load_reg(RAX, reg_d, pc);
+                        self.load_reg(RCX, reg_s, pc);
+                        self.alu_op(10, true, ShiftCarry::Unchanged);
+                    }
+                    _ => {
+                        self.load_reg(RAX, reg_s, pc);
+                        self.store_reg(reg_d, RAX);
+                    }
+                }
+            }
+            // Load address.
+            0b10100 | 0b10101 => {
+                let offset = (inst & 0xFF) * 4;
+                if inst & (1 << 11) != 0 {
+                    self.load_reg(RAX, 13, 0);
+                    self.asm.alu_i(EXT_ADD, RAX, offset);
+                } else {
+                    self.asm.mov_ri(RAX, ((addr + 4) & !3) + offset);
+                }
+                self.store_reg(reg_d_hi, RAX);
+            }
+            // Add offset to SP.
+            0b10110 => {
+                let ext = if inst & (1 << 7) != 0 {
+                    EXT_SUB
+                } else {
+                    EXT_ADD
+                };
+                self.load_reg(RAX, 13, 0);
+                self.asm.alu_i(ext, RAX, (inst & 0x7F) * 4);
+                self.store_reg(13, RAX);
+            }
+            // First half of BL.
+            _ => {
+                let offset = (((inst & 0x7FF) << 21) as i32 >> 9) as u32;
+                self.asm
+                    .store_i(RBX, reg_offset(14), (addr + 4).wrapping_add(offset));
+            }
+        }
+    }
+
+    fn thumb_transfer(&mut self, index: usize) {
+        let inst = self.insts[index];
+        let addr = self.inst_addr(index);
+        let reg_d = inst & 0b111;
+        let reg_b = (inst >> 3) & 0b111;
+        let reg_d_hi = (inst >> 8) & 0b111;
+        let offset = (inst >> 6) & 0x1F;
+        // Address into esi, and the value to store into edi.
+        let (width, extend, reg_d) = match inst >> 11 {
+            // PC relative load.
+            0b01001 => {
+                self.asm.mov_ri(RSI, ((addr + 4) & !3) + (inst & 0xFF) * 4);
+                (Width::Word, Extend::Rotate, reg_d_hi)
+            }
+            // Register offset.
+            0b01010 | 0b01011 => {
+                self.load_reg(RSI, reg_b, 0);
+                self.load_reg(RCX, (inst >> 6) & 0b111, 0);
+                self.asm.alu(OP_ADD, RSI, RCX);
+                let (width, extend) = match (inst >> 9) & 0b111 {
+                    0 | 4 => (Width::Word, Extend::Rotate),
+                    1 | 5 => (Width::Half, Extend::Rotate),
+                    2 | 6 => (Width::Byte, Extend::Rotate),
+                    3 => (Width::Byte, Extend::Signed),
+                    _ => (Width::Half, Extend::Signed),
+                };
+                (width, extend, reg_d)
+            }
+            // Immediate offset.
+            0b01100..=0b01111 => {
+                let byte = inst & (1 << 12) != 0;
+                self.load_reg(RSI, reg_b, 0);
+                self.asm
+                    .alu_i(EXT_ADD, RSI, if byte { offset } else { offset * 4 });
+                let width = if byte { Width::Byte } else { Width::Word };
+                (width, Extend::Rotate, reg_d)
+            }
+            // Halfword.
+            0b10000 | 0b10001 => {
+                self.load_reg(RSI, reg_b, 0);
+                self.asm.alu_i(EXT_ADD, RSI, offset * 2);
+                (Width::Half, Extend::Rotate, reg_d)
+            }
+            // SP relative.
+            _ => {
+                self.load_reg(RSI, 13, 0);
+                self.asm.alu_i(EXT_ADD, RSI, (inst & 0xFF) * 4);
+                (Width::Word, Extend::Rotate, reg_d_hi)
+            }
+        };
+        if thumb_kind(inst as u16) == Kind::Load {
+            self.load_into(index, width, extend, reg_d);
+        } else {
+            self.load_reg(RDI, reg_d, 0);
+            self.store(index, width);
+        }
+    }
+
+    /// Compile a Thumb branch. Returns false if it's unconditional.
+    fn thumb_branch(&mut self, index: usize) -> bool {
+        let inst = self.insts[index];
+        let addr = self.inst_addr(index);
+        match inst >> 11 {
+            // Conditional branch.
+            0b11010 | 0b11011 => {
+                let skip = self.skip_unless((inst >> 8) & 0xF);
+                self.before_call(index);
+                let offset = (((inst & 0xFF) << 24) as i32 >> 23) as u32;
+                self.asm.mov_ri(RSI, (addr + 4).wrapping_add(offset));
+                self.branch(index);
+                if let Some(skip) = skip {
+                    self.asm.bind(skip);
+                }
+                true
+            }
+            // Unconditional branch.
+            0b11100 => {
+                self.before_call(index);
+                let offset = (((inst & 0x7FF) << 21) as i32 >> 20) as u32;
+                self.asm.mov_ri(RSI, (addr + 4).wrapping_add(offset));
+                self.branch(index);
+                false
+            }
+            // Second half of BL.
+            _ => {
+                self.before_call(index);
+                self.load_reg(RSI, 14, 0);
+                self.asm.alu_i(EXT_ADD, RSI, (inst & 0x7FF) << 1);
+                self.asm.store_i(RBX, reg_offset(14), (addr + 2) | 1);
+                self.branch(index);
+                false
+            }
+        }
+    }
+}
+
+/// A mapping of executable memory.
+struct ExecutableMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+const SYS_MMAP: usize = 9;
+const SYS_MPROTECT: usize = 10;
+const SYS_MUNMAP: usize = 11;
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// Make a Linux system call.
+unsafe fn syscall(number: usize, args: [usize; 6]) -> isize {
+    let result: isize;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+impl ExecutableMemory {
+    /// Map memory containing the given code (writable while copying, then executable).
+    fn new(code: &[u8]) -> Option<ExecutableMemory> {
+        let len = (code.len() + 4095) & !4095;
+        unsafe {
+            let ptr = syscall(
+                SYS_MMAP,
+                [
+                    0,
+                    len,
+                    PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    usize::MAX,
+                    0,
+                ],
+            );
+            if ptr < 0 {
+                return None;
+            }
+            let memory = ExecutableMemory {
+                ptr: ptr as *mut u8,
+                len,
+            };
+            std::ptr::copy_nonoverlapping(code.as_ptr(), memory.ptr, code.len());
+            if syscall(
+                SYS_MPROTECT,
+                [ptr as usize, len, PROT_READ | PROT_EXEC, 0, 0, 0],
+            ) < 0
+            {
+                return None;
+            }
+            Some(memory)
+        }
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe {
+            syscall(SYS_MUNMAP, [self.ptr as usize, self.len, 0, 0, 0, 0]);
+        }
+    }
+}
+#[cfg(all(test, feature = "jit"))]
+mod tests {
+    use super::super::tests::test_gba;
+    use super::super::CpuExecutionState;
+    use crate::io::{REG_IE, REG_IME, REG_TM0CNT_H, REG_TM0CNT_L, REG_WAITCNT};
+    use crate::{Gba, InterruptKind, Memory};
+
+    /// Where a test program runs from.
+    #[derive(Clone, Copy, Debug)]
+    enum Location {
+        Iwram,
+        Ewram,
+        Rom,
+        RomWithPrefetch,
+    }
+
+    const LOCATIONS: [Location; 4] = [
+        Location::Iwram,
+        Location::Ewram,
+        Location::Rom,
+        Location::RomWithPrefetch,
+    ];
+
+    // Data the programs load and store (away from the code).
+    const IWRAM_DATA: u32 = 0x0300_4000;
+    const EWRAM_DATA: u32 = 0x0201_0000;
+
+    /// Programs end with a SWI, so they stop at its vector.
+    const END: u32 = 0x08;
+
+    /// Small xorshift generator, so the programs are reproducible.
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, n: u32) -> u32 {
+            self.next() % n
+        }
+
+        fn pick(&mut self, values: &[u32]) -> u32 {
+            values[self.below(values.len() as u32) as usize]
+        }
+    }
+
+    /// Create a GBA with `code` at `location`, and random data to work on.
+    fn setup(code: &[u8], location: Location, thumb: bool, random: &mut Random) -> Gba {
+        let mut rom = code.to_vec();
+        rom.resize(0x400, 0);
+        let start = match location {
+            Location::Iwram => {
+                let mut gba = test_gba();
+                gba.iwram[..code.len()].copy_from_slice(code);
+                (gba, 0x0300_0000)
+            }
+            Location::Ewram => {
+                let mut gba = test_gba();
+                gba.ewram[..code.len()].copy_from_slice(code);
+                (gba, 0x0200_0000)
+            }
+            Location::Rom => (Gba::new_for_test(&rom), 0x0800_0000),
+            Location::RomWithPrefetch => {
+                let mut gba = Gba::new_for_test(&rom);
+                gba.io_write_16(REG_WAITCNT, 0x4000 | (0b01 << 2) | (1 << 4));
+                (gba, 0x0800_0000)
+            }
+        };
+        let (mut gba, start) = start;
+        for addr in (0..0x1000).step_by(4) {
+            gba.iwram
+                .write_32((IWRAM_DATA & 0x7FFF) - 0x1000 + addr, random.next());
+            gba.iwram
+                .write_32((IWRAM_DATA & 0x7FFF) + addr, random.next());
+            gba.ewram
+                .write_32((EWRAM_DATA & 0x3FFFF) - 0x1000 + addr, random.next());
+            gba.ewram
+                .write_32((EWRAM_DATA & 0x3FFFF) + addr, random.next());
+        }
+        gba.cpu.cpsr = (random.next() & 0xF000_0000 | 0x1F).into();
+        gba.cpu.cpsr.execution_state = if thumb {
+            CpuExecutionState::Thumb
+        } else {
+            CpuExecutionState::Arm
+        };
+        for reg in 0..8 {
+            gba.cpu.gpr[reg] = random.next();
+        }
+        gba.cpu_jump(start);
+        gba
+    }
+
+    /// Run until the program ends, with the JIT or only the interpreter.
+    fn run(gba: &mut Gba, jit: bool) {
+        gba.cpu_cache.enabled = jit;
+        for _ in 0..1_000_000 {
+            if gba.cpu_pc() == END {
+                return;
+            }
+            if !(jit && gba.cpu_run_cached()) {
+                gba.cpu_step();
+            }
+            // Run idle loops normally.
+            gba.idle_loop.idle = false;
+        }
+        panic!("the program didn't finish");
+    }
+
+    fn assert_same_state(jit: &Gba, interpreter: &Gba) {
+        let cpsr: u32 = jit.cpu.cpsr.into();
+        assert_eq!(jit.cpu.gpr, interpreter.cpu.gpr);
+        assert_eq!(jit.cpu.gpr_banked_r13, interpreter.cpu.gpr_banked_r13);
+        assert_eq!(jit.cpu.gpr_banked_r14, interpreter.cpu.gpr_banked_r14);
+        assert_eq!(cpsr, interpreter.cpu.cpsr.into());
+        assert_eq!(jit.cpu.pc, interpreter.cpu.pc);
+        assert_eq!(jit.cpu.pipeline, interpreter.cpu.pipeline);
+        assert!(jit.iwram == interpreter.iwram, "IWRAM differs");
+        assert!(jit.ewram == interpreter.ewram, "EWRAM differs");
+        assert!(jit.ppu.vram == interpreter.ppu.vram, "VRAM differs");
+        assert_eq!(jit.idle_loop, interpreter.idle_loop);
+        assert_eq!(jit.scheduler.timestamp(), interpreter.scheduler.timestamp());
+    }
+
+    /// Run `code` with both the JIT and the interpreter, and compare the results.
+    fn compare(code: &[u8], location: Location, thumb: bool, seed: u32) {
+        let mut jit = setup(code, location, thumb, &mut Random(seed));
+        let mut interpreter = setup(code, location, thumb, &mut Random(seed));
+        run(&mut jit, true);
+        run(&mut interpreter, false);
+        assert!(jit.cpu_cache_compiled_blocks() > 0);
+        assert_same_state(&jit, &interpreter);
+    }
+
+    /// A random ARM instruction for the body of a test program.
+    ///
+    /// r0-r6 hold data, r7 a small offset, r8-r10 point to IWRAM, EWRAM and VRAM, r11 is
+    /// the loop counter, and r12 is a copy of r8 for addressing modes with writeback.
+    fn random_arm(random: &mut Random) -> u32 {
+        let cond = if random.below(2) == 0 {
+            0xE
+        } else {
+            random.below(15)
+        };
+        let reg_d = random.below(7);
+        let data = |random: &mut Random| {
+            if random.below(20) == 0 {
+                15
+            } else {
+                random.below(8)
+            }
+        };
+        let inst = match random.below(20) {
+            // Data processing.
+            0..=7 => {
+                let opcode = random.below(16);
+                let set_flags = (8..=11).contains(&opcode) || random.below(2) == 0;
+                let operand = if random.below(2) == 0 {
+                    1 << 25 | random.below(16) << 8 | random.below(256)
+                } else if random.below(10) == 0 {
+                    // Register shift.
+                    random.below(8) << 8 | random.below(4) << 5 | 1 << 4 | random.below(8)
+                } else {
+                    random.below(32) << 7 | random.below(4) << 5 | data(random)
+                };
+                opcode << 21 | (set_flags as u32) << 20 | data(random) << 16 | reg_d << 12 | operand
+            }
+            // LDR/STR.
+            8..=11 => {
+                let load = random.below(2);
+                let reg_d = if load == 1 { reg_d } else { data(random) };
+                let byte = random.below(2) << 22;
+                let up = random.below(2) << 23;
+                match random.below(4) {
+                    // Immediate offset.
+                    0 => {
+                        let reg_n = if load == 1 && random.below(4) == 0 {
+                            15
+                        } else {
+                            random.pick(&[8, 9, 10])
+                        };
+                        0x0500_0000
+                            | up
+                            | byte
+                            | load << 20
+                            | reg_n << 16
+                            | reg_d << 12
+                            | random.below(0x1000)
+                    }
+                    // Register offset.
+                    1 => {
+                        let shift = random.below(3) << 5;
+                        let amount = if shift == 0 {
+                            random.below(5)
+                        } else {
+                            random.below(32)
+                        };
+                        0x0700_0000
+                            | up
+                            | byte
+                            | load << 20
+                            | random.pick(&[8, 9, 10]) << 16
+                            | reg_d << 12
+                            | amount << 7
+                            | shift
+                            | 7
+                    }
+                    // Pre-indexed with writeback.
+                    2 => {
+                        0x0520_0000
+                            | up
+                            | byte
+                            | load << 20
+                            | 12 << 16
+                            | reg_d << 12
+                            | random.below(0x40)
+                    }
+                    // Post-indexed.
+                    _ => 0x0400_0000 | up | byte | load << 20 | 12 << 16 | reg_d << 12 | 7,
+                }
+            }
+            // Halfword and signed transfers.
+            12..=14 => {
+                let (load, op) = match random.below(4) {
+                    0 => (0, 1),
+                    n => (1, n),
+                };
+                let reg_d = if load == 1 { reg_d } else { random.below(8) };
+                let up = random.below(2) << 23;
+                let immediate = 1 << 22;
+                let (mode, offset) = match random.below(3) {
+                    0 => (
+                        immediate | random.pick(&[8, 9, 10]) << 16,
+                        random.below(0x100),
+                    ),
+                    1 => (immediate | 1 << 21 | 12 << 16, random.below(0x40)),
+                    _ => (random.pick(&[8, 9, 10]) << 16, 7),
+                };
+                let offset = match mode & immediate {
+                    0 => offset,
+                    _ => (offset & 0xF0) << 4 | offset & 0xF,
+                };
+                1 << 24 | mode | up | load << 20 | reg_d << 12 | 0b1001 << 4 | op << 5 | offset
+            }
+            // Branch over the next instruction.
+            15 => 0x0A00_0000,
+            // Instructions that go through the interpreter.
+            16 => 0x0000_0090 | reg_d << 16 | random.below(8) << 8 | random.below(8),
+            17 => 0x010F_0000 | reg_d << 12,
+            18 => 0x0128_F000 | random.below(8),
+            _ => random.pick(&[0x0898_000F, 0x0889_00FF]),
+        };
+        cond << 28 | inst
+    }
+
+    /// A random ARM test program: a loop around random instructions.
+    fn random_arm_program(random: &mut Random) -> Vec<u8> {
+        let mut code = vec![
+            0xE3A0_7035, // mov r7, #0x35
+            0xE3A0_8403, // mov r8, #0x03000000
+            0xE388_8901, // orr r8, r8, #0x4000
+            0xE3A0_9402, // mov r9, #0x02000000
+            0xE389_9801, // orr r9, r9, #0x10000
+            0xE3A0_A406, // mov r10, #0x06000000
+            0xE3A0_B064, // mov r11, #100
+        ];
+        let start = code.len();
+        code.push(0xE1A0_C008); // mov r12, r8
+        for _ in 0..24 {
+            code.push(random_arm(random));
+        }
+        code.push(0xE25B_B001); // subs r11, r11, #1
+        let offset = start as i32 - (code.len() as i32 + 2);
+        code.push(0x1A00_0000 | (offset as u32 & 0xFF_FFFF)); // bne start
+        code.push(0xEF00_0000); // swi 0
+        code.extend([0; 4]);
+        code.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    /// A random Thumb instruction (or two) for the body of a test program.
+    ///
+    /// r0-r3 hold data, r4 a small offset, r5-r7 point to IWRAM, EWRAM and VRAM, r9 is the
+    /// loop counter, and SP points to IWRAM.
+    fn random_thumb(random: &mut Random, code: &mut Vec<u16>) {
+        let reg_d = random.below(4);
+        let reg_s = random.below(8);
+        let pointer = random.pick(&[5, 6, 7]);
+        let load = random.below(2);
+        let reg_t = if load == 1 { reg_d } else { reg_s };
+        let inst = match random.below(22) {
+            0 | 1 => random.below(3) << 11 | random.below(32) << 6 | reg_s << 3 | reg_d,
+            2 => 0x1800 | random.below(4) << 9 | random.below(8) << 6 | reg_s << 3 | reg_d,
+            3 | 4 => 0x2000 | random.below(4) << 11 | reg_d << 8 | random.below(256),
+            5..=7 => 0x4000 | random.below(16) << 6 | reg_s << 3 | reg_d,
+            8 => {
+                let reg_d = random.pick(&[0, 1, 2, 3, 8, 10, 11, 12]);
+                let reg_s = random.below(16);
+                0x4400 | random.below(3) << 8 | (reg_d & 8) << 4 | reg_s << 3 | (reg_d & 7)
+            }
+            9 => 0x4800 | reg_d << 8 | random.below(256),
+            10 | 11 => 0x5000 | random.below(8) << 9 | 4 << 6 | pointer << 3 | reg_d,
+            12 | 13 => {
+                0x6000
+                    | random.below(2) << 12
+                    | load << 11
+                    | random.below(32) << 6
+                    | pointer << 3
+                    | reg_t
+            }
+            14 => 0x8000 | load << 11 | random.below(32) << 6 | pointer << 3 | reg_t,
+            15 => 0x9000 | load << 11 | reg_t << 8 | random.below(256),
+            16 => 0xA000 | random.below(2) << 11 | reg_d << 8 | random.below(256),
+            17 => {
+                let offset = random.below(128);
+                code.push((0xB000 | offset) as u16);
+                0xB080 | offset
+            }
+            // Branch over a move.
+            18 => {
+                code.push((0xD000 | random.below(14) << 8) as u16);
+                0x2000 | reg_d << 8 | random.below(256)
+            }
+            // BL to the next instruction.
+            19 => {
+                code.push(0xF000);
+                0xF800
+            }
+            // Instructions that go through the interpreter.
+            20 => {
+                code.push(0xB40F); // push {r0-r3}
+                0xBC0F // pop {r0-r3}
+            }
+            _ => 0x4340 | reg_s << 3 | reg_d, // mul
+        };
+        code.push(inst as u16);
+    }
+
+    /// A random Thumb test program: a loop around random instructions.
+    fn random_thumb_program(random: &mut Random) -> Vec<u8> {
+        let mut code = vec![
+            0x2435, // mov r4, #0x35
+            0x2503, // mov r5, #3
+            0x062D, // lsl r5, r5, #24
+            0x2040, // mov r0, #0x40
+            0x0200, // lsl r0, r0, #8
+            0x182D, // add r5, r5, r0 (IWRAM_DATA)
+            0x2602, // mov r6, #2
+            0x0636, // lsl r6, r6, #24
+            0x2001, // mov r0, #1
+            0x0400, // lsl r0, r0, #16
+            0x1836, // add r6, r6, r0 (EWRAM_DATA)
+            0x2706, // mov r7, #6
+            0x063F, // lsl r7, r7, #24 (VRAM_DATA)
+            0x2020, // mov r0, #0x20
+            0x0200, // lsl r0, r0, #8
+            0x1940, // add r0, r0, r5
+            0x4685, // mov sp, r0
+            0x2064, // mov r0, #100
+            0x4681, // mov r9, r0
+        ];
+        let start = code.len();
+        let mut body = Vec::new();
+        while body.len() < 24 {
+            random_thumb(random, &mut body);
+        }
+        code.extend(body);
+        code.extend([
+            0x464B, // mov r3, r9
+            0x3B01, // sub r3, #1
+            0x4699, // mov r9, r3
+        ]);
+        let offset = start as i32 - (code.len() as i32 + 2);
+        code.push(0xD100 | (offset as u16 & 0xFF)); // bne start
+        code.push(0xDF00); // swi 0
+        code.extend([0; 4]);
+        code.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn random_arm_programs() {
+        for seed in 1..=40 {
+            let code = random_arm_program(&mut Random(seed));
+            for location in LOCATIONS {
+                compare(&code, location, false, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn random_thumb_programs() {
+        for seed in 1..=40 {
+            let code = random_thumb_program(&mut Random(seed));
+            for location in LOCATIONS {
+                compare(&code, location, true, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn idle_loop_detection() {
+        // A loop that only reads is idle, and one that writes isn't.
+        for (inst, idle) in [(0xE598_0000, true), (0xE588_0000, false)] {
+            let mut gba = test_gba();
+            gba.cpu.gpr[8] = IWRAM_DATA;
+            gba.cpu_test_load_arm(&[
+                inst,        // loop: ldr r0, [r8] (or str r0, [r8])
+                0xEAFF_FFFD, // b loop
+            ]);
+            gba.cpu_cache.enabled = true;
+            let run = |gba: &mut Gba| {
+                for _ in 0..200 {
+                    if !gba.cpu_run_cached() {
+                        gba.cpu_step();
+                    }
+                }
+            };
+            run(&mut gba);
+            assert!(gba.cpu_cache_compiled_blocks() > 0);
+            gba.idle_loop.idle = false;
+            run(&mut gba);
+            assert_eq!(gba.idle_loop.idle, idle);
+        }
+    }
+
+    #[test]
+    fn timer_irq_mid_block() {
+        // IRQ handler in the BIOS: acknowledge the IRQ, and add up the return addresses (so
+        // taking it at a different instruction changes the result).
+        const HANDLER: [u32; 7] = [
+            0xE3A0_9301, // mov r9, #0x04000000
+            0xE289_9C02, // add r9, r9, #0x200
+            0xE3A0_80FF, // mov r8, #0xFF
+            0xE1C9_80B2, // strh r8, [r9, #2] (IF)
+            0xE08A_A00E, // add r10, r10, lr
+            0xE28B_B001, // add r11, r11, #1
+            0xE25E_F004, // subs pc, lr, #4
+        ];
+        // A long block that only does ALU operations.
+        let mut code = [
+            0xE280_0001, // loop: add r0, r0, #1
+            0xE021_1000, // eor r1, r1, r0
+            0xE082_2081, // add r2, r2, r1, lsl #1
+            0xE043_3002, // sub r3, r3, r2
+            0xE084_4003, // add r4, r4, r3
+        ]
+        .repeat(4);
+        code.push(0xEAFF_FFEA); // b loop
+        let code: Vec<u8> = code
+            .iter()
+            .flat_map(|inst: &u32| inst.to_le_bytes())
+            .collect();
+
+        for location in LOCATIONS {
+            let [jit, interpreter] = [true, false].map(|jit| {
+                let mut gba = setup(&code, location, false, &mut Random(1));
+                for (i, &inst) in HANDLER.iter().enumerate() {
+                    gba.bios_rom.write_32(0x18 + 4 * i as u32, inst);
+                }
+                gba.cpu.gpr[10] = 0;
+                gba.cpu.gpr[11] = 0;
+                // Timer 0 overflows every 37 cycles, with an IRQ.
+                gba.io_write_16(REG_TM0CNT_L, 37u16.wrapping_neg());
+                gba.io_write_16(REG_TM0CNT_H, 0xC0);
+                gba.io_write_16(REG_IE, 1 << InterruptKind::Timer0 as u16);
+                gba.io_write_16(REG_IME, 1);
+                gba.cpu_cache.enabled = jit;
+                gba.emulate_cycles(100_000);
+                gba
+            });
+            assert!(jit.cpu_cache_compiled_blocks() > 0);
+            assert!(interpreter.cpu.gpr[11] > 100, "{:?}", location);
+            assert_same_state(&jit, &interpreter);
+        }
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let mut code = vec![
+            0xE3A0_B064, // mov r11, #100
+            0xE28F_80FC, // add r8, pc, #0xFC (the add in the function below)
+            0xEB00_003C, // loop: bl function
+            0xE598_2000, // ldr r2, [r8]
+            0xE222_2003, // eor r2, r2, #3
+            0xE588_2000, // str r2, [r8]
+            0xE25B_B001, // subs r11, r11, #1
+            0x1AFF_FFF9, // bne loop
+            0xEF00_0000, // swi 0
+        ];
+        // In the next page, so the loop's blocks stay compiled.
+        code.resize(0x40, 0);
+        code.extend([
+            0xE1A0_0000, // function: nop
+            0xE1A0_0000, // nop
+            0xE280_0001, // add r0, r0, #1 (alternates with add r0, r0, #2)
+            0xE12F_FF1E, // bx lr
+            0,
+            0,
+        ]);
+        let code: Vec<u8> = code
+            .iter()
+            .flat_map(|inst: &u32| inst.to_le_bytes())
+            .collect();
+        for location in [Location::Iwram, Location::Ewram] {
+            compare(&code, location, false, 1);
+        }
+    }
+}
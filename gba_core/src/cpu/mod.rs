@@ -1,12 +1,18 @@
 mod alu;
 mod arm;
+mod cache;
 mod cond;
 mod exception;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+mod jit;
 mod psr;
+#[cfg(test)]
+mod tests;
 mod thumb;
 
 use crate::bus::MemoryAccessType;
 use crate::Gba;
+pub use cache::BlockCache;
 use psr::ProgramStatusRegister;
 use serde::{Deserialize, Serialize};
 
@@ -190,15 +196,16 @@ impl Gba {
                 //     inst
                 // );
                 self.cpu.pipeline[1] =
-                    self.cpu_load16(self.cpu.pc, self.cpu.next_fetch_access) as u32;
+                    self.cpu_fetch16(self.cpu.pc, self.cpu.next_fetch_access) as u32;
 
+                let inst_addr = self.cpu_thumb_pc();
                 match self.cpu_execute_thumb(inst) {
                     InstructionResult::Normal => {
                         // Advance program counter.
                         self.cpu.pc += 2;
                         self.cpu.next_fetch_access = MemoryAccessType::Sequential;
                     }
-                    InstructionResult::Branch => {}
+                    InstructionResult::Branch => self.idle_on_branch(inst_addr),
                 }
             }
             CpuExecutionState::Arm => {
@@ -208,15 +215,16 @@ impl Gba {
                 //     self.cpu_arm_pc(),
                 //     inst
                 // );
-                self.cpu.pipeline[1] = self.cpu_load32(self.cpu.pc, self.cpu.next_fetch_access);
+                self.cpu.pipeline[1] = self.cpu_fetch32(self.cpu.pc, self.cpu.next_fetch_access);
 
+                let inst_addr = self.cpu_arm_pc();
                 match self.cpu_execute_arm(inst) {
                     InstructionResult::Normal => {
                         // Advance program counter.
                         self.cpu.pc += 4;
                         self.cpu.next_fetch_access = MemoryAccessType::Sequential;
                     }
-                    InstructionResult::Branch => {}
+                    InstructionResult::Branch => self.idle_on_branch(inst_addr),
                 }
             }
         }
@@ -229,14 +237,15 @@ impl Gba {
             CpuExecutionState::Thumb => {
                 let pc = pc & !0b1;
                 self.cpu.pc = pc + 4;
-                self.cpu.pipeline[0] = self.cpu_load16(pc, MemoryAccessType::NonSequential) as u32;
-                self.cpu.pipeline[1] = self.cpu_load16(pc + 2, MemoryAccessType::Sequential) as u32;
+                self.cpu.pipeline[0] = self.cpu_fetch16(pc, MemoryAccessType::NonSequential) as u32;
+                self.cpu.pipeline[1] =
+                    self.cpu_fetch16(pc + 2, MemoryAccessType::Sequential) as u32;
             }
             CpuExecutionState::Arm => {
                 let pc = pc & !0b11;
                 self.cpu.pc = pc + 8;
-                self.cpu.pipeline[0] = self.cpu_load32(pc, MemoryAccessType::NonSequential);
-                self.cpu.pipeline[1] = self.cpu_load32(pc + 4, MemoryAccessType::Sequential);
+                self.cpu.pipeline[0] = self.cpu_fetch32(pc, MemoryAccessType::NonSequential);
+                self.cpu.pipeline[1] = self.cpu_fetch32(pc + 4, MemoryAccessType::Sequential);
             }
         }
         self.cpu.next_fetch_access = MemoryAccessType::Sequential;
@@ -306,6 +315,7 @@ impl Gba {
     /// Do a CPU internal cycle.
     fn cpu_internal_cycle(&mut self) {
         self.scheduler.update(1);
+        self.bus_idle_cycles(1);
     }
 
     /// Format a debug dump of the CPU.
@@ -0,0 +1,140 @@
+use super::{CpuExecutionState, CpuMode, REG_LR};
+use crate::{Gba, Memory};
+
+/// Address that test code is placed at (in IWRAM).
+pub(super) const CODE_START: u32 = 0x0300_0000;
+
+/// Address of scratch data for loads and stores (in EWRAM).
+const DATA_START: u32 = 0x0200_0100;
+
+/// Create a GBA with an empty BIOS and cartridge, past the BIOS.
+pub(super) fn test_gba() -> Gba {
+    Gba::new_for_test(&[0; 0x200])
+}
+
+impl Gba {
+    /// Put ARM code at [`CODE_START`], and jump to it.
+    pub(super) fn cpu_test_load_arm(&mut self, code: &[u32]) {
+        for (i, &inst) in code.iter().enumerate() {
+            self.iwram.write_32(4 * i as u32, inst);
+        }
+        self.cpu.cpsr.execution_state = CpuExecutionState::Arm;
+        self.cpu_jump(CODE_START);
+    }
+
+    /// Put Thumb code at [`CODE_START`], and jump to it.
+    pub(super) fn cpu_test_load_thumb(&mut self, code: &[u16]) {
+        for (i, &inst) in code.iter().enumerate() {
+            self.iwram.write_16(2 * i as u32, inst);
+        }
+        self.cpu.cpsr.execution_state = CpuExecutionState::Thumb;
+        self.cpu_jump(CODE_START);
+    }
+}
+
+/// Run a single ARM instruction with the given registers.
+fn run_arm(gba: &mut Gba, inst: u32, registers: &[(usize, u32)]) {
+    for &(register, value) in registers {
+        gba.cpu.gpr[register] = value;
+    }
+    gba.cpu_test_load_arm(&[inst]);
+    gba.cpu_step();
+}
+
+fn ewram_32(gba: &mut Gba, addr: u32) -> u32 {
+    gba.ewram.read_32(addr & 0x3FFFF)
+}
+
+#[test]
+fn ldrt_post_indexed_immediate() {
+    // LDRT r0, [r1], #4
+    let mut gba = test_gba();
+    gba.ewram.write_32(DATA_START & 0x3FFFF, 0x1234_5678);
+    run_arm(&mut gba, 0xE4B1_0004, &[(1, DATA_START)]);
+    assert_eq!(gba.cpu.gpr[0], 0x1234_5678);
+    assert_eq!(gba.cpu.gpr[1], DATA_START + 4);
+}
+
+#[test]
+fn ldrt_post_indexed_register() {
+    // LDRT r0, [r1], -r2, LSL #2
+    let mut gba = test_gba();
+    gba.ewram.write_32(DATA_START & 0x3FFFF, 0xCAFE_F00D);
+    run_arm(&mut gba, 0xE631_0102, &[(1, DATA_START), (2, 3)]);
+    assert_eq!(gba.cpu.gpr[0], 0xCAFE_F00D);
+    assert_eq!(gba.cpu.gpr[1], DATA_START - 12);
+}
+
+#[test]
+fn strt_post_indexed() {
+    // STRT r0, [r1], #4
+    let mut gba = test_gba();
+    run_arm(&mut gba, 0xE4A1_0004, &[(0, 0xDEAD_BEEF), (1, DATA_START)]);
+    assert_eq!(ewram_32(&mut gba, DATA_START), 0xDEAD_BEEF);
+    assert_eq!(gba.cpu.gpr[1], DATA_START + 4);
+}
+
+#[test]
+fn ldrbt_post_indexed() {
+    // LDRBT r0, [r1], #1
+    let mut gba = test_gba();
+    gba.ewram.write_32(DATA_START & 0x3FFFF, 0x1234_5678);
+    run_arm(
+        &mut gba,
+        0xE4F1_0001,
+        &[(0, 0xFFFF_FFFF), (1, DATA_START + 1)],
+    );
+    assert_eq!(gba.cpu.gpr[0], 0x56);
+    assert_eq!(gba.cpu.gpr[1], DATA_START + 2);
+}
+
+#[test]
+fn strbt_post_indexed() {
+    // STRBT r0, [r1], #1
+    let mut gba = test_gba();
+    gba.ewram.write_32(DATA_START & 0x3FFFF, 0x1122_3344);
+    run_arm(
+        &mut gba,
+        0xE4E1_0001,
+        &[(0, 0xABCD_EF99), (1, DATA_START + 2)],
+    );
+    assert_eq!(ewram_32(&mut gba, DATA_START), 0x1199_3344);
+    assert_eq!(gba.cpu.gpr[1], DATA_START + 3);
+}
+
+/// Check that the CPU just took the Undefined exception, from code in `old_cpsr`.
+fn assert_undefined_exception(gba: &Gba, old_cpsr: u32, return_address: u32) {
+    assert_eq!(gba.cpu.cpsr.mode, CpuMode::Undefined);
+    assert_eq!(gba.cpu.cpsr.execution_state, CpuExecutionState::Arm);
+    assert!(gba.cpu.cpsr.interrupt_i);
+    assert_eq!(gba.cpu.gpr[REG_LR], return_address);
+    assert_eq!(gba.cpu.spsr, old_cpsr);
+    assert_eq!(gba.cpu_arm_pc(), 0x04);
+}
+
+#[test]
+fn arm_undefined_instruction() {
+    let mut gba = test_gba();
+    // MOV r0, r0, then a permanently undefined instruction.
+    gba.cpu_test_load_arm(&[0xE1A0_0000, 0xE7F0_00F0]);
+    gba.cpu_step();
+    let old_cpsr: u32 = gba.cpu.cpsr.into();
+    gba.cpu_step();
+    assert_undefined_exception(&gba, old_cpsr, CODE_START + 4 + 4);
+}
+
+#[test]
+fn thumb_undefined_instructions() {
+    // An undefined conditional branch condition, and ARMv5's BLX suffix.
+    for inst in [0xDE00, 0xE800] {
+        let mut gba = test_gba();
+        // MOV r0, r0 first.
+        gba.cpu_test_load_thumb(&[0x1C00, inst]);
+        gba.cpu_step();
+        let old_cpsr: u32 = gba.cpu.cpsr.into();
+        gba.cpu_step();
+        assert_undefined_exception(&gba, old_cpsr, CODE_START + 2 + 2);
+        // The saved CPSR has the Thumb bit set.
+        assert_eq!(gba.cpu.spsr & 0x20, 0x20);
+    }
+}
@@ -9,12 +9,12 @@ use super::{
 use bit::BitIndex;
 
 /// A function that can execute a Thumb instruction.
-type ThumbHandler = fn(&mut Gba, inst: u16) -> InstructionResult;
+pub(super) type ThumbHandler = fn(&mut Gba, inst: u16) -> InstructionResult;
 
-/// Dummy unimplemented / invalid Thumb instruction.
-fn thumb_unimplemented(_s: &mut Gba, inst: u16) -> InstructionResult {
+/// Undefined Thumb instruction.
+fn thumb_undefined(s: &mut Gba, inst: u16) -> InstructionResult {
     eprintln!(
-        "Unknown Thumb instruction: {:04x} / [{:04b} {:04b} {:02b}] {:02b} {:04b}",
+        "Undefined Thumb instruction: {:04x} / [{:04b} {:04b} {:02b}] {:02b} {:04b}",
         inst,
         (inst >> 12) & 0b1111,
         (inst >> 8) & 0b1111,
@@ -22,7 +22,9 @@ fn thumb_unimplemented(_s: &mut Gba, inst: u16) -> InstructionResult {
         (inst >> 4) & 0b11,
         (inst >> 0) & 0b1111,
     );
-    InstructionResult::Normal
+    let return_address = s.cpu_thumb_pc() + 2;
+    s.cpu_exception(ExceptionType::Undefined, return_address);
+    InstructionResult::Branch
 }
 
 // THUMB.1: shift by immediate
@@ -617,6 +619,12 @@ fn thumb_exec_branch_link<const SUFFIX: bool>(s: &mut Gba, inst: u16) -> Instruc
 // Include look-up table for instruction handlers.
 include!(concat!(env!("OUT_DIR"), "/thumb_table.rs"));
 
+/// Decode a Thumb instruction into its handler.
+#[inline(always)]
+pub(super) fn thumb_decode(inst: u16) -> ThumbHandler {
+    THUMB_HANDLERS[((inst >> 6) & 0x3ff) as usize]
+}
+
 impl Gba {
     /// Get the program counter of the *currently executing Thumb instruction*.
     pub fn cpu_thumb_pc(&self) -> u32 {
@@ -626,7 +634,6 @@ impl Gba {
 
     /// Execute the given Thumb instruction.
     pub(super) fn cpu_execute_thumb(&mut self, inst: u16) -> InstructionResult {
-        let key = ((inst >> 6) & 0x3ff) as usize;
-        (THUMB_HANDLERS[key])(self, inst)
+        thumb_decode(inst)(self, inst)
     }
 }
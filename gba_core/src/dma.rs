@@ -42,6 +42,10 @@ struct DmaChannel {
     internal_dest: u32,
     /// Internal count register.
     internal_count: u32,
+
+    /// The last value read by this channel. Reads from unmapped memory (or the BIOS)
+    /// transfer this instead.
+    latch: u32,
 }
 
 impl Default for DmaChannel {
@@ -56,6 +60,7 @@ impl Default for DmaChannel {
             internal_src: 0,
             internal_dest: 0,
             internal_count: 0,
+            latch: 0,
         }
     }
 }
@@ -166,12 +171,21 @@ impl Gba {
         // XXX: determine whether we need to go one cycle at a time
         // (e.g. for interaction with interrupts, DMAs of different priorities)
 
+        // DMA holds the bus (including the Game Pak bus) while it transfers.
+        self.bus_set_dma_running(true);
         for channel in 0..NUM_CHANNELS {
             // From high to low priority.
             if self.dma.active.bit(channel) {
                 self.transfer_channel(channel);
             }
         }
+        self.bus_set_dma_running(false);
+    }
+
+    /// Spend the time for a DMA read from memory it can't read (a single cycle).
+    fn dma_open_bus_cycle(&mut self) {
+        self.scheduler.update(1);
+        self.bus_idle_cycles(1);
     }
 
     /// Perform a DMA transfer for the given channel.
@@ -195,11 +209,28 @@ impl Gba {
             dest_adjust = channel.control.dest_adjustment();
         }
 
+        // DMA can't read from the BIOS or unmapped memory: it gets the latched value instead.
+        let readable = (0x0200_0000..0x1000_0000).contains(&src);
         if word_size == 2 {
-            let data = self.cpu_load16(src & !0b1, access);
+            let data = if readable {
+                let data = self.cpu_load16(src & !0b1, access);
+                self.dma.channels[index].latch = (data as u32) * 0x0001_0001;
+                data
+            } else {
+                self.dma_open_bus_cycle();
+                let latch = self.dma.channels[index].latch;
+                (latch >> ((dest & 0b10) << 3)) as u16
+            };
             self.cpu_store16(dest & !0b1, data, access);
         } else {
-            let data = self.cpu_load32(src & !0b11, access);
+            let data = if readable {
+                let data = self.cpu_load32(src & !0b11, access);
+                self.dma.channels[index].latch = data;
+                data
+            } else {
+                self.dma_open_bus_cycle();
+                self.dma.channels[index].latch
+            };
             self.cpu_store32(dest & !0b11, data, access);
         }
 
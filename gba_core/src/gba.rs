@@ -1,8 +1,17 @@
-use std::ops::DerefMut;
+use std::{io, ops::DerefMut};
 
 use crate::{
-    cartridge::BackupType, interrupt::InterruptManager, io::CpuPowerState, Apu, BackupFile, Bus,
-    Cartridge, Cpu, Dma, Event, Io, KeypadState, Ppu, Rom, Scheduler, TimerManager,
+    cartridge::{BackupType, FlashChip},
+    cpu::BlockCache,
+    idle::IdleLoopDetector,
+    interrupt::InterruptManager,
+    io::CpuPowerState,
+    ppu::{
+        debug::{LayerBuffers, LayerMask},
+        ColorLut, FrameBlender,
+    },
+    Apu, BackupFile, Bus, Cartridge, ColorProfile, Cpu, Dma, Event, FrameBlending, Io, KeypadState,
+    Ppu, Rom, Scheduler, TimerManager,
 };
 
 use serde::{Deserialize, Serialize};
@@ -10,6 +19,9 @@ use serde::{Deserialize, Serialize};
 pub const WIDTH: usize = 240;
 pub const HEIGHT: usize = 160;
 
+/// Minimum number of cycles between writes of the cartridge backup to its file (~1 second).
+const BACKUP_FLUSH_INTERVAL: usize = 1 << 24;
+
 /// Game Boy Advance Emulator
 #[derive(Serialize, Deserialize)]
 pub struct Gba {
@@ -22,6 +34,9 @@ pub struct Gba {
     /// The cartridge backup file.
     #[serde(skip)]
     pub(crate) cart_backup_file: Option<Box<dyn BackupFile>>,
+    /// Cycles emulated since the backup was last written to its file.
+    #[serde(skip)]
+    cart_backup_flush_cycles: usize,
 
     /// CPU state.
     pub(crate) cpu: Cpu,
@@ -69,6 +84,22 @@ pub struct Gba {
     ///
     /// This may be set to false during fast forwarding.
     pub(crate) should_render: bool,
+
+    /// Idle loop detection state.
+    #[serde(skip)]
+    pub(crate) idle_loop: IdleLoopDetector,
+
+    /// Decoded code cache for the CPU.
+    #[serde(skip)]
+    pub(crate) cpu_cache: BlockCache,
+
+    /// Which layers are shown (for debugging).
+    #[serde(skip)]
+    pub(crate) layer_mask: LayerMask,
+
+    /// Each layer rendered separately (for debugging), if enabled.
+    #[serde(skip)]
+    pub(crate) layer_buffers: Option<LayerBuffers>,
 }
 
 /// Builder struct for [`Gba`].
@@ -84,6 +115,21 @@ pub struct GbaBuilder {
 
     /// Backup type (or None for autodetection).
     backup_type: Option<BackupType>,
+
+    /// Flash chip model (or None to use the game database or a default).
+    flash_chip: Option<FlashChip>,
+
+    /// Whether to use the block cache for the CPU.
+    cached_interpreter: bool,
+
+    /// Whether the PPU renders in chunks within a scanline.
+    sub_scanline_rendering: bool,
+
+    /// How output colors are corrected.
+    color_profile: ColorProfile,
+
+    /// How frames are blended together.
+    frame_blending: FrameBlending,
 }
 
 impl Gba {
@@ -95,16 +141,22 @@ impl Gba {
             skip_bios: false,
             backup_file: None,
             backup_type: None,
+            flash_chip: None,
+            cached_interpreter: false,
+            sub_scanline_rendering: false,
+            color_profile: ColorProfile::default(),
+            frame_blending: FrameBlending::default(),
         }
     }
 
     /// Create a new GBA emulator from the builder.
     fn build(builder: GbaBuilder) -> Gba {
-        let cartridge = Cartridge::new(&builder.cart_rom, builder.backup_type);
+        let cartridge = Cartridge::new(&builder.cart_rom, builder.backup_type, builder.flash_chip);
         let mut gba = Gba {
             cart_rom: builder.cart_rom,
             bios_rom: builder.bios_rom,
             cart_backup_file: builder.backup_file,
+            cart_backup_flush_cycles: 0,
 
             cpu: Cpu::new(),
             bus: Bus::new(),
@@ -121,7 +173,15 @@ impl Gba {
             last_emulation_overshoot: 0,
             keypad_state: KeypadState::default(),
             should_render: false,
+            idle_loop: IdleLoopDetector::default(),
+            cpu_cache: BlockCache::default(),
+            layer_mask: LayerMask::default(),
+            layer_buffers: None,
         };
+        gba.cpu_cache.enabled = builder.cached_interpreter;
+        gba.ppu.sub_scanline = builder.sub_scanline_rendering;
+        gba.set_color_profile(builder.color_profile);
+        gba.set_frame_blending(builder.frame_blending);
         gba.ppu_init();
         gba.apu_init();
 
@@ -132,6 +192,7 @@ impl Gba {
 
         if builder.skip_bios {
             gba.cpu.skip_bios();
+            gba.bus.skip_bios();
             gba.ppu.skip_bios();
         }
 
@@ -146,6 +207,20 @@ impl Gba {
 
         'outer: loop {
             while self.scheduler.timestamp() < self.scheduler.peek_deadline().unwrap() {
+                if self.io.power_state == CpuPowerState::Stopped {
+                    if self.interrupt_stop_wakeup() {
+                        self.io.power_state = CpuPowerState::Normal;
+                    } else {
+                        // The PPU, APU, timers, and DMA are all frozen. Skip to the end
+                        // of this run, without firing any events.
+                        let delta =
+                            (start_time + cycles).saturating_sub(self.scheduler.timestamp());
+                        self.scheduler.advance_frozen(delta);
+                        self.timer_skip_frozen();
+                        break;
+                    }
+                }
+
                 let cpu_active = self.io.power_state == CpuPowerState::Normal;
                 let dma_active = self.dma_active();
 
@@ -160,7 +235,19 @@ impl Gba {
                             self.cpu_irq();
                         }
 
-                        self.cpu_step();
+                        if !(self.cpu_cache.enabled && self.cpu_run_cached()) {
+                            self.cpu_step();
+                        }
+
+                        // The CPU is spinning waiting for something to change, and nothing
+                        // will until the next event. Skip to it, like when halted.
+                        if self.idle_loop.idle {
+                            self.idle_loop.idle = false;
+                            if !self.interrupt_pending() {
+                                self.scheduler.skip_to_next_event();
+                                break;
+                            }
+                        }
                     }
                     (false, false) => {
                         // CPU is in halt state and no DMA is active. Skip to next interrupt.
@@ -184,6 +271,7 @@ impl Gba {
                     Event::TimerUpdate => self.timer_handle_event(),
                     Event::AudioSample => self.apu_on_sample_event(lateness),
                     Event::AudioSequencerTick => self.apu_on_sequencer_event(lateness),
+                    Event::FlashOperationComplete => self.cart_on_flash_operation_complete(),
                 }
             }
         }
@@ -214,16 +302,92 @@ impl Gba {
             self.last_emulation_overshoot = actually_ran - run_cycles;
         }
 
-        // Persist the backup buffer (if it's dirty).
-        if let Some(backup_file) = self.cart_backup_file.as_mut() {
-            self.cartridge.backup_buffer.save(backup_file.deref_mut());
+        // Persist the backup buffer (if it's dirty), at most once per interval.
+        self.cart_backup_flush_cycles += cycles;
+        if self.cart_backup_flush_cycles >= BACKUP_FLUSH_INTERVAL {
+            if let Err(e) = self.flush_backup() {
+                // Try again next interval.
+                eprintln!("Failed to write cartridge backup: {}", e);
+            }
+        }
+    }
+
+    /// Write the cartridge backup to its file now, if it has unwritten changes.
+    ///
+    /// This happens periodically while emulating, but should also be called before exiting.
+    pub fn flush_backup(&mut self) -> io::Result<()> {
+        self.cart_backup_flush_cycles = 0;
+        match self.cart_backup_file.as_mut() {
+            Some(backup_file) => self.cartridge.backup_buffer.save(backup_file.deref_mut()),
+            None => Ok(()),
         }
     }
 
     /// Get the frame buffer.
     /// (240 * 160) pixels, each pixel in ARGB format, row major.
+    ///
+    /// With frame blending, this is the last finished frame, blended with the previous ones.
     pub fn framebuffer(&self) -> &[u32] {
-        &self.ppu.framebuffer
+        match self.ppu.frame_blender.mode {
+            FrameBlending::Off => &self.ppu.framebuffer,
+            _ => &self.ppu.frame_blender.output,
+        }
+    }
+
+    /// Get the color profile used for the frame buffer.
+    pub fn color_profile(&self) -> ColorProfile {
+        self.ppu.color_lut.profile()
+    }
+
+    /// Set the color profile used for the frame buffer (from the next scanline on).
+    pub fn set_color_profile(&mut self, profile: ColorProfile) {
+        if profile != self.ppu.color_lut.profile() {
+            self.ppu.color_lut = ColorLut::new(profile);
+        }
+    }
+
+    /// Get how frames are blended together.
+    pub fn frame_blending(&self) -> FrameBlending {
+        self.ppu.frame_blender.mode
+    }
+
+    /// Set how frames are blended together.
+    pub fn set_frame_blending(&mut self, mode: FrameBlending) {
+        if mode != self.ppu.frame_blender.mode {
+            self.ppu.frame_blender = FrameBlender::new(mode, &self.ppu.framebuffer);
+        }
+    }
+
+    /// Get which layers are shown.
+    pub fn layer_mask(&self) -> LayerMask {
+        self.layer_mask
+    }
+
+    /// Set which layers are shown. This is for debugging: the game can't tell, and it
+    /// isn't saved in save states.
+    pub fn set_layer_mask(&mut self, mask: LayerMask) {
+        self.layer_mask = mask;
+    }
+
+    /// Set whether each layer is also rendered into its own buffer (see [`Gba::layer_buffers`]).
+    pub fn set_layer_buffers_enabled(&mut self, enabled: bool) {
+        if enabled != self.layer_buffers.is_some() {
+            self.layer_buffers = enabled.then(LayerBuffers::new);
+        }
+    }
+
+    /// Get the separately rendered layers, if enabled with [`Gba::set_layer_buffers_enabled`].
+    ///
+    /// Layers hidden by the layer mask are still rendered here.
+    pub fn layer_buffers(&self) -> Option<&LayerBuffers> {
+        self.layer_buffers.as_ref()
+    }
+
+    /// Get the current contents of the cartridge backup (e.g. the save data).
+    ///
+    /// This may include changes that haven't been written to the backup file yet.
+    pub fn backup_data(&self) -> &[u8] {
+        &self.cartridge.backup_buffer.storage
     }
 
     /// Get the audio samples created during the last frame.
@@ -248,6 +412,14 @@ impl Gba {
         swap(&mut self.cart_rom, &mut new_gba.cart_rom);
         swap(&mut self.bios_rom, &mut new_gba.bios_rom);
         swap(&mut self.cart_backup_file, &mut new_gba.cart_backup_file);
+        swap(&mut self.cpu_cache, &mut new_gba.cpu_cache);
+        self.cpu_cache.clear();
+        self.ppu.sub_scanline = new_gba.ppu.sub_scanline;
+        swap(&mut self.ppu.color_lut, &mut new_gba.ppu.color_lut);
+        swap(&mut self.ppu.frame_blender, &mut new_gba.ppu.frame_blender);
+        self.ppu.frame_blender.reset(&self.ppu.framebuffer);
+        self.layer_mask = new_gba.layer_mask;
+        swap(&mut self.layer_buffers, &mut new_gba.layer_buffers);
     }
 }
 
@@ -265,13 +437,66 @@ impl GbaBuilder {
     }
 
     /// Set the backup type. Overrides autodetection.
+    ///
+    /// [`BackupType::Eeprom512`] and [`BackupType::Eeprom8K`] force the EEPROM size,
+    /// skipping size detection entirely.
     pub fn backup_type(mut self, backup_type: BackupType) -> Self {
         self.backup_type = Some(backup_type);
         self
     }
 
+    /// Set the Flash chip model. Overrides the game database.
+    ///
+    /// Only used if the cartridge has a Flash backup.
+    pub fn flash_chip(mut self, flash_chip: FlashChip) -> Self {
+        self.flash_chip = Some(flash_chip);
+        self
+    }
+
+    /// Set whether the CPU should run code from a cache of decoded blocks.
+    ///
+    /// This is faster, and gives the same results as the regular interpreter (which is
+    /// still used for code outside ROM and work RAM). Disabled by default.
+    pub fn cached_interpreter(mut self, enabled: bool) -> Self {
+        self.cached_interpreter = enabled;
+        self
+    }
+
+    /// Set whether the PPU should render each scanline in chunks as it's drawn.
+    ///
+    /// Normally, a whole scanline is rendered at once when it starts, so writes to PPU
+    /// registers, palette, VRAM, or OAM in the middle of the line don't affect it.
+    /// This mode renders up to the current pixel before each such write, which is
+    /// needed for some raster effects, but is slower. Disabled by default.
+    pub fn sub_scanline_rendering(mut self, enabled: bool) -> Self {
+        self.sub_scanline_rendering = enabled;
+        self
+    }
+
+    /// Set how output colors are corrected. Defaults to [`ColorProfile::Raw`].
+    pub fn color_profile(mut self, profile: ColorProfile) -> Self {
+        self.color_profile = profile;
+        self
+    }
+
+    /// Set how frames are blended together, to emulate the slow LCD. Defaults to
+    /// [`FrameBlending::Off`].
+    pub fn frame_blending(mut self, mode: FrameBlending) -> Self {
+        self.frame_blending = mode;
+        self
+    }
+
     /// Build the GBA emulator with the current configuration.
     pub fn build(self) -> Gba {
         Gba::build(self)
     }
 }
+
+#[cfg(test)]
+impl Gba {
+    /// Create a GBA for tests, with an empty BIOS and the given cartridge ROM, past the BIOS.
+    pub(crate) fn new_for_test(rom: &[u8]) -> Gba {
+        let bios = vec![0; 16 * 1024].into_boxed_slice();
+        Gba::builder(bios, Rom::new(rom)).skip_bios(true).build()
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{cpu::CpuExecutionState, Gba};
+
+/// Largest backward branch (in bytes) that we consider as a possible idle loop.
+const MAX_LOOP_SIZE: u32 = 32;
+
+/// Number of identical iterations before a loop is considered idle.
+const IDLE_ITERATIONS: u32 = 2;
+
+/// State for idle loop detection.
+///
+/// An idle loop is a short loop that only polls memory or I/O waiting for something
+/// (usually an interrupt or a register like VCOUNT) to change. Nothing can change until
+/// the next scheduler event, so we can skip straight to it.
+///
+/// Loops are detected automatically: a backward branch to the same target, where an
+/// iteration writes nothing, and reads the same values and leaves the same register state
+/// as the previous iteration.
+#[derive(Default)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct IdleLoopDetector {
+    /// Target address of the loop being watched.
+    loop_start: Option<u32>,
+
+    /// Register state (r0-r14 and CPSR) at the start of the current iteration.
+    registers: [u32; 16],
+
+    /// Signature of the addresses and values read during the previous iteration.
+    previous_reads: u32,
+
+    /// Signature of the addresses and values read so far during this iteration.
+    pub(crate) reads: u32,
+
+    /// Whether anything was written during this iteration.
+    pub(crate) wrote: bool,
+
+    /// Number of identical iterations seen in a row.
+    iterations: u32,
+
+    /// Whether the CPU was found spinning in an idle loop.
+    pub idle: bool,
+}
+
+impl Gba {
+    /// Record a CPU read for idle loop detection.
+    #[inline(always)]
+    pub(crate) fn idle_on_load(&mut self, addr: u32, value: u32) {
+        let detector = &mut self.idle_loop;
+        detector.reads = detector.reads.rotate_left(5) ^ addr ^ value.rotate_left(16);
+    }
+
+    /// Record a CPU write for idle loop detection.
+    #[inline(always)]
+    pub(crate) fn idle_on_store(&mut self) {
+        self.idle_loop.wrote = true;
+    }
+
+    /// Check for an idle loop after a branch from `from` was taken.
+    pub(crate) fn idle_on_branch(&mut self, from: u32) {
+        let target = match self.cpu.cpsr.execution_state {
+            CpuExecutionState::Thumb => self.cpu_thumb_pc(),
+            CpuExecutionState::Arm => self.cpu_arm_pc(),
+        };
+
+        let mut registers = [0; 16];
+        registers[..15].copy_from_slice(&self.cpu.gpr);
+        registers[15] = self.cpu.cpsr.into();
+
+        let detector = &mut self.idle_loop;
+        let is_short_loop = target <= from && from - target <= MAX_LOOP_SIZE;
+        if is_short_loop
+            && detector.loop_start == Some(target)
+            && !detector.wrote
+            && detector.reads == detector.previous_reads
+            && detector.registers == registers
+        {
+            detector.iterations += 1;
+            if detector.iterations >= IDLE_ITERATIONS {
+                detector.idle = true;
+            }
+        } else {
+            detector.loop_start = if is_short_loop { Some(target) } else { None };
+            detector.iterations = 0;
+        }
+
+        detector.registers = registers;
+        detector.previous_reads = detector.reads;
+        detector.reads = 0;
+        detector.wrote = false;
+    }
+}
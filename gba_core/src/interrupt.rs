@@ -60,6 +60,16 @@ impl Gba {
         self.interrupt.global_enabled && ((self.interrupt.pending & self.interrupt.enabled) != 0)
     }
 
+    /// Checks whether an interrupt that can wake the system from Stop mode is requested.
+    /// Only Keypad, Game Pak, and Serial interrupts can, regardless of IME.
+    #[inline(always)]
+    pub(crate) fn interrupt_stop_wakeup(&self) -> bool {
+        const WAKEUP: u16 = (1 << InterruptKind::Keypad as u16)
+            | (1 << InterruptKind::Gamepak as u16)
+            | (1 << InterruptKind::Serial as u16);
+        (self.interrupt.pending & self.interrupt.enabled & WAKEUP) != 0
+    }
+
     /// Raise an interrupt.
     pub(crate) fn interrupt_raise(&mut self, kind: InterruptKind) {
         self.interrupt.pending.set_bit(kind as usize, true);
@@ -12,6 +12,8 @@ pub struct Io {
     pub power_state: CpuPowerState,
     /// Value of the WAITCNT (wait control) register.
     pub waitcnt: WaitControl,
+    /// Value of the (undocumented) internal memory control register.
+    pub memcnt: MemoryControl,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -30,12 +32,24 @@ impl Io {
             keycnt: 0,
             power_state: CpuPowerState::Normal,
             waitcnt: WaitControl(0),
+            memcnt: MemoryControl::default(),
         }
     }
 }
 
+/// Map mirrors of the internal memory control register (every 64K) to the register.
+#[inline(always)]
+fn io_unmirror(addr: u32) -> u32 {
+    if (addr & 0xFFFC) == (REG_MEMCNT & 0xFFFC) {
+        REG_MEMCNT | (addr & 0b11)
+    } else {
+        addr
+    }
+}
+
 impl Gba {
     pub fn io_read_16(&mut self, addr: u32) -> u16 {
+        let addr = io_unmirror(addr);
         match addr {
             REG_DISPCNT => self.ppu.dispcnt.read(),
             REG_DISPSTAT => self.ppu.dispstat.read(),
@@ -61,6 +75,8 @@ impl Gba {
             REG_IF => self.interrupt.pending,
             REG_DMA_START..=REG_DMA_END => self.dma_reg_read(addr - REG_DMA_START),
             REG_WAITCNT => self.io.waitcnt.0,
+            REG_MEMCNT => self.io.memcnt.0 as u16,
+            REG_MEMCNT_H => (self.io.memcnt.0 >> 16) as u16,
             REG_SOUND_START..=REG_SOUND_END => {
                 let lo = self.apu_io_read(addr);
                 let hi = self.apu_io_read(addr + 1);
@@ -71,6 +87,11 @@ impl Gba {
     }
 
     pub fn io_write_16(&mut self, addr: u32, value: u16) {
+        let addr = io_unmirror(addr);
+        if addr <= REG_BLDY {
+            // The write might affect the rest of the scanline.
+            self.ppu_catch_up();
+        }
         match addr {
             REG_DISPCNT => self.ppu.dispcnt.write(value),
             REG_DISPSTAT => self.ppu.dispstat.write(value),
@@ -151,6 +172,16 @@ impl Gba {
                 self.io.waitcnt.0 = value & 0x7FFF;
                 self.bus.update_waitcnt(self.io.waitcnt);
             }
+            REG_MEMCNT => {
+                self.io.memcnt.0 = (self.io.memcnt.0 & 0xFFFF_0000) | (value as u32);
+                self.bus.update_memcnt(self.io.memcnt);
+                // Code cached from EWRAM is stale if it was disabled.
+                self.cpu_cache.clear();
+            }
+            REG_MEMCNT_H => {
+                self.io.memcnt.0 = (self.io.memcnt.0 & 0x0000_FFFF) | ((value as u32) << 16);
+                self.bus.update_memcnt(self.io.memcnt);
+            }
             REG_SOUND_START..=REG_SOUND_END => {
                 self.apu_io_write(addr, value as u8);
                 self.apu_io_write(addr + 1, (value >> 8) as u8);
@@ -192,8 +223,10 @@ impl Gba {
         match addr {
             REG_HALTCNT => {
                 if value.bit(7) {
+                    // Stop: everything but the clock is frozen until a wakeup interrupt.
+                    self.timer_update();
+                    self.ppu_blank_screen();
                     self.io.power_state = CpuPowerState::Stopped;
-                    todo!("HALTCNT = STOP not supported");
                 } else {
                     self.io.power_state = CpuPowerState::Halted;
                 }
@@ -283,6 +316,32 @@ impl WaitControl {
     }
 }
 
+/// The internal memory control register (undocumented, mirrored every 64K).
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct MemoryControl(pub u32);
+
+impl Default for MemoryControl {
+    fn default() -> Self {
+        // EWRAM enabled, 2 waitstates.
+        MemoryControl(0x0D00_0020)
+    }
+}
+
+impl MemoryControl {
+    /// Whether the on-board ("external") work RAM is enabled.
+    pub fn ewram_enabled(self) -> bool {
+        self.0.bit(5)
+    }
+
+    /// Number of EWRAM waitstates.
+    ///
+    /// The register holds 15 minus the waitstates. 0 waitstates locks up the GBA,
+    /// so we treat it as 1.
+    pub fn ewram_waitstates(self) -> usize {
+        (15 - self.0.bit_range(24..28) as usize).max(1)
+    }
+}
+
 pub const REG_DISPCNT: u32 = 0x0400_0000;
 pub const REG_DISPSTAT: u32 = 0x0400_0004;
 pub const REG_VCOUNT: u32 = 0x0400_0006;
@@ -344,6 +403,8 @@ pub const REG_IE: u32 = 0x0400_0200;
 pub const REG_IF: u32 = 0x0400_0202;
 pub const REG_WAITCNT: u32 = 0x0400_0204;
 pub const REG_HALTCNT: u32 = 0x0400_0301;
+pub const REG_MEMCNT: u32 = 0x0400_0800;
+pub const REG_MEMCNT_H: u32 = 0x0400_0802;
 
 pub const REG_DMA_START: u32 = 0x0400_00B0;
 pub const REG_DMA_END: u32 = 0x0400_00DE;
@@ -35,19 +35,19 @@ impl Default for KeypadState {
     }
 }
 
-impl Into<u16> for KeypadState {
-    fn into(self) -> u16 {
+impl From<KeypadState> for u16 {
+    fn from(state: KeypadState) -> u16 {
         // 0 for pressed, 1 for not pressed.
-        ((!self.a as u16) << 0)
-            | ((!self.b as u16) << 1)
-            | ((!self.select as u16) << 2)
-            | ((!self.start as u16) << 3)
-            | ((!self.right as u16) << 4)
-            | ((!self.left as u16) << 5)
-            | ((!self.up as u16) << 6)
-            | ((!self.down as u16) << 7)
-            | ((!self.r as u16) << 8)
-            | ((!self.l as u16) << 9)
+        ((!state.a as u16) << 0)
+            | ((!state.b as u16) << 1)
+            | ((!state.select as u16) << 2)
+            | ((!state.start as u16) << 3)
+            | ((!state.right as u16) << 4)
+            | ((!state.left as u16) << 5)
+            | ((!state.up as u16) << 6)
+            | ((!state.down as u16) << 7)
+            | ((!state.r as u16) << 8)
+            | ((!state.l as u16) << 9)
     }
 }
 
@@ -62,7 +62,8 @@ impl Gba {
         if irq_enabled {
             // False: logical OR. True: logical AND.
             let irq_condition = self.io.keycnt.bit(15);
-            let pressed = Into::<u16>::into(state) & 0x3FF;
+            // KEYINPUT is active low: invert it, so 1 means pressed.
+            let pressed = !u16::from(state) & 0x3FF;
             let mask = keycnt & 0x3FF;
             if mask != 0 {
                 let fire = if irq_condition {
@@ -79,3 +80,43 @@ impl Gba {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{CpuPowerState, REG_HALTCNT, REG_IE, REG_KEYCNT};
+
+    /// KEYCNT: interrupt when L, R, and Select are all pressed (like games' sleep modes).
+    const KEYCNT_L_R_SELECT: u16 = (1 << 15) | (1 << 14) | (1 << 9) | (1 << 8) | (1 << 2);
+
+    #[test]
+    fn stop_wakes_on_keypad_combination() {
+        let mut rom = vec![0; 0x200];
+        // b . (infinite loop)
+        rom[..4].copy_from_slice(&0xEAFF_FFFEu32.to_le_bytes());
+        let mut gba = Gba::new_for_test(&rom);
+        gba.io_write_16(REG_IE, 1 << InterruptKind::Keypad as u16);
+        gba.io_write_16(REG_KEYCNT, KEYCNT_L_R_SELECT);
+        gba.io_write_8(REG_HALTCNT, 0x80);
+        assert!(gba.io.power_state == CpuPowerState::Stopped);
+
+        // Frontends set the keypad state every frame. With nothing (or only some of the
+        // keys) held, it stays stopped.
+        let mut keys = KeypadState::default();
+        for _ in 0..3 {
+            gba.set_keypad_state(keys);
+            gba.emulate_frame(true);
+            assert!(gba.io.power_state == CpuPowerState::Stopped);
+        }
+        keys.l = true;
+        keys.r = true;
+        gba.set_keypad_state(keys);
+        gba.emulate_frame(true);
+        assert!(gba.io.power_state == CpuPowerState::Stopped);
+
+        keys.select = true;
+        gba.set_keypad_state(keys);
+        gba.emulate_frame(true);
+        assert!(gba.io.power_state == CpuPowerState::Normal);
+    }
+}
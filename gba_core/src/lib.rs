@@ -4,14 +4,18 @@ mod cartridge;
 mod cpu;
 mod dma;
 mod gba;
+mod idle;
 mod interrupt;
 mod io;
 mod keypad;
 mod mem;
-mod ppu;
+pub mod ppu;
 mod scheduler;
+#[cfg(test)]
+mod test_images;
 mod timer;
 pub mod util;
+pub mod video;
 
 use apu::Apu;
 use bus::Bus;
@@ -26,6 +30,7 @@ use scheduler::{Event, Scheduler};
 use timer::TimerManager;
 
 pub use apu::{AUDIO_CHANNELS, AUDIO_SAMPLE_RATE};
-pub use cartridge::{BackupFile, BackupType, Rom};
+pub use cartridge::{BackupFile, BackupType, FlashChip, Rom};
 pub use gba::{Gba, HEIGHT, WIDTH};
 pub use keypad::KeypadState;
+pub use ppu::{ColorProfile, FrameBlending};
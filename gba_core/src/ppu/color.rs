@@ -16,6 +16,12 @@ impl Color15 {
         0xFF00_0000 | r | g | b
     }
 
+    /// Convert the 15-bit color to 32-bit RGBA bytes.
+    pub fn as_rgba(self) -> [u8; 4] {
+        let (r, g, b) = self.as_rgb();
+        [(r << 3) as u8, (g << 3) as u8, (b << 3) as u8, 0xFF]
+    }
+
     pub fn as_rgb(self) -> (u16, u16, u16) {
         let r = (self.0 >> 0) & 0b11111;
         let g = (self.0 >> 5) & 0b11111;
@@ -0,0 +1,343 @@
+//! Debug views of the PPU state (like mGBA's inspection tools).
+//!
+//! Everything is rendered from the current contents of VRAM, OAM, and palette RAM into
+//! [`Image`]s, which frontends can display or save.
+
+use super::{
+    color::Color15,
+    obj_vram_start,
+    render::{objects::ObjectAttributes, PALETTE_TABLE_BG, PALETTE_TABLE_OBJ},
+    ColorMode,
+};
+use crate::{Gba, HEIGHT, WIDTH};
+
+pub use super::render::objects::{GraphicsMode, ObjectMode};
+
+/// Size (in pixels) of each color swatch in [`palette`].
+pub const SWATCH_SIZE: usize = 8;
+
+/// Number of tiles in each row of a tile sheet.
+const TILES_PER_ROW: usize = 32;
+
+/// An RGBA image.
+pub struct Image {
+    /// Width in pixels.
+    pub width: usize,
+
+    /// Height in pixels.
+    pub height: usize,
+
+    /// Pixels: row major, 4 bytes (R, G, B, A) per pixel. Transparent pixels have A = 0.
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Create a fully transparent image.
+    fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            data: vec![0; width * height * 4],
+        }
+    }
+
+    /// Set a pixel to a color.
+    fn set(&mut self, x: usize, y: usize, color: Color15) {
+        let offset = (y * self.width + x) * 4;
+        self.data[offset..(offset + 4)].copy_from_slice(&color.as_rgba());
+    }
+}
+
+/// Layers to show on screen. Hidden layers are hidden from the player, not the game.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LayerMask {
+    /// Backgrounds 0-3.
+    pub bg: [bool; 4],
+
+    /// Objects (the object window still works when they're hidden).
+    pub obj: bool,
+
+    /// Windows (when disabled, the whole screen is outside every window).
+    pub windows: bool,
+
+    /// Color special effects: alpha blending and brightness.
+    pub blending: bool,
+}
+
+impl Default for LayerMask {
+    fn default() -> Self {
+        LayerMask {
+            bg: [true; 4],
+            obj: true,
+            windows: true,
+            blending: true,
+        }
+    }
+}
+
+/// Each layer rendered into its own frame buffer, in the same format as
+/// [`Gba::framebuffer`]. Transparent pixels are 0.
+pub struct LayerBuffers {
+    /// Backgrounds 0-3.
+    pub bg: [Box<[u32]>; 4],
+
+    /// Objects (the top object at each pixel, not including the object window).
+    pub obj: Box<[u32]>,
+}
+
+impl LayerBuffers {
+    pub(crate) fn new() -> LayerBuffers {
+        let buffer = || vec![0; WIDTH * HEIGHT].into_boxed_slice();
+        LayerBuffers {
+            bg: [buffer(), buffer(), buffer(), buffer()],
+            obj: buffer(),
+        }
+    }
+}
+
+/// Which half of palette RAM to use.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteTable {
+    Background,
+    Object,
+}
+
+impl PaletteTable {
+    /// Offset of the table in palette RAM.
+    fn address(self) -> u32 {
+        match self {
+            PaletteTable::Background => PALETTE_TABLE_BG,
+            PaletteTable::Object => PALETTE_TABLE_OBJ,
+        }
+    }
+}
+
+/// An object (sprite) in OAM.
+pub struct ObjectInfo {
+    /// Index in OAM.
+    pub index: usize,
+
+    /// Screen position of the top left corner (of the bounding box, for affine objects).
+    pub x: i32,
+    pub y: i32,
+
+    /// Size in pixels (not including the doubled bounding box of affine objects).
+    pub width: usize,
+    pub height: usize,
+
+    pub object_mode: ObjectMode,
+    pub graphics_mode: GraphicsMode,
+    pub mosaic: bool,
+    pub color_mode: ColorMode,
+
+    /// Affine matrix index (only used by affine objects).
+    pub affine_index: usize,
+
+    /// Flips (only used by non-affine objects).
+    pub h_flip: bool,
+    pub v_flip: bool,
+
+    /// Base tile index.
+    pub tile_index: usize,
+    pub priority: u16,
+
+    /// Palette bank (only used in 4bpp mode).
+    pub palette_bank: u8,
+
+    /// The object's graphics, without flipping or affine transformation.
+    pub image: Image,
+}
+
+/// Returns whether the given background is affine in the current video mode, or None
+/// if it isn't a tiled background in this mode.
+fn background_affine(gba: &Gba, index: usize) -> Option<bool> {
+    match (gba.ppu.dispcnt.mode, index) {
+        (0, _) | (1, 0..=1) => Some(false),
+        (1, 2) | (2, 2..=3) => Some(true),
+        _ => None,
+    }
+}
+
+/// Render the whole tilemap of a background (ignoring scrolling and affine transformation).
+///
+/// Returns None if the background (0-3) isn't a tiled background in the current video mode.
+pub fn background_tilemap(gba: &Gba, index: usize) -> Option<Image> {
+    let affine = background_affine(gba, index)?;
+    let control = gba.ppu.bgcnt[index];
+    let (w, h) = control.size.pixels(affine);
+    let color_mode = if affine {
+        ColorMode::Bpp8
+    } else {
+        control.color_mode
+    };
+    let entry_address_base = 0x800 * (control.screen_base_block as u32);
+    let tile_address_base = 0x4000 * (control.character_base_block as u32);
+    let bg_vram_end = obj_vram_start(gba.ppu.dispcnt.mode);
+    let vram = &gba.ppu.vram;
+
+    let mut image = Image::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            let (tile_x, tile_y) = ((x / 8) as u32, (y / 8) as u32);
+            let (mut subtile_x, mut subtile_y) = ((x % 8) as u32, (y % 8) as u32);
+            let (tile_index, palette_bank) = if affine {
+                let entry_address = entry_address_base + tile_x + tile_y * (w as u32 / 8);
+                (vram[entry_address as usize] as u32, 0)
+            } else {
+                // Screenblocks are 32x32 tiles each.
+                let mut entry_index = (tile_x % 32) + (tile_y % 32) * 32;
+                if tile_x >= 32 {
+                    entry_index += 0x400;
+                }
+                if tile_y >= 32 {
+                    entry_index += if w == 512 { 0x800 } else { 0x400 };
+                }
+                let entry_address = (entry_address_base + entry_index * 2) as usize;
+                let entry = u16::from_le_bytes([vram[entry_address], vram[entry_address + 1]]);
+                if (entry & (1 << 10)) != 0 {
+                    subtile_x = 7 - subtile_x;
+                }
+                if (entry & (1 << 11)) != 0 {
+                    subtile_y = 7 - subtile_y;
+                }
+                ((entry & 0x3FF) as u32, (entry >> 12) as u32)
+            };
+
+            let tile_size = match color_mode {
+                ColorMode::Bpp4 => 0x20,
+                ColorMode::Bpp8 => 0x40,
+            };
+            let tile_address = tile_address_base + tile_size * tile_index;
+            if tile_address >= bg_vram_end {
+                // Backgrounds can't fetch tiles from OBJ VRAM.
+                continue;
+            }
+            let pixel = gba.tile_get_index(color_mode, tile_address, subtile_x, subtile_y);
+            let bank = match color_mode {
+                ColorMode::Bpp4 => palette_bank,
+                ColorMode::Bpp8 => 0,
+            };
+            let color = gba.palette_get_color(pixel, bank, PALETTE_TABLE_BG);
+            if !color.transparent() {
+                image.set(x, y, color);
+            }
+        }
+    }
+    Some(image)
+}
+
+/// Render all of VRAM as a sheet of tiles, 32 tiles wide.
+///
+/// `palette_bank` is only used for 4bpp tiles.
+pub fn tiles(gba: &Gba, color_mode: ColorMode, table: PaletteTable, palette_bank: u8) -> Image {
+    let (tile_size, bank) = match color_mode {
+        ColorMode::Bpp4 => (0x20, palette_bank as u32 & 0xF),
+        ColorMode::Bpp8 => (0x40, 0),
+    };
+    let tile_count = gba.ppu.vram.len() / tile_size;
+    let rows = tile_count.div_ceil(TILES_PER_ROW);
+
+    let mut image = Image::new(TILES_PER_ROW * 8, rows * 8);
+    for tile in 0..tile_count {
+        let address = (tile * tile_size) as u32;
+        let (left, top) = ((tile % TILES_PER_ROW) * 8, (tile / TILES_PER_ROW) * 8);
+        for y in 0..8 {
+            for x in 0..8 {
+                let pixel = gba.tile_get_index(color_mode, address, x, y);
+                let color = gba.palette_get_color(pixel, bank, table.address());
+                if !color.transparent() {
+                    image.set(left + x as usize, top + y as usize, color);
+                }
+            }
+        }
+    }
+    image
+}
+
+/// Decode every entry in OAM, and render its graphics.
+pub fn objects(gba: &Gba) -> Vec<ObjectInfo> {
+    (0..128).map(|index| object(gba, index)).collect()
+}
+
+/// Decode an entry (0-127) in OAM, and render its graphics.
+pub fn object(gba: &Gba, index: usize) -> ObjectInfo {
+    let attrs = ObjectAttributes::read(&gba.ppu.oam, index);
+    let (x, y) = attrs.pos();
+    let (w, h) = attrs.size();
+    let (width, height) = (w as usize, h as usize);
+    let color_mode = attrs.color_mode();
+    let (tiles_wide, bank) = match color_mode {
+        ColorMode::Bpp4 => (width as u32 / 8, attrs.palette_bank() as u32),
+        ColorMode::Bpp8 => (width as u32 / 4, 0),
+    };
+    let tile_stride = if gba.ppu.dispcnt.obj_character_vram_mapping {
+        tiles_wide
+    } else {
+        32
+    };
+    let obj_start = obj_vram_start(gba.ppu.dispcnt.mode);
+
+    let mut image = Image::new(width, height);
+    for sprite_y in 0..height {
+        for sprite_x in 0..width {
+            let (tile_x, tile_y) = ((sprite_x / 8) as u32, (sprite_y / 8) as u32);
+            let tile_start = attrs.tile_index() as u32 + tile_y * tile_stride;
+            let tile_index = match color_mode {
+                ColorMode::Bpp4 => tile_start + tile_x,
+                ColorMode::Bpp8 => tile_start + 2 * tile_x,
+            };
+            let tile_address = 0x10000 + (tile_index % 1024) * 32;
+            if tile_address < obj_start {
+                // In bitmap modes, tiles 0-511 overlap the bitmap and are transparent.
+                continue;
+            }
+            let (subtile_x, subtile_y) = ((sprite_x % 8) as u32, (sprite_y % 8) as u32);
+            let pixel = gba.tile_get_index(color_mode, tile_address, subtile_x, subtile_y);
+            let color = gba.palette_get_color(pixel, bank, PALETTE_TABLE_OBJ);
+            if !color.transparent() {
+                image.set(sprite_x, sprite_y, color);
+            }
+        }
+    }
+
+    ObjectInfo {
+        index,
+        x,
+        y,
+        width,
+        height,
+        object_mode: attrs.object_mode(),
+        graphics_mode: attrs.gfx_mode(),
+        mosaic: attrs.mosaic(),
+        color_mode,
+        affine_index: attrs.affine_index(),
+        h_flip: attrs.h_flip(),
+        v_flip: attrs.v_flip(),
+        tile_index: attrs.tile_index(),
+        priority: attrs.priority(),
+        palette_bank: attrs.palette_bank(),
+        image,
+    }
+}
+
+/// Render palette RAM as swatches of [`SWATCH_SIZE`] pixels: 16 colors (one bank) per row,
+/// with the 16 background banks above the 16 object banks.
+pub fn palette(gba: &Gba) -> Image {
+    let mut image = Image::new(16 * SWATCH_SIZE, 32 * SWATCH_SIZE);
+    for row in 0..32 {
+        let (table, bank) = if row < 16 {
+            (PaletteTable::Background, row)
+        } else {
+            (PaletteTable::Object, row - 16)
+        };
+        for column in 0..16 {
+            let color = gba.palette_read_color(column as u8, bank as u32, table.address());
+            for y in 0..SWATCH_SIZE {
+                for x in 0..SWATCH_SIZE {
+                    image.set(column * SWATCH_SIZE + x, row * SWATCH_SIZE + y, color);
+                }
+            }
+        }
+    }
+    image
+}
@@ -0,0 +1,92 @@
+/// Blending of each frame with the previous ones, to emulate the slow response of the
+/// LCD. Some games flicker objects every other frame to fake transparency, relying on it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FrameBlending {
+    /// Show each frame as-is.
+    #[default]
+    Off,
+    /// Show the exact average of the current and previous frames.
+    Average,
+    /// Mix the current frame with the previous one, which has the given weight (0.0 to
+    /// 1.0). Like `Average` (which is a weight of 0.5), but the previous frame can linger
+    /// more or less.
+    Ghosting(f32),
+}
+
+/// State for frame blending.
+#[derive(Default)]
+pub(crate) struct FrameBlender {
+    pub mode: FrameBlending,
+
+    /// The previous frame, as rendered (not blended).
+    previous: Box<[u32]>,
+
+    /// The blended frame that's shown.
+    pub output: Box<[u32]>,
+}
+
+impl FrameBlender {
+    /// Create a blender, starting from the given frame (as if it had been shown for a while).
+    pub fn new(mode: FrameBlending, frame: &[u32]) -> FrameBlender {
+        match mode {
+            FrameBlending::Off => FrameBlender::default(),
+            _ => FrameBlender {
+                mode,
+                previous: frame.into(),
+                output: frame.into(),
+            },
+        }
+    }
+
+    /// Forget the previous frames, and show the given frame as-is.
+    pub fn reset(&mut self, frame: &[u32]) {
+        if self.mode != FrameBlending::Off {
+            self.previous.copy_from_slice(frame);
+            self.output.copy_from_slice(frame);
+        }
+    }
+
+    /// Blend a finished frame into the output.
+    pub fn blend(&mut self, frame: &[u32]) {
+        match self.mode {
+            FrameBlending::Off => {}
+            FrameBlending::Average => self.blend_with(frame, average),
+            FrameBlending::Ghosting(weight) => {
+                let weight = (weight.clamp(0.0, 1.0) * 256.0) as u32;
+                self.blend_with(frame, |previous, current| mix(previous, current, weight));
+            }
+        }
+    }
+
+    /// Set each output pixel to `f(previous, current)`, and keep the frame as the previous
+    /// one.
+    #[inline(always)]
+    fn blend_with(&mut self, frame: &[u32], f: impl Fn(u32, u32) -> u32) {
+        for ((output, previous), &current) in self
+            .output
+            .iter_mut()
+            .zip(self.previous.iter_mut())
+            .zip(frame.iter())
+        {
+            *output = f(*previous, current);
+            *previous = current;
+        }
+    }
+}
+
+/// Average two ARGB colors (rounding down), without unpacking the channels.
+#[inline(always)]
+fn average(a: u32, b: u32) -> u32 {
+    (a & b) + (((a ^ b) & 0xFEFE_FEFE) >> 1)
+}
+
+/// Mix two ARGB colors, with `a` weighted by `weight` / 256.
+#[inline(always)]
+fn mix(a: u32, b: u32, weight: u32) -> u32 {
+    let channel = |shift: u32| {
+        let a = (a >> shift) & 0xFF;
+        let b = (b >> shift) & 0xFF;
+        ((a * weight + b * (256 - weight)) >> 8) << shift
+    };
+    0xFF00_0000 | channel(16) | channel(8) | channel(0)
+}
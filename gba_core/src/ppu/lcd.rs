@@ -0,0 +1,114 @@
+use super::color::Color15;
+
+/// How colors are converted for output, to look like a particular screen.
+///
+/// The corrected profiles are approximations, in the style of Pokefan531's color shaders.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorProfile {
+    /// Expand each 5-bit channel linearly (what the game asked for, but oversaturated
+    /// compared to a real GBA).
+    #[default]
+    Raw,
+    /// The original GBA LCD: dark, and washed out.
+    Gba,
+    /// The backlit GBA SP (AGS-101).
+    GbaSp101,
+    /// The Game Boy Player on a TV.
+    GameBoyPlayer,
+}
+
+/// Parameters for color correction.
+struct Correction {
+    /// Gamma of the emulated screen.
+    gamma: f64,
+
+    /// Overall brightness.
+    luminance: f64,
+
+    /// Color matrix: each row gives the output channel (R, G, B) from the input channels.
+    matrix: [[f64; 3]; 3],
+}
+
+/// Gamma of the output display.
+const DISPLAY_GAMMA: f64 = 2.2;
+
+impl ColorProfile {
+    fn correction(self) -> Option<Correction> {
+        match self {
+            ColorProfile::Raw => None,
+            ColorProfile::Gba => Some(Correction {
+                gamma: 3.2,
+                luminance: 0.94,
+                matrix: [
+                    [0.82, 0.24, -0.06],
+                    [0.125, 0.665, 0.21],
+                    [0.195, 0.075, 0.73],
+                ],
+            }),
+            ColorProfile::GbaSp101 => Some(Correction {
+                gamma: 2.2,
+                luminance: 0.94,
+                matrix: [
+                    [0.86, 0.19, -0.05],
+                    [0.11, 0.66, 0.23],
+                    [0.1325, 0.0575, 0.81],
+                ],
+            }),
+            ColorProfile::GameBoyPlayer => Some(Correction {
+                gamma: 2.4,
+                luminance: 1.0,
+                matrix: [[0.93, 0.1, -0.03], [0.06, 0.85, 0.09], [0.03, 0.06, 0.91]],
+            }),
+        }
+    }
+}
+
+/// Lookup table from 15-bit colors to output ARGB colors, for a color profile.
+pub(crate) struct ColorLut {
+    profile: ColorProfile,
+    table: Box<[u32]>,
+}
+
+impl ColorLut {
+    pub fn new(profile: ColorProfile) -> ColorLut {
+        let correction = profile.correction();
+        let table = (0..0x8000)
+            .map(|raw| {
+                let color = Color15(raw);
+                match &correction {
+                    None => color.as_argb(),
+                    Some(correction) => correct(color, correction),
+                }
+            })
+            .collect();
+        ColorLut { profile, table }
+    }
+
+    pub fn profile(&self) -> ColorProfile {
+        self.profile
+    }
+
+    /// Convert a color to ARGB for output.
+    #[inline(always)]
+    pub fn get(&self, color: Color15) -> u32 {
+        self.table[(color.0 & 0x7FFF) as usize]
+    }
+}
+
+impl Default for ColorLut {
+    fn default() -> Self {
+        ColorLut::new(ColorProfile::default())
+    }
+}
+
+/// Convert a color to ARGB, with color correction.
+fn correct(color: Color15, correction: &Correction) -> u32 {
+    let (r, g, b) = color.as_rgb();
+    let linear = [r, g, b].map(|c| (c as f64 / 31.0).powf(correction.gamma));
+    let [r, g, b] = correction.matrix.map(|row| {
+        let c = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+        let c = (c * correction.luminance).clamp(0.0, 1.0);
+        (c.powf(1.0 / DISPLAY_GAMMA) * 255.0).round() as u32
+    });
+    0xFF00_0000 | (r << 16) | (g << 8) | b
+}
@@ -1,13 +1,21 @@
 use crate::{
+    bus::{region_from_address, REGION_OAM, REGION_VRAM},
     scheduler::{Event, PpuEvent},
     Gba, InterruptKind, HEIGHT, WIDTH,
 };
 use color::Color15;
+pub(crate) use frame_blend::FrameBlender;
+pub(crate) use lcd::ColorLut;
 use registers::*;
 
 mod color;
+pub mod debug;
+mod frame_blend;
+mod lcd;
 mod registers;
 mod render;
+#[cfg(test)]
+mod tests;
 
 #[allow(unused)]
 mod constants {
@@ -22,12 +30,40 @@ mod constants {
     pub const CYCLES_VDRAW: usize = CYCLES_SCANLINE * PIXELS_HEIGHT;
     pub const CYCLES_VBLANK: usize = CYCLES_SCANLINE * SCANLINES_VBLANK;
     pub const CYCLES_FRAME: usize = CYCLES_VDRAW + CYCLES_VBLANK;
+    pub const VRAM_SIZE: usize = 96 * 1024;
 }
 pub use constants::*;
+pub use frame_blend::FrameBlending;
+pub use lcd::ColorProfile;
 use serde::{Deserialize, Serialize};
 
+/// Translate an address in the VRAM region to an offset into VRAM.
+///
+/// VRAM is mirrored every 128 KiB, and within that, 0x18000-0x1FFFF mirrors the
+/// OBJ tiles at 0x10000-0x17FFF.
+#[inline(always)]
+pub(crate) fn vram_offset(addr: u32) -> u32 {
+    let addr = addr & 0x1FFFF;
+    if addr >= 0x18000 {
+        addr - 0x8000
+    } else {
+        addr
+    }
+}
+
+/// Returns the start of OBJ VRAM in the current video mode: BG data takes more space
+/// in bitmap modes.
+#[inline(always)]
+fn obj_vram_start(mode: u16) -> u32 {
+    if mode >= 3 {
+        0x14000
+    } else {
+        0x10000
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-pub struct Ppu {
+pub(crate) struct Ppu {
     /// Framebuffer: row major, each pixel is ARGB, length (WIDTH * HEIGHT).
     pub framebuffer: Box<[u32]>,
 
@@ -92,6 +128,24 @@ pub struct Ppu {
     /// Current frame.
     #[allow(unused)]
     pub frame: usize,
+
+    /// Lookup table for output colors (for the color profile).
+    #[serde(skip)]
+    pub color_lut: ColorLut,
+
+    /// Frame blending state.
+    #[serde(skip)]
+    pub frame_blender: FrameBlender,
+
+    /// Whether to render scanlines in chunks as they're drawn, instead of all at once.
+    #[serde(skip)]
+    pub sub_scanline: bool,
+
+    /// Timestamp of the start of the current scanline's HDraw.
+    pub line_start: usize,
+
+    /// Number of pixels of the current scanline rendered so far (in sub-scanline mode).
+    pub line_rendered: usize,
 }
 
 impl Ppu {
@@ -115,9 +169,13 @@ impl Ppu {
             vcount: 0,
             frame: 0,
             window_scanline_active: [false; 2],
+            color_lut: ColorLut::default(),
+            frame_blender: FrameBlender::default(),
+            sub_scanline: false,
+            line_start: 0,
+            line_rendered: PIXELS_WIDTH,
 
-            // 96KiB, but we'll make it 128KiB for accesses
-            vram: vec![0; 128 * 1024].into_boxed_slice(),
+            vram: vec![0; VRAM_SIZE].into_boxed_slice(),
             palette: vec![0; 1024].into_boxed_slice(),
             oam: vec![0; 1024].into_boxed_slice(),
         }
@@ -139,6 +197,47 @@ impl Gba {
             .push_event(Event::Ppu(PpuEvent::EndHDraw), CYCLES_HDRAW);
     }
 
+    /// Returns whether the PPU is currently accessing the video memory at `addr` (so a
+    /// CPU access would have to wait). The PPU only fetches data for enabled layers.
+    pub(crate) fn ppu_memory_busy(&self, addr: u32) -> bool {
+        let dispcnt = &self.ppu.dispcnt;
+        if dispcnt.forced_blank || self.ppu.dispstat.vblank {
+            return false;
+        }
+        let any_bg = dispcnt.display_bg.iter().any(|&enabled| enabled);
+        match region_from_address(addr) {
+            // OAM is also read during H-Blank, unless "H-Blank interval free" is set.
+            REGION_OAM => {
+                dispcnt.display_obj && (!self.ppu.dispstat.hblank || !dispcnt.h_blank_interval_free)
+            }
+            REGION_VRAM if vram_offset(addr) >= obj_vram_start(dispcnt.mode) => {
+                dispcnt.display_obj && !self.ppu.dispstat.hblank
+            }
+            // BG VRAM and palette RAM.
+            _ => any_bg && !self.ppu.dispstat.hblank,
+        }
+    }
+
+    /// Handle an 8-bit write to VRAM.
+    pub(crate) fn ppu_vram_write_8(&mut self, addr: u32, value: u8) {
+        let offset = vram_offset(addr);
+        if offset < obj_vram_start(self.ppu.dispcnt.mode) {
+            // Byte writes to BG VRAM write the byte to both halves of the halfword.
+            let offset = (offset & !1) as usize;
+            self.ppu.vram[offset] = value;
+            self.ppu.vram[offset | 1] = value;
+        }
+        // Byte writes to OBJ VRAM are ignored.
+    }
+
+    /// Blank the screen (the LCD is turned off, e.g. in Stop mode).
+    pub(crate) fn ppu_blank_screen(&mut self) {
+        let ppu = &mut self.ppu;
+        ppu.framebuffer.fill(ppu.color_lut.get(Color15::BLACK));
+        // Frame blending only updates at V-Blank, which won't happen while the LCD is off.
+        ppu.frame_blender.reset(&ppu.framebuffer);
+    }
+
     fn update_vcount(&mut self, new_vcount: u16) {
         self.ppu.vcount = new_vcount;
         self.ppu.dispstat.vcounter = self.ppu.dispstat.vcount_setting == new_vcount;
@@ -157,6 +256,40 @@ impl Gba {
         }
     }
 
+    /// Advance the vertical mosaic counters at the start of a visible scanline.
+    ///
+    /// Mosaic BGs and OBJs keep showing the first line of the current block until the
+    /// counter reaches the mosaic size (so changing MOSAIC mid-frame affects later blocks).
+    fn ppu_update_mosaic(&mut self) {
+        let vcount = self.ppu.vcount;
+        let mosaic = &mut self.ppu.mosaic;
+        let (bg_start, obj_start) = if vcount == 0 {
+            mosaic.bg_counter = 0;
+            mosaic.obj_counter = 0;
+            (true, true)
+        } else {
+            mosaic.bg_counter += 1;
+            mosaic.obj_counter += 1;
+            (
+                mosaic.bg_counter >= mosaic.bg_y,
+                mosaic.obj_counter >= mosaic.obj_y,
+            )
+        };
+
+        if bg_start {
+            mosaic.bg_counter = 0;
+            mosaic.bg_line = vcount;
+            for affine in self.ppu.bg_affine.iter_mut() {
+                affine.mosaic_dx = affine.internal_dx;
+                affine.mosaic_dy = affine.internal_dy;
+            }
+        }
+        if obj_start {
+            mosaic.obj_counter = 0;
+            mosaic.obj_line = vcount;
+        }
+    }
+
     pub fn ppu_on_event(&mut self, event: PpuEvent, lateness: usize) {
         let (next_event, deadline) = match event {
             PpuEvent::EndHDraw => self.ppu_on_end_hdraw(),
@@ -164,11 +297,47 @@ impl Gba {
             PpuEvent::EndVBlankHDraw => self.ppu_on_end_vblank_hdraw(),
             PpuEvent::EndVBlankHBlank => self.ppu_on_end_vblank_hblank(),
         };
+        if next_event == PpuEvent::EndHDraw {
+            self.ppu.line_start = self.scheduler.timestamp() - lateness;
+        }
         let deadline = deadline - lateness;
         self.scheduler.push_event(Event::Ppu(next_event), deadline);
     }
 
+    /// Start rendering a visible scanline (at the start of its HDraw).
+    fn ppu_start_scanline(&mut self, should_render: bool) {
+        if !should_render {
+            self.ppu.line_rendered = PIXELS_WIDTH;
+        } else if self.ppu.sub_scanline {
+            // Pixels are rendered as HDraw goes on, so mid-line writes take effect.
+            self.ppu.line_rendered = 0;
+        } else {
+            self.ppu_render_scanline(0, PIXELS_WIDTH);
+            self.ppu.line_rendered = PIXELS_WIDTH;
+        }
+    }
+
+    /// In sub-scanline mode, render the current scanline up to the current cycle.
+    ///
+    /// This must be called before anything that affects rendering (PPU registers,
+    /// palette, VRAM, or OAM) is written.
+    #[inline]
+    pub(crate) fn ppu_catch_up(&mut self) {
+        if self.ppu.line_rendered == PIXELS_WIDTH || self.ppu.dispstat.vblank {
+            return;
+        }
+        let elapsed = self.scheduler.timestamp() - self.ppu.line_start;
+        let x = (elapsed / CYCLES_PIXEL).min(PIXELS_WIDTH);
+        if x > self.ppu.line_rendered {
+            self.ppu_render_scanline(self.ppu.line_rendered, x);
+            self.ppu.line_rendered = x;
+        }
+    }
+
     fn ppu_on_end_hdraw(&mut self) -> (PpuEvent, usize) {
+        // Finish the current scanline.
+        self.ppu_catch_up();
+
         self.ppu.dispstat.hblank = true;
         if self.ppu.dispstat.hblank_irq {
             self.interrupt_raise(InterruptKind::HBlank);
@@ -195,6 +364,12 @@ impl Gba {
             }
             self.dma_notify_vblank();
 
+            // The frame is finished.
+            if self.should_render {
+                let ppu = &mut self.ppu;
+                ppu.frame_blender.blend(&ppu.framebuffer);
+            }
+
             // Copy the affine displacement registers to the internal ones.
             for i in 0..2 {
                 self.ppu.bg_affine[i].internal_dx = self.ppu.bg_affine[i].dx;
@@ -208,11 +383,10 @@ impl Gba {
                 self.ppu.bg_affine[i].internal_dx += self.ppu.bg_affine[i].pb as i32;
                 self.ppu.bg_affine[i].internal_dy += self.ppu.bg_affine[i].pd as i32;
             }
+            self.ppu_update_mosaic();
 
             // Draw the next scanline (which is visible).
-            if self.should_render {
-                self.ppu_render_scanline();
-            }
+            self.ppu_start_scanline(self.should_render);
 
             (PpuEvent::EndHDraw, CYCLES_HDRAW)
         }
@@ -240,9 +414,10 @@ impl Gba {
             self.ppu.dispstat.vblank = false;
             self.update_vcount(0);
             self.ppu.frame += 1;
+            self.ppu_update_mosaic();
 
             // Draw the first scanline.
-            self.ppu_render_scanline();
+            self.ppu_start_scanline(true);
 
             (PpuEvent::EndHDraw, CYCLES_HDRAW)
         } else {
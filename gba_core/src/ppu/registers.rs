@@ -212,6 +212,9 @@ pub struct BackgroundAffine {
     pub dy: i32,
     pub internal_dx: i32,
     pub internal_dy: i32,
+    /// Internal reference point at the start of the current vertical mosaic block.
+    pub mosaic_dx: i32,
+    pub mosaic_dy: i32,
 }
 
 /// MOSAIC - Mosaic size.
@@ -225,6 +228,15 @@ pub struct Mosaic {
     pub obj_x: u8,
     /// OBJ mosaic actual v-size.
     pub obj_y: u8,
+
+    /// Internal BG vertical mosaic counter: lines since the current block started.
+    pub bg_counter: u8,
+    /// Internal OBJ vertical mosaic counter: lines since the current block started.
+    pub obj_counter: u8,
+    /// First scanline of the current BG mosaic block.
+    pub bg_line: u16,
+    /// First scanline of the current OBJ mosaic block.
+    pub obj_line: u16,
 }
 
 impl Default for Mosaic {
@@ -234,6 +246,10 @@ impl Default for Mosaic {
             bg_y: 1,
             obj_x: 1,
             obj_y: 1,
+            bg_counter: 0,
+            obj_counter: 0,
+            bg_line: 0,
+            obj_line: 0,
         }
     }
 }
@@ -1,5 +1,7 @@
+use std::ops::Range;
+
 use super::{BackgroundBuffer, PALETTE_TABLE_BG};
-use crate::ppu::{ColorMode, PIXELS_WIDTH};
+use crate::ppu::{obj_vram_start, ColorMode};
 use crate::{mem::Memory, Gba};
 use bit::BitIndex;
 
@@ -40,27 +42,29 @@ impl Gba {
         }
     }
 
-    /// Apply mosaic effect to a background Y coordinate.
+    /// Get the scanline to render a background from: the first line of the current
+    /// vertical mosaic block, if mosaic is enabled.
     #[inline]
-    pub(super) fn bg_mosaic_y(&self, index: usize, y: u32) -> u32 {
+    pub(super) fn bg_mosaic_y(&self, index: usize) -> u32 {
         if self.ppu.bgcnt[index].mosaic {
-            let size = self.ppu.mosaic.bg_y as u32;
-            y - (y % size)
+            self.ppu.mosaic.bg_line as u32
         } else {
-            y
+            self.ppu.vcount as u32
         }
     }
 
-    /// Render an affine background in the current scanline.
+    /// Render pixels `pixels` of an affine background in the current scanline.
     pub(super) fn ppu_render_affine_background(
         &mut self,
         index: usize,
         buffer: &mut BackgroundBuffer,
+        pixels: Range<usize>,
     ) {
         let control = self.ppu.bgcnt[index];
         let (w, h) = control.size.pixels(true);
+        let bg_vram_end = obj_vram_start(self.ppu.dispcnt.mode);
 
-        for screen_x in 0..PIXELS_WIDTH {
+        for screen_x in pixels {
             let transformed = self.bg_affine_transform(index, screen_x as i32, w as i32, h as i32);
             let (tx, ty) = match transformed {
                 Some(x) => x,
@@ -79,17 +83,23 @@ impl Gba {
 
             let tile_address_base = 0x4000 * (control.character_base_block as u32);
             let tile_address = tile_address_base + (0x40 * (entry as u32));
-            let index = self.tile_8bpp_get_index(tile_address, subtile_x, subtile_y);
+            let index = if tile_address < bg_vram_end {
+                self.tile_8bpp_get_index(tile_address, subtile_x, subtile_y)
+            } else {
+                // Backgrounds can't fetch tiles from OBJ VRAM.
+                0
+            };
             let color = self.palette_get_color(index, 0, PALETTE_TABLE_BG);
-            buffer[screen_x as usize] = color;
+            buffer[screen_x] = color;
         }
     }
 
-    /// Render a regular (non-affine) background in the current scanline.
+    /// Render pixels `pixels` of a regular (non-affine) background in the current scanline.
     pub(super) fn ppu_render_regular_background(
         &mut self,
         index: usize,
         buffer: &mut BackgroundBuffer,
+        pixels: Range<usize>,
     ) {
         let off_x = self.ppu.bg_hofs[index];
         let off_y = self.ppu.bg_vofs[index];
@@ -97,7 +107,7 @@ impl Gba {
         let (w, h) = control.size.pixels(false);
 
         // Y coordinate of the line of the background we're rendering.
-        let screen_y = self.bg_mosaic_y(index, self.ppu.vcount as u32);
+        let screen_y = self.bg_mosaic_y(index);
         let bg_y = ((off_y as u32) + screen_y) % (h as u32);
         let tile_y = bg_y / 8;
         let subtile_y = bg_y % 8;
@@ -105,8 +115,9 @@ impl Gba {
         // Address of the base screenblock and tileblock in VRAM.
         let entry_address_base = 0x800 * (control.screen_base_block as u32);
         let tile_address_base = 0x4000 * (control.character_base_block as u32);
+        let bg_vram_end = obj_vram_start(self.ppu.dispcnt.mode);
 
-        for original_screen_x in 0..PIXELS_WIDTH {
+        for original_screen_x in pixels {
             let screen_x = self.bg_mosaic_x(index, original_screen_x as u32);
             // XXX: consider doing optimization to keep the same tile data for 8 pixels.
             let bg_x = ((off_x as u32) + screen_x) % (w as u32);
@@ -138,23 +149,31 @@ impl Gba {
                 subtile_y
             };
 
-            // Load the tile data.
-            // TODO don't allow accessing data in tileblocks 4-5 (object tileblocks).
+            // Load the tile data. Tiles in OBJ VRAM (tileblocks 4-5) can't be fetched by
+            // backgrounds, and are transparent.
             let tile_index = entry.tile_index() as u32;
             let (index, palette_bank) = match control.color_mode {
                 ColorMode::Bpp4 => {
                     let address = tile_address_base + (0x20 * tile_index);
-                    let index = self.tile_4bpp_get_index(address, subtile_x, subtile_y);
+                    let index = if address < bg_vram_end {
+                        self.tile_4bpp_get_index(address, subtile_x, subtile_y)
+                    } else {
+                        0
+                    };
                     (index, entry.palette_bank())
                 }
                 ColorMode::Bpp8 => {
                     let address = tile_address_base + (0x40 * tile_index);
-                    let index = self.tile_8bpp_get_index(address, subtile_x, subtile_y);
+                    let index = if address < bg_vram_end {
+                        self.tile_8bpp_get_index(address, subtile_x, subtile_y)
+                    } else {
+                        0
+                    };
                     (index, 0)
                 }
             };
             let color = self.palette_get_color(index, palette_bank, PALETTE_TABLE_BG);
-            buffer[original_screen_x as usize] = color;
+            buffer[original_screen_x] = color;
         }
     }
 }
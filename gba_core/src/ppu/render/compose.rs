@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use super::{BackgroundBuffer, ObjectBuffer, ObjectBufferEntry};
 use crate::{
     mem::Memory,
@@ -10,12 +12,14 @@ use crate::{
 };
 
 impl Gba {
-    /// Do final composition of a scanline and write it to the screenbuffer.
+    /// Do final composition of the given pixels of a scanline and write them to the
+    /// screenbuffer.
     pub(super) fn ppu_compose_scanline(
         &mut self,
         object_buffer: &ObjectBuffer,
         background_buffers: &[BackgroundBuffer; 4],
         background_indices: &mut [usize],
+        pixels: Range<usize>,
     ) {
         let framebuffer_offset = PIXELS_WIDTH * (self.ppu.vcount as usize);
         let backdrop_color = Color15(self.ppu.palette.read_16(0));
@@ -23,7 +27,7 @@ impl Gba {
         // Sort backgrounds.
         background_indices.sort_by_key(|&x| self.ppu.bgcnt[x].priority);
 
-        for x in 0..PIXELS_WIDTH {
+        for x in pixels {
             let obj = &object_buffer[x];
             let color = self.compose_pixel(
                 background_buffers,
@@ -32,7 +36,7 @@ impl Gba {
                 x,
                 backdrop_color,
             );
-            self.ppu.framebuffer[framebuffer_offset + x] = color.as_argb();
+            self.ppu.framebuffer[framebuffer_offset + x] = self.ppu.color_lut.get(color);
         }
     }
 
@@ -45,7 +49,7 @@ impl Gba {
         backdrop_color: Color15,
     ) -> Color15 {
         // First determine the active window.
-        let window = if !self.ppu.dispcnt.windows_enabled() {
+        let window = if !self.ppu.dispcnt.windows_enabled() || !self.layer_mask.windows {
             WindowControl::none()
         } else {
             if self.ppu.dispcnt.window_display[0]
@@ -65,73 +69,89 @@ impl Gba {
             }
         };
 
-        // TODO: implement more complex object/background priority interactions.
-        // To support blending, we need to find the top two non-transparent layers.
-        let (top, bottom) = {
-            // First loop at backgrounds and backdrops.
-            let backdrop = Layer::backdrop(backdrop_color);
-            let mut bg_iter = bg_indices
-                .iter()
-                .filter(|&&i| !bg_buffers[i][x].transparent() && window.layer[i]);
-            let mut top = bg_iter.next().map_or(backdrop, |&i| {
-                Layer::background(i, bg_buffers[i][x], self.ppu.bgcnt[i].priority)
-            });
-            let mut bottom = bg_iter.next().map_or(backdrop, |&i| {
-                Layer::background(i, bg_buffers[i][x], self.ppu.bgcnt[i].priority)
-            });
-
-            // Now see if there's an object that goes on top.
-            if self.ppu.dispcnt.display_obj && !obj.color.transparent() && window.layer[KIND_OBJ] {
-                if obj.priority <= top.priority {
-                    bottom = top;
-                    top = Layer::object(obj.color, obj.priority);
-                } else if obj.priority <= bottom.priority {
-                    bottom = Layer::object(obj.color, obj.priority);
-                }
-            }
+        let (top, bottom) =
+            self.top_two_layers(bg_buffers, bg_indices, obj, x, &window, backdrop_color);
+        if !self.layer_mask.blending {
+            return top.color;
+        }
 
-            (top, bottom)
-        };
+        // Semi-transparent objects are always a first target, and always alpha blend with
+        // a second target below them, regardless of BLDCNT's mode and the window.
+        let object_alpha = top.kind == KIND_OBJ && obj.blend;
+        let second_target = self.ppu.bldcnt.bottom[bottom.kind];
+        if object_alpha && second_target {
+            return self.alpha_blend(top, bottom);
+        }
 
-        // Whether the top layer is a blended object (has special behavior).
-        let object_blend = (top.kind == KIND_OBJ) && obj.blend;
-        if !(window.blend || object_blend) {
-            // No blending in this window.
+        // Otherwise, the regular effect applies (if the top layer is a first target).
+        if !window.blend || !self.ppu.bldcnt.top[top.kind] {
             return top.color;
         }
-
-        let blend_top = self.ppu.bldcnt.top[top.kind];
-        let blend_bottom = self.ppu.bldcnt.bottom[bottom.kind];
-        let blend_mode = if object_blend && blend_bottom {
-            BlendMode::Normal
-        } else {
-            self.ppu.bldcnt.mode
-        };
-        let blend_enabled = (blend_top && blend_mode != BlendMode::None) || object_blend;
-
-        if blend_enabled {
-            match blend_mode {
-                BlendMode::Normal if blend_bottom => Color15::blend(
-                    top.color,
-                    bottom.color,
-                    self.ppu.bldalpha.top,
-                    self.ppu.bldalpha.bottom,
-                ),
-                BlendMode::White => {
-                    let fade = self.ppu.bldy.fade.min(16);
-                    Color15::blend(top.color, Color15::WHITE, 16 - fade, fade)
-                }
-                BlendMode::Black => {
-                    let fade = self.ppu.bldy.fade.min(16);
-                    Color15::blend(top.color, Color15::BLACK, 16 - fade, fade)
-                }
-                _ => top.color,
+        match self.ppu.bldcnt.mode {
+            BlendMode::Normal if second_target => self.alpha_blend(top, bottom),
+            BlendMode::White => {
+                let fade = self.ppu.bldy.fade.min(16);
+                Color15::blend(top.color, Color15::WHITE, 16 - fade, fade)
+            }
+            BlendMode::Black => {
+                let fade = self.ppu.bldy.fade.min(16);
+                Color15::blend(top.color, Color15::BLACK, 16 - fade, fade)
             }
+            _ => top.color,
+        }
+    }
+
+    /// Find the top two visible layers at a pixel (the second may be the backdrop).
+    ///
+    /// Layers are ordered by priority. On a tie, objects go above backgrounds, and
+    /// lower-numbered backgrounds go above higher-numbered ones. Only the top object
+    /// pixel takes part: objects never blend with other objects.
+    fn top_two_layers(
+        &self,
+        bg_buffers: &[BackgroundBuffer; 4],
+        bg_indices: &[usize],
+        obj: &ObjectBufferEntry,
+        x: usize,
+        window: &WindowControl,
+        backdrop_color: Color15,
+    ) -> (Layer, Layer) {
+        let mut layers = bg_indices
+            .iter()
+            .filter(|&&i| {
+                !bg_buffers[i][x].transparent() && window.layer[i] && self.layer_mask.bg[i]
+            })
+            .map(|&i| Layer::background(i, bg_buffers[i][x], self.ppu.bgcnt[i].priority));
+        let backdrop = Layer::backdrop(backdrop_color);
+        let top = layers.next().unwrap_or(backdrop);
+        let bottom = layers.next().unwrap_or(backdrop);
+
+        // Insert the object, if it's visible.
+        if !self.ppu.dispcnt.display_obj
+            || !self.layer_mask.obj
+            || obj.color.transparent()
+            || !window.layer[KIND_OBJ]
+        {
+            return (top, bottom);
+        }
+        let object = Layer::object(obj.color, obj.priority);
+        if object.priority <= top.priority {
+            (object, top)
+        } else if object.priority <= bottom.priority {
+            (top, object)
         } else {
-            // No blending. Use the top layer.
-            top.color
+            (top, bottom)
         }
     }
+
+    /// Alpha blend two layers with the coefficients in BLDALPHA.
+    fn alpha_blend(&self, top: Layer, bottom: Layer) -> Color15 {
+        Color15::blend(
+            top.color,
+            bottom.color,
+            self.ppu.bldalpha.top,
+            self.ppu.bldalpha.bottom,
+        )
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -1,14 +1,14 @@
 use super::constants::*;
-use super::Color15;
-use crate::{mem::Memory, Gba};
+use super::{vram_offset, Color15, ColorMode};
+use crate::Gba;
 
 mod backgrounds;
 mod bitmap;
 mod compose;
-mod objects;
+pub(super) mod objects;
 
-const PALETTE_TABLE_BG: u32 = 0x0000;
-const PALETTE_TABLE_OBJ: u32 = 0x0200;
+pub(super) const PALETTE_TABLE_BG: u32 = 0x0000;
+pub(super) const PALETTE_TABLE_OBJ: u32 = 0x0200;
 
 /// Affine transformation matrix.
 struct AffineMatrix {
@@ -61,13 +61,14 @@ type ObjectBuffer = [ObjectBufferEntry; PIXELS_WIDTH];
 type BackgroundBuffer = [Color15; PIXELS_WIDTH];
 
 impl Gba {
-    /// Render the current scanline.
-    pub(super) fn ppu_render_scanline(&mut self) {
+    /// Render pixels `start..end` of the current scanline.
+    pub(super) fn ppu_render_scanline(&mut self, start: usize, end: usize) {
         // Forced blank shows all white.
         if self.ppu.dispcnt.forced_blank {
             let framebuffer_offset = PIXELS_WIDTH * (self.ppu.vcount as usize);
-            for x in 0..PIXELS_WIDTH {
-                self.ppu.framebuffer[framebuffer_offset + x] = Color15::WHITE.as_argb();
+            let white = self.ppu.color_lut.get(Color15::WHITE);
+            for x in start..end {
+                self.ppu.framebuffer[framebuffer_offset + x] = white;
             }
             return;
         }
@@ -75,10 +76,16 @@ impl Gba {
         // Render objects.
         let mut object_buffer = [ObjectBufferEntry::default(); PIXELS_WIDTH];
         if self.ppu.dispcnt.display_obj {
-            self.ppu_render_objects(&mut object_buffer);
+            self.ppu_render_objects(&mut object_buffer, start..end);
         }
 
-        // Render backgrounds.
+        // Render backgrounds. Layers hidden by the layer mask are skipped, unless they're
+        // needed for the layer buffers.
+        let keep_hidden = self.layer_buffers.is_some();
+        let mut display_bg = self.ppu.dispcnt.display_bg;
+        for (display, &shown) in display_bg.iter_mut().zip(self.layer_mask.bg.iter()) {
+            *display &= shown || keep_hidden;
+        }
         let mut background_buffers = [[Color15::TRANSPARENT; PIXELS_WIDTH]; 4];
         let mut background_indices = [0usize; 4];
         let mut background_count = 0;
@@ -86,9 +93,9 @@ impl Gba {
             0 => {
                 // Mode 0: Four regular tilemaps.
                 for i in 0..4 {
-                    if self.ppu.dispcnt.display_bg[i] {
+                    if display_bg[i] {
                         let buffer = &mut background_buffers[i];
-                        self.ppu_render_regular_background(i, buffer);
+                        self.ppu_render_regular_background(i, buffer, start..end);
                         background_indices[background_count] = i;
                         background_count += 1;
                     }
@@ -97,16 +104,16 @@ impl Gba {
             1 => {
                 // Mode 1: Two regular tilemaps (0, 1), one affine (2).
                 for i in 0..2 {
-                    if self.ppu.dispcnt.display_bg[i] {
+                    if display_bg[i] {
                         let buffer = &mut background_buffers[i];
-                        self.ppu_render_regular_background(i, buffer);
+                        self.ppu_render_regular_background(i, buffer, start..end);
                         background_indices[background_count] = i;
                         background_count += 1;
                     }
                 }
-                if self.ppu.dispcnt.display_bg[2] {
+                if display_bg[2] {
                     let buffer = &mut background_buffers[2];
-                    self.ppu_render_affine_background(2, buffer);
+                    self.ppu_render_affine_background(2, buffer, start..end);
                     background_indices[background_count] = 2;
                     background_count += 1;
                 }
@@ -114,49 +121,76 @@ impl Gba {
             2 => {
                 // Mode 2: Two affine tilemaps (2, 3).
                 for i in 2..=3 {
-                    if self.ppu.dispcnt.display_bg[i] {
+                    if display_bg[i] {
                         let buffer = &mut background_buffers[i];
-                        self.ppu_render_affine_background(i, buffer);
+                        self.ppu_render_affine_background(i, buffer, start..end);
                         background_indices[background_count] = i;
                         background_count += 1;
                     }
                 }
             }
-            3 => {
+            3 if display_bg[2] => {
                 // Mode 3: Bitmap: 240x160, 16 bpp
-                if self.ppu.dispcnt.display_bg[2] {
-                    let buffer = &mut background_buffers[2];
-                    self.ppu_render_bitmap_3(buffer);
-                    background_indices[0] = 2;
-                    background_count = 1;
-                }
+                let buffer = &mut background_buffers[2];
+                self.ppu_render_bitmap_3(buffer, start..end);
+                background_indices[0] = 2;
+                background_count = 1;
             }
-            4 => {
+            4 if display_bg[2] => {
                 // Mode 4: Bitmap: 240x160, 8 bpp (palette) (allows page flipping)
-                if self.ppu.dispcnt.display_bg[2] {
-                    let buffer = &mut background_buffers[2];
-                    self.ppu_render_bitmap_4(buffer);
-                    background_indices[0] = 2;
-                    background_count = 1;
-                }
+                let buffer = &mut background_buffers[2];
+                self.ppu_render_bitmap_4(buffer, start..end);
+                background_indices[0] = 2;
+                background_count = 1;
             }
-            5 => {
+            5 if display_bg[2] => {
                 // Mode 5: Bitmap: 160x128 pixels, 16 bpp, allows page flipping
-                if self.ppu.dispcnt.display_bg[2] {
-                    let buffer = &mut background_buffers[2];
-                    self.ppu_render_bitmap_5(buffer);
-                    background_indices[0] = 2;
-                    background_count = 1;
-                }
+                let buffer = &mut background_buffers[2];
+                self.ppu_render_bitmap_5(buffer, start..end);
+                background_indices[0] = 2;
+                background_count = 1;
+            }
+            _ => {
+                // Modes 6 and 7 are prohibited, and bitmap modes may have BG2 hidden: no
+                // backgrounds are displayed.
             }
-            m @ _ => panic!("Unsupported video mode {}", m),
         }
 
         self.ppu_compose_scanline(
             &object_buffer,
             &background_buffers,
             &mut background_indices[..background_count],
+            start..end,
         );
+        if self.layer_buffers.is_some() {
+            self.ppu_store_layers(&object_buffer, &background_buffers, start, end);
+        }
+    }
+
+    /// Copy pixels `start..end` of each layer in the current scanline to the layer buffers.
+    fn ppu_store_layers(
+        &mut self,
+        object_buffer: &ObjectBuffer,
+        background_buffers: &[BackgroundBuffer; 4],
+        start: usize,
+        end: usize,
+    ) {
+        let offset = PIXELS_WIDTH * (self.ppu.vcount as usize);
+        let lut = &self.ppu.color_lut;
+        let as_argb = |color: Color15| {
+            if color.transparent() {
+                0
+            } else {
+                lut.get(color)
+            }
+        };
+        let layers = self.layer_buffers.as_mut().unwrap();
+        for x in start..end {
+            for (i, buffer) in background_buffers.iter().enumerate() {
+                layers.bg[i][offset + x] = as_argb(buffer[x]);
+            }
+            layers.obj[offset + x] = as_argb(object_buffer[x].color);
+        }
     }
 
     /// Get a palette index from a 4bpp tile.
@@ -164,9 +198,9 @@ impl Gba {
     /// `address`: the address of the tile in VRAM
     /// `x`: the x coordinate of the pixel in the tile
     /// `y`: the y coordinate of the pixel in the tile
-    fn tile_4bpp_get_index(&mut self, address: u32, x: u32, y: u32) -> u8 {
+    pub(super) fn tile_4bpp_get_index(&self, address: u32, x: u32, y: u32) -> u8 {
         let pixel = y * 8 + x;
-        let address = address + (pixel / 2);
+        let address = vram_offset(address + (pixel / 2));
         let data = self.ppu.vram[address as usize];
         if (pixel & 1) == 0 {
             data & 0xF
@@ -180,29 +214,44 @@ impl Gba {
     /// `address`: the address of the tile in VRAM
     /// `x`: the x coordinate of the pixel in the tile
     /// `y`: the y coordinate of the pixel in the tile
-    fn tile_8bpp_get_index(&mut self, address: u32, x: u32, y: u32) -> u8 {
+    pub(super) fn tile_8bpp_get_index(&self, address: u32, x: u32, y: u32) -> u8 {
         let pixel = y * 8 + x;
-        let address = address + pixel;
+        let address = vram_offset(address + pixel);
         self.ppu.vram[address as usize]
     }
 
+    /// Get a palette index from a tile in either color mode.
+    pub(super) fn tile_get_index(&self, color_mode: ColorMode, address: u32, x: u32, y: u32) -> u8 {
+        match color_mode {
+            ColorMode::Bpp4 => self.tile_4bpp_get_index(address, x, y),
+            ColorMode::Bpp8 => self.tile_8bpp_get_index(address, x, y),
+        }
+    }
+
     /// Get a color from a palette.
     ///
     /// `index`: the index of the color in the palette
     /// `bank`: the palette bank
     /// `table`: selects between sprite and bg palettes
-    fn palette_get_color(&mut self, index: u8, bank: u32, table: u32) -> Color15 {
+    pub(super) fn palette_get_color(&self, index: u8, bank: u32, table: u32) -> Color15 {
         if index == 0 {
             Color15::TRANSPARENT
         } else {
-            let address = table + (2 * index as u32) + (32 * bank);
-            let raw = self.ppu.palette.read_16(address);
-            Color15(raw & 0x7FFF)
+            self.palette_read_color(index, bank, table)
         }
     }
 
+    /// Read a color from a palette, like `palette_get_color`, but index 0 isn't
+    /// transparent.
+    pub(super) fn palette_read_color(&self, index: u8, bank: u32, table: u32) -> Color15 {
+        let address = (table + (2 * index as u32) + (32 * bank)) as usize;
+        let palette = &self.ppu.palette;
+        let raw = u16::from_le_bytes([palette[address], palette[address + 1]]);
+        Color15(raw & 0x7FFF)
+    }
+
     /// Do the affine background transformation for the given background
-    /// for the current scanline and given screen x position.
+    /// for the current scanline and given screen x position (applying mosaic).
     ///
     /// Returns the texture coordinate, or None if it's out of bounds.
     fn bg_affine_transform(
@@ -213,10 +262,16 @@ impl Gba {
         h: i32,
     ) -> Option<(u32, u32)> {
         let affine = self.ppu.bg_affine[index - 2];
-        let (dx, dy) = (affine.internal_dx, affine.internal_dy);
+        let (dx, dy) = if self.ppu.bgcnt[index].mosaic {
+            // Use the reference point from the first line of the mosaic block.
+            (affine.mosaic_dx, affine.mosaic_dy)
+        } else {
+            (affine.internal_dx, affine.internal_dy)
+        };
+        let screen_x = self.bg_mosaic_x(index, screen_x as u32) as i32;
 
-        let tx = (dx + (screen_x as i32) * (affine.pa as i32)) >> 8;
-        let ty = (dy + (screen_x as i32) * (affine.pc as i32)) >> 8;
+        let tx = (dx + screen_x * (affine.pa as i32)) >> 8;
+        let ty = (dy + screen_x * (affine.pc as i32)) >> 8;
         if tx < 0 || tx >= w || ty < 0 || ty >= h {
             if self.ppu.bgcnt[index].affine_wrap {
                 Some((tx.rem_euclid(w) as u32, ty.rem_euclid(h) as u32))
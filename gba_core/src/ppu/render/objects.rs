@@ -1,13 +1,24 @@
 use std::hint::unreachable_unchecked;
+use std::ops::Range;
 
 use super::super::constants::*;
 use super::{AffineMatrix, ObjectBuffer, PALETTE_TABLE_OBJ};
-use crate::ppu::{ColorMode, PIXELS_WIDTH};
+use crate::ppu::{obj_vram_start, ColorMode, PIXELS_WIDTH};
 use crate::{mem::Memory, ppu::color::Color15, Gba};
 use bit::BitIndex;
 
+/// Cycles available to render objects in each scanline (304 * 4 - 6).
+const OBJ_CYCLES_SCANLINE: usize = 1210;
+
+/// Cycles available to render objects when "H-Blank interval free" is set (objects
+/// can't be rendered during H-Blank): 240 * 4 - 6.
+const OBJ_CYCLES_SCANLINE_HBLANK_FREE: usize = CYCLES_HDRAW - 6;
+
+/// Extra cycles taken to render an affine object.
+const OBJ_CYCLES_AFFINE: usize = 10;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
-enum ObjectMode {
+pub enum ObjectMode {
     Regular = 0b00,
     Affine = 0b01,
     Hide = 0b10,
@@ -27,7 +38,16 @@ pub struct ObjectAttributes {
 }
 
 impl ObjectAttributes {
-    fn pos(&self) -> (i32, i32) {
+    /// Read the attributes of the object at the given OAM index.
+    pub fn read(oam: &[u8], index: usize) -> ObjectAttributes {
+        let offset = index * 8;
+        let attribute = |i: usize| u16::from_le_bytes([oam[offset + i], oam[offset + i + 1]]);
+        ObjectAttributes {
+            raw: [attribute(0), attribute(2), attribute(4)],
+        }
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
         let mut x = self.raw[1].bit_range(0..9) as i32;
         let mut y = self.raw[0].bit_range(0..8) as i32;
         if x >= (PIXELS_WIDTH as i32) {
@@ -39,7 +59,7 @@ impl ObjectAttributes {
         (x, y)
     }
 
-    fn object_mode(&self) -> ObjectMode {
+    pub fn object_mode(&self) -> ObjectMode {
         match self.raw[0].bit_range(8..10) {
             0b00 => ObjectMode::Regular,
             0b01 => ObjectMode::Affine,
@@ -59,7 +79,7 @@ impl ObjectAttributes {
         }
     }
 
-    fn mosaic(&self) -> bool {
+    pub fn mosaic(&self) -> bool {
         self.raw[0].bit(0xC)
     }
 
@@ -67,7 +87,7 @@ impl ObjectAttributes {
         self.gfx_mode() == GraphicsMode::Window
     }
 
-    fn color_mode(&self) -> ColorMode {
+    pub fn color_mode(&self) -> ColorMode {
         if self.raw[0].bit(0xD) {
             ColorMode::Bpp8
         } else {
@@ -76,21 +96,21 @@ impl ObjectAttributes {
     }
 
     /// OAM_AFF_ENTY this sprite uses, valid only if sprite is affine.
-    fn affine_index(&self) -> usize {
+    pub fn affine_index(&self) -> usize {
         self.raw[1].bit_range(9..14) as usize
     }
 
     /// Only valid if sprite isn't affine.
-    fn h_flip(&self) -> bool {
+    pub fn h_flip(&self) -> bool {
         self.raw[1].bit(0xC)
     }
 
     /// Only valid if sprite isn't affine.
-    fn v_flip(&self) -> bool {
+    pub fn v_flip(&self) -> bool {
         self.raw[1].bit(0xd)
     }
 
-    fn size(&self) -> (i32, i32) {
+    pub fn size(&self) -> (i32, i32) {
         let shape = self.raw[0].bit_range(14..16);
         let size = self.raw[1].bit_range(14..16);
         match (shape, size) {
@@ -110,8 +130,8 @@ impl ObjectAttributes {
         }
     }
 
-    /// Base tile index. Must be >= 512 in bitmap modes.
-    fn tile_index(&self) -> usize {
+    /// Base tile index. Tiles below 512 aren't displayed in bitmap modes.
+    pub fn tile_index(&self) -> usize {
         self.raw[2].bit_range(0..10) as usize
     }
 
@@ -119,7 +139,7 @@ impl ObjectAttributes {
         self.raw[2].bit_range(10..12)
     }
 
-    fn palette_bank(&self) -> u8 {
+    pub fn palette_bank(&self) -> u8 {
         self.raw[2].bit_range(12..16) as u8
     }
 }
@@ -127,13 +147,7 @@ impl ObjectAttributes {
 impl Gba {
     /// Get object attributes for a specific OAM index.
     fn get_attributes(&mut self, index: usize) -> ObjectAttributes {
-        let offset = (index as u32) * 8;
-        let raw = [
-            self.ppu.oam.read_16(offset + 0),
-            self.ppu.oam.read_16(offset + 2),
-            self.ppu.oam.read_16(offset + 4),
-        ];
-        ObjectAttributes { raw }
+        ObjectAttributes::read(&self.ppu.oam, index)
     }
 
     /// Get an affine matrix by index.
@@ -158,19 +172,29 @@ impl Gba {
         }
     }
 
-    /// Apply mosaic effect to a Y coordinate.
+    /// Get the Y coordinate within the object (or its bounding box) to render in the
+    /// current scanline. With mosaic, this is the first line of the current vertical
+    /// mosaic block (or the top of the object, if it starts within the block).
     #[inline]
-    fn mosaic_y(&self, attrs: &ObjectAttributes, y: i32) -> i32 {
+    fn mosaic_y(&self, attrs: &ObjectAttributes, obj_y: i32) -> i32 {
         if attrs.mosaic() {
-            let size = self.ppu.mosaic.obj_y as i32;
-            y - (y % size)
+            (self.ppu.mosaic.obj_line as i32 - obj_y).max(0)
         } else {
-            y
+            self.ppu.vcount as i32 - obj_y
         }
     }
 
-    /// Render a normal (non-affine) object.
-    fn render_normal_object(&mut self, attrs: ObjectAttributes, buffer: &mut ObjectBuffer) {
+    /// Render the part of a normal (non-affine) object within `pixels`.
+    ///
+    /// Each pixel of its width takes one cycle of the `budget` (even if it's offscreen, or
+    /// outside `pixels`).
+    fn render_normal_object(
+        &mut self,
+        attrs: ObjectAttributes,
+        budget: &mut usize,
+        buffer: &mut ObjectBuffer,
+        pixels: &Range<usize>,
+    ) {
         let screen_y = self.ppu.vcount as i32;
         let ((obj_x, obj_y), (obj_w, obj_h)) = (attrs.pos(), attrs.size());
         if screen_y < obj_y
@@ -180,8 +204,18 @@ impl Gba {
             // Sprite isn't in this scanline.
             return;
         }
-        let left = obj_x.max(0).min(PIXELS_WIDTH as i32);
-        let right = (obj_x + obj_w).max(0).min(PIXELS_WIDTH as i32);
+
+        // If we run out of cycles, only the left part of the sprite is drawn.
+        let drawn_w = obj_w.min(*budget as i32);
+        *budget = if drawn_w < obj_w {
+            0
+        } else {
+            *budget - obj_w as usize
+        };
+
+        let (start, end) = (pixels.start as i32, pixels.end as i32);
+        let left = obj_x.clamp(start, end);
+        let right = (obj_x + drawn_w).clamp(start, end);
 
         let color_mode = attrs.color_mode();
         let palette_bank = match color_mode {
@@ -190,8 +224,7 @@ impl Gba {
         };
 
         // Y relative to sprite top.
-        let mut sprite_y = screen_y - obj_y;
-        sprite_y = self.mosaic_y(&attrs, sprite_y);
+        let mut sprite_y = self.mosaic_y(&attrs, obj_y);
         if attrs.v_flip() {
             sprite_y = obj_h - sprite_y - 1
         }
@@ -213,6 +246,7 @@ impl Gba {
             (attrs.tile_index() as u32) + (tile_y * tile_stride)
         };
         let subtile_y = (sprite_y % 8) as u32; // Y within the current tile.
+        let obj_start = obj_vram_start(self.ppu.dispcnt.mode);
 
         for screen_x in left..right {
             // X relative to sprite left.
@@ -229,11 +263,15 @@ impl Gba {
                 ColorMode::Bpp8 => tile_start + (2 * tile_x),
             };
 
-            // TODO if using bitmap mode and tile_index < 512, don't draw it.
+            // In bitmap modes, tiles 0-511 overlap the bitmap and are transparent.
             let tile_address = 0x10000 + ((tile_index % 1024) * 32);
-            let index = match color_mode {
-                ColorMode::Bpp4 => self.tile_4bpp_get_index(tile_address, subtile_x, subtile_y),
-                ColorMode::Bpp8 => self.tile_8bpp_get_index(tile_address, subtile_x, subtile_y),
+            let index = if tile_address < obj_start {
+                0
+            } else {
+                match color_mode {
+                    ColorMode::Bpp4 => self.tile_4bpp_get_index(tile_address, subtile_x, subtile_y),
+                    ColorMode::Bpp8 => self.tile_8bpp_get_index(tile_address, subtile_x, subtile_y),
+                }
             };
             let color = self.palette_get_color(index, palette_bank, PALETTE_TABLE_OBJ);
             if color != Color15::TRANSPARENT {
@@ -242,8 +280,17 @@ impl Gba {
         }
     }
 
-    /// Render an affine object.
-    fn render_affine_object(&mut self, attrs: ObjectAttributes, buffer: &mut ObjectBuffer) {
+    /// Render the part of an affine object within `pixels`.
+    ///
+    /// It takes 10 cycles of the `budget`, plus two cycles for each pixel of the width of
+    /// its bounding box (even if it's offscreen, or outside `pixels`).
+    fn render_affine_object(
+        &mut self,
+        attrs: ObjectAttributes,
+        budget: &mut usize,
+        buffer: &mut ObjectBuffer,
+        pixels: &Range<usize>,
+    ) {
         let screen_y = self.ppu.vcount as i32;
         let ((obj_x, obj_y), (obj_w, obj_h)) = (attrs.pos(), attrs.size());
         let (box_w, box_h) = if attrs.object_mode() == ObjectMode::Affine {
@@ -258,6 +305,16 @@ impl Gba {
             // Sprite isn't in this scanline.
             return;
         }
+
+        // If we run out of cycles, only the left part of the bounding box is drawn.
+        let available = budget.saturating_sub(OBJ_CYCLES_AFFINE) / 2;
+        let drawn_w = box_w.min(available as i32);
+        *budget = if drawn_w < box_w {
+            0
+        } else {
+            *budget - OBJ_CYCLES_AFFINE - 2 * box_w as usize
+        };
+
         let matrix = self.get_affine_matrix(attrs.affine_index());
 
         // Tile mapping stuff.
@@ -278,13 +335,15 @@ impl Gba {
             ColorMode::Bpp8 => 0u32,
         };
 
+        let obj_start = obj_vram_start(self.ppu.dispcnt.mode);
+
         let half_width = box_w / 2;
         let half_height = box_h / 2;
 
-        let left = obj_x.max(0).min(PIXELS_WIDTH as i32);
-        let right = (obj_x + box_w).max(0).min(PIXELS_WIDTH as i32);
-        let box_y = screen_y - obj_y;
-        let iy = self.mosaic_y(&attrs, box_y) - half_height;
+        let (start, end) = (pixels.start as i32, pixels.end as i32);
+        let left = obj_x.clamp(start, end);
+        let right = (obj_x + drawn_w).clamp(start, end);
+        let iy = self.mosaic_y(&attrs, obj_y) - half_height;
 
         for screen_x in left..right {
             // Apply the transformation.
@@ -305,9 +364,18 @@ impl Gba {
                     ColorMode::Bpp8 => tile_start + (2 * tile_x),
                 };
                 let tile_address = 0x10000 + ((tile_index % 1024) * 32);
-                let index = match attrs.color_mode() {
-                    ColorMode::Bpp4 => self.tile_4bpp_get_index(tile_address, subtile_x, subtile_y),
-                    ColorMode::Bpp8 => self.tile_8bpp_get_index(tile_address, subtile_x, subtile_y),
+                let index = if tile_address < obj_start {
+                    // In bitmap modes, tiles 0-511 overlap the bitmap and are transparent.
+                    0
+                } else {
+                    match attrs.color_mode() {
+                        ColorMode::Bpp4 => {
+                            self.tile_4bpp_get_index(tile_address, subtile_x, subtile_y)
+                        }
+                        ColorMode::Bpp8 => {
+                            self.tile_8bpp_get_index(tile_address, subtile_x, subtile_y)
+                        }
+                    }
                 };
                 let color = self.palette_get_color(index, palette_bank, PALETTE_TABLE_OBJ);
                 if color != Color15::TRANSPARENT {
@@ -317,15 +385,28 @@ impl Gba {
         }
     }
 
-    /// Render the objects in the current scanline.
-    pub(super) fn ppu_render_objects(&mut self, buffer: &mut ObjectBuffer) {
+    /// Render pixels `pixels` of the objects in the current scanline.
+    ///
+    /// Objects are rendered in OAM order, until the scanline's cycle budget runs out (the
+    /// budget is for the whole scanline, whichever pixels are rendered).
+    pub(super) fn ppu_render_objects(&mut self, buffer: &mut ObjectBuffer, pixels: Range<usize>) {
+        let mut budget = if self.ppu.dispcnt.h_blank_interval_free {
+            OBJ_CYCLES_SCANLINE_HBLANK_FREE
+        } else {
+            OBJ_CYCLES_SCANLINE
+        };
         for i in 0..128 {
+            if budget == 0 {
+                break;
+            }
             let attrs = self.get_attributes(i);
             match attrs.object_mode() {
-                ObjectMode::Regular => self.render_normal_object(attrs, buffer),
+                ObjectMode::Regular => {
+                    self.render_normal_object(attrs, &mut budget, buffer, &pixels)
+                }
                 ObjectMode::Hide => {}
                 ObjectMode::Affine | ObjectMode::AffineDouble => {
-                    self.render_affine_object(attrs, buffer)
+                    self.render_affine_object(attrs, &mut budget, buffer, &pixels)
                 }
             }
         }
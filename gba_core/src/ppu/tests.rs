@@ -0,0 +1,417 @@
+//! Mosaic tests: scenes modelled on the TONC mosaic demo (a mosaic background and
+//! sprites, at a range of mosaic sizes), checked against reference framebuffers in
+//! `testdata/mosaic`, and against the block structure mosaic should produce.
+//!
+//! The reference framebuffers are snapshots of this emulator's output, not captures from
+//! hardware or another emulator: they catch regressions, and were only checked by eye.
+//! The block structure checks don't depend on them.
+//!
+//! There's also a check that sub-scanline rendering shows mid-line palette writes.
+
+use std::ops::Range;
+
+use super::{
+    CYCLES_FRAME, CYCLES_HDRAW, CYCLES_PIXEL, CYCLES_SCANLINE, PIXELS_HEIGHT, PIXELS_WIDTH,
+};
+use crate::bus::MemoryAccessType;
+use crate::io::{
+    REG_BG2CNT, REG_BG2PA, REG_BG2PB, REG_BG2PC, REG_BG2PD, REG_BG2X_H, REG_BG2X_L, REG_BG2Y_H,
+    REG_BG2Y_L, REG_DISPCNT, REG_MOSAIC,
+};
+use crate::test_images::assert_reference_image;
+use crate::{Gba, Memory};
+
+/// DISPCNT: display BG2.
+const DISPLAY_BG2: u16 = 1 << 10;
+/// DISPCNT: display OBJs, with 1-D tile mapping.
+const DISPLAY_OBJ: u16 = (1 << 12) | (1 << 6);
+
+/// BGxCNT: mosaic enabled.
+const BG_MOSAIC: u16 = 1 << 6;
+
+/// Backdrop color (dark gray).
+const BACKDROP: u16 = 0x2108;
+
+/// Create a GBA with an empty BIOS, past the BIOS, with the CPU spinning in ROM.
+fn test_gba() -> Gba {
+    let mut rom = vec![0; 0x200];
+    // b . (infinite loop)
+    rom[..4].copy_from_slice(&0xEAFF_FFFEu32.to_le_bytes());
+    let mut gba = Gba::new_for_test(&rom);
+    gba.ppu.palette.write_16(0, BACKDROP);
+    gba
+}
+
+/// Value of the test pattern at (x, y). It changes every 4 pixels horizontally, and on
+/// every line, so any change in the line a row is taken from shows up.
+fn pattern(x: u32, y: u32) -> u8 {
+    (1 + ((x / 4) * 7 + y * 5) % 254) as u8
+}
+
+/// Color for a pattern value. Adjacent lines of the pattern always differ in red.
+fn pattern_color(value: u8) -> u16 {
+    let i = value as u16;
+    (i % 32) | (((i / 4) % 32) << 5) | ((31 - (i / 2) % 32) << 10)
+}
+
+/// Fill the BG palette with the pattern colors.
+fn load_bg_palette(gba: &mut Gba) {
+    for i in 1..=255 {
+        gba.ppu.palette.write_16(2 * i, pattern_color(i as u8));
+    }
+}
+
+/// Set the MOSAIC register from block sizes (in pixels).
+fn set_mosaic(gba: &mut Gba, (bg_w, bg_h): (usize, usize), (obj_w, obj_h): (usize, usize)) {
+    let value = (bg_w - 1) | (bg_h - 1) << 4 | (obj_w - 1) << 8 | (obj_h - 1) << 12;
+    gba.io_write_16(REG_MOSAIC, value as u16);
+}
+
+/// Set the BG2 affine matrix and reference point (in whole pixels).
+fn set_bg2_affine(gba: &mut Gba, [pa, pb, pc, pd]: [i16; 4], (x, y): (i32, i32)) {
+    gba.io_write_16(REG_BG2PA, pa as u16);
+    gba.io_write_16(REG_BG2PB, pb as u16);
+    gba.io_write_16(REG_BG2PC, pc as u16);
+    gba.io_write_16(REG_BG2PD, pd as u16);
+    let (x, y) = ((x << 8) as u32, (y << 8) as u32);
+    gba.io_write_16(REG_BG2X_L, x as u16);
+    gba.io_write_16(REG_BG2X_H, (x >> 16) as u16);
+    gba.io_write_16(REG_BG2Y_L, y as u16);
+    gba.io_write_16(REG_BG2Y_H, (y >> 16) as u16);
+}
+
+/// Rotation by about 25 degrees, scaled by about 1.1x.
+const ROTATE: [i16; 4] = [200, -96, 96, 200];
+
+/// Emulate two frames, so the whole framebuffer is from a frame with the current setup.
+fn render(gba: &mut Gba) {
+    gba.emulate_frame(true);
+    gba.emulate_frame(true);
+}
+
+/// Check that every mosaic block within a region of the framebuffer has a single color.
+///
+/// Blocks are `w` x `h` pixels, aligned horizontally to the left of the region (the left
+/// of the screen for backgrounds, or of the object) and vertically to the top of the
+/// screen. Blocks are clipped to the region.
+fn assert_mosaic_blocks(gba: &Gba, x: Range<usize>, y: Range<usize>, (w, h): (usize, usize)) {
+    let frame = gba.framebuffer();
+    for line in y.clone() {
+        let top = (line - line % h).max(y.start);
+        for px in x.clone() {
+            let left = x.start + (px - x.start) / w * w;
+            assert_eq!(
+                frame[line * PIXELS_WIDTH + px],
+                frame[top * PIXELS_WIDTH + left],
+                "pixel ({}, {}) differs from its {}x{} mosaic block at ({}, {})",
+                px,
+                line,
+                w,
+                h,
+                left,
+                top
+            );
+        }
+    }
+}
+
+fn assert_frame(gba: &Gba, name: &str) {
+    let name = format!("mosaic/{}", name);
+    assert_reference_image(&name, gba.framebuffer(), PIXELS_WIDTH, PIXELS_HEIGHT);
+}
+
+/// Load a 128x128 affine tilemap (screen base block 8) showing the pattern.
+fn load_affine_tilemap(gba: &mut Gba) {
+    for tile in 0..256u32 {
+        let (tile_x, tile_y) = (tile % 16, tile / 16);
+        gba.ppu.vram[0x4000 + tile as usize] = tile as u8;
+        for i in 0..64 {
+            let (x, y) = (tile_x * 8 + i % 8, tile_y * 8 + i / 8);
+            gba.ppu.vram[(tile * 64 + i) as usize] = pattern(x, y);
+        }
+    }
+}
+
+#[test]
+fn affine_background_mosaic() {
+    for size in [(2, 2), (5, 3), (16, 16)] {
+        let mut gba = test_gba();
+        load_bg_palette(&mut gba);
+        load_affine_tilemap(&mut gba);
+        // Mode 1: BG2 is affine. Wrapping, so it covers the whole screen.
+        gba.io_write_16(REG_DISPCNT, 1 | DISPLAY_BG2);
+        gba.io_write_16(REG_BG2CNT, BG_MOSAIC | (8 << 8) | (1 << 13));
+        set_bg2_affine(&mut gba, ROTATE, (20, -30));
+        set_mosaic(&mut gba, size, (1, 1));
+        render(&mut gba);
+
+        assert_mosaic_blocks(&gba, 0..PIXELS_WIDTH, 0..PIXELS_HEIGHT, size);
+        assert_frame(&gba, &format!("affine_bg_{}x{}", size.0, size.1));
+    }
+}
+
+/// Check a bitmap mode without rotation against the pattern: each pixel should show the
+/// top-left pixel of its mosaic block (if it's inside the bitmap).
+fn assert_bitmap_pattern(gba: &Gba, (width, height): (usize, usize), (w, h): (usize, usize)) {
+    let frame = gba.framebuffer();
+    for y in 0..PIXELS_HEIGHT {
+        for x in 0..PIXELS_WIDTH {
+            let (src_x, src_y) = (x - x % w, y - y % h);
+            let color = if src_x < width && src_y < height {
+                pattern_color(pattern(src_x as u32, src_y as u32))
+            } else {
+                BACKDROP
+            };
+            let expected = gba.ppu.color_lut.get(super::Color15(color));
+            assert_eq!(
+                frame[y * PIXELS_WIDTH + x],
+                expected,
+                "pixel ({}, {})",
+                x,
+                y
+            );
+        }
+    }
+}
+
+#[test]
+fn bitmap_mode_3_mosaic() {
+    for size in [(4, 4), (3, 7)] {
+        let mut gba = test_gba();
+        for y in 0..160 {
+            for x in 0..240 {
+                let color = pattern_color(pattern(x, y));
+                gba.ppu.vram.write_16(2 * (y * 240 + x), color);
+            }
+        }
+        gba.io_write_16(REG_DISPCNT, 3 | DISPLAY_BG2);
+        gba.io_write_16(REG_BG2CNT, BG_MOSAIC);
+        set_mosaic(&mut gba, size, (1, 1));
+        render(&mut gba);
+
+        assert_bitmap_pattern(&gba, (240, 160), size);
+        assert_frame(&gba, &format!("mode_3_{}x{}", size.0, size.1));
+    }
+}
+
+#[test]
+fn bitmap_mode_4_mosaic() {
+    for size in [(3, 6), (8, 8)] {
+        let mut gba = test_gba();
+        load_bg_palette(&mut gba);
+        for y in 0..160 {
+            for x in 0..240 {
+                gba.ppu.vram[(y * 240 + x) as usize] = pattern(x, y);
+            }
+        }
+        // Rotated, so the bitmap doesn't cover the whole screen.
+        gba.io_write_16(REG_DISPCNT, 4 | DISPLAY_BG2);
+        gba.io_write_16(REG_BG2CNT, BG_MOSAIC);
+        set_bg2_affine(&mut gba, ROTATE, (40, -20));
+        set_mosaic(&mut gba, size, (1, 1));
+        render(&mut gba);
+
+        assert_mosaic_blocks(&gba, 0..PIXELS_WIDTH, 0..PIXELS_HEIGHT, size);
+        assert_frame(&gba, &format!("mode_4_{}x{}", size.0, size.1));
+    }
+}
+
+#[test]
+fn bitmap_mode_5_mosaic() {
+    // The bitmap is 160x128: blocks at its right and bottom edges overhang it.
+    for size in [(7, 2), (6, 5)] {
+        let mut gba = test_gba();
+        for y in 0..128 {
+            for x in 0..160 {
+                let color = pattern_color(pattern(x, y));
+                gba.ppu.vram.write_16(2 * (y * 160 + x), color);
+            }
+        }
+        gba.io_write_16(REG_DISPCNT, 5 | DISPLAY_BG2);
+        gba.io_write_16(REG_BG2CNT, BG_MOSAIC);
+        set_mosaic(&mut gba, size, (1, 1));
+        render(&mut gba);
+
+        assert_bitmap_pattern(&gba, (160, 128), size);
+        assert_frame(&gba, &format!("mode_5_{}x{}", size.0, size.1));
+    }
+}
+
+/// Tile index of the 32x32 8bpp test sprite (in the part of OBJ VRAM that's also usable
+/// in bitmap modes).
+const SPRITE_TILE: u16 = 512;
+
+/// Load the test sprite (a disc with the pattern) and the OBJ palette, and hide all
+/// objects.
+fn load_sprite(gba: &mut Gba) {
+    for i in 1..=255 {
+        gba.ppu
+            .palette
+            .write_16(0x200 + 2 * i, pattern_color(i as u8));
+    }
+    for y in 0..32u32 {
+        for x in 0..32u32 {
+            let (dx, dy) = (x as i32 - 16, y as i32 - 16);
+            let value = if dx * dx + dy * dy < 16 * 16 {
+                pattern(x, y)
+            } else {
+                0
+            };
+            let tile = (y / 8) * 4 + x / 8;
+            let offset = 0x14000 + tile * 64 + (y % 8) * 8 + x % 8;
+            gba.ppu.vram[offset as usize] = value;
+        }
+    }
+    for i in 0..128 {
+        // Hidden.
+        gba.ppu.oam.write_16(i * 8, 0x200);
+    }
+}
+
+/// Object modes (OAM attribute 0, bits 8-9).
+const OBJ_REGULAR: u16 = 0b00;
+const OBJ_AFFINE: u16 = 0b01;
+const OBJ_AFFINE_DOUBLE: u16 = 0b11;
+
+/// Place the 32x32 mosaic test sprite as object `index`, with affine matrix `matrix`
+/// (if it's affine).
+fn set_sprite(gba: &mut Gba, index: u32, mode: u16, (x, y): (u16, u16), matrix: u16) {
+    let attr0 = y | mode << 8 | 1 << 12 | 1 << 13;
+    let attr1 = x | matrix << 9 | 0b10 << 14;
+    gba.ppu.oam.write_16(index * 8, attr0);
+    gba.ppu.oam.write_16(index * 8 + 2, attr1);
+    gba.ppu.oam.write_16(index * 8 + 4, SPRITE_TILE);
+}
+
+fn set_obj_affine(gba: &mut Gba, index: u32, matrix: [i16; 4]) {
+    for (i, value) in matrix.into_iter().enumerate() {
+        gba.ppu
+            .oam
+            .write_16(index * 32 + 6 + 8 * i as u32, value as u16);
+    }
+}
+
+#[test]
+fn affine_object_mosaic() {
+    // (mode, position, bounding box size)
+    let objects = [
+        (OBJ_AFFINE, (16, 32), 32),
+        (OBJ_AFFINE_DOUBLE, (64, 32), 64),
+        (OBJ_REGULAR, (144, 32), 32),
+        // Starts partway through a vertical mosaic block.
+        (OBJ_AFFINE_DOUBLE, (176, 94), 64),
+    ];
+    for size in [(2, 2), (4, 7), (9, 9)] {
+        let mut gba = test_gba();
+        load_sprite(&mut gba);
+        // Rotated by 45 degrees.
+        set_obj_affine(&mut gba, 0, [181, -181, 181, 181]);
+        // Rotated by 30 degrees, and scaled by 0.8x.
+        set_obj_affine(&mut gba, 1, [277, -160, 160, 277]);
+        for (i, &(mode, pos, _)) in objects.iter().enumerate() {
+            set_sprite(&mut gba, i as u32, mode, pos, i as u16 % 2);
+        }
+        gba.io_write_16(REG_DISPCNT, DISPLAY_OBJ);
+        set_mosaic(&mut gba, (1, 1), size);
+        render(&mut gba);
+
+        for &(_, (x, y), box_size) in objects.iter() {
+            let (x, y) = (x as usize, y as usize);
+            assert_mosaic_blocks(&gba, x..x + box_size, y..y + box_size, size);
+        }
+        assert_frame(&gba, &format!("objects_{}x{}", size.0, size.1));
+    }
+}
+
+#[test]
+fn vertical_mosaic_counter() {
+    // Mode 3 with a mosaic sprite on the right. The mosaic height changes in line 5's
+    // H-Blank: blocks that have already started keep going until the counter reaches the
+    // new size.
+    let mut gba = test_gba();
+    load_sprite(&mut gba);
+    // The bitmap is black behind the sprite.
+    for y in 0..160 {
+        for x in 0..192 {
+            let color = pattern_color(pattern(x, y));
+            gba.ppu.vram.write_16(2 * (y * 240 + x), color);
+        }
+    }
+    set_sprite(&mut gba, 0, OBJ_REGULAR, (200, 0), 0);
+    gba.io_write_16(REG_DISPCNT, 3 | DISPLAY_BG2 | DISPLAY_OBJ);
+    gba.io_write_16(REG_BG2CNT, BG_MOSAIC);
+    set_mosaic(&mut gba, (1, 4), (1, 4));
+
+    gba.emulate_frame(true);
+    let elapsed = 5 * CYCLES_SCANLINE + CYCLES_HDRAW + 64;
+    gba.emulate_cycles(elapsed);
+    assert_eq!(gba.ppu.vcount, 5);
+    assert!(gba.ppu.dispstat.hblank);
+    set_mosaic(&mut gba, (1, 8), (1, 2));
+    gba.emulate_cycles(CYCLES_FRAME - elapsed);
+
+    // BG blocks: 0-3, 4-11 (the block started before the change), 12-19, ...
+    let bg_source = |line: usize| if line < 4 { 0 } else { line - (line - 4) % 8 };
+    // OBJ blocks: 0-3, 4-5 (the counter was already at 1 when the size changed), 6-7, ...
+    let obj_source = |line: usize| if line < 4 { 0 } else { line - line % 2 };
+    let frame = gba.framebuffer();
+    let row = |line: usize, x: Range<usize>| &frame[line * PIXELS_WIDTH..][x];
+    for line in 0..PIXELS_HEIGHT {
+        assert_eq!(
+            row(line, 0..192),
+            row(bg_source(line), 0..192),
+            "BG line {}",
+            line
+        );
+        if line < 32 {
+            assert_eq!(
+                row(line, 200..232),
+                row(obj_source(line), 200..232),
+                "OBJ line {}",
+                line
+            );
+        }
+    }
+    for start in [4, 12, 20] {
+        assert_ne!(row(start, 0..192), row(start - 1, 0..192));
+    }
+    assert_ne!(row(6, 200..232), row(5, 200..232));
+
+    assert_frame(&gba, "vertical_counter");
+}
+
+#[test]
+fn mid_line_palette_write() {
+    const RED: u16 = 0x001F;
+    const BLUE: u16 = 0x7C00;
+    for sub_scanline in [false, true] {
+        let mut gba = test_gba();
+        gba.ppu.sub_scanline = sub_scanline;
+        // Mode 4, with every pixel using palette entry 1.
+        gba.ppu.vram[..240 * 160].fill(1);
+        gba.ppu.palette.write_16(2, RED);
+        gba.io_write_16(REG_DISPCNT, 4 | DISPLAY_BG2);
+        render(&mut gba);
+
+        // Change the color partway through HDraw of line 10.
+        gba.emulate_cycles(10 * CYCLES_SCANLINE + 100 * CYCLES_PIXEL);
+        gba.cpu_store16(0x0500_0002, BLUE, MemoryAccessType::NonSequential);
+        let x = (gba.scheduler.timestamp() - gba.ppu.line_start) / CYCLES_PIXEL;
+        assert_eq!(gba.ppu.vcount, 10);
+        assert!((100..104).contains(&x), "write at pixel {}", x);
+        gba.emulate_cycles(CYCLES_FRAME - 10 * CYCLES_SCANLINE - 100 * CYCLES_PIXEL);
+
+        let frame = gba.framebuffer();
+        let line = |y: usize| &frame[y * PIXELS_WIDTH..(y + 1) * PIXELS_WIDTH];
+        let (red, blue) = (line(9)[0], line(11)[0]);
+        assert_ne!(red, blue);
+        for (px, &color) in line(10).iter().enumerate() {
+            let expected = if sub_scanline && px >= x { blue } else { red };
+            assert_eq!(
+                color, expected,
+                "pixel {} (sub-scanline: {})",
+                px, sub_scanline
+            );
+        }
+    }
+}
@@ -10,8 +10,18 @@ pub struct Scheduler {
 
     /// Priority queue of events.
     queue: BinaryHeap<ScheduledEvent>,
+
+    /// Deadline of the next event (`usize::MAX` if there are none), kept up to date so
+    /// native code can read it directly.
+    next_deadline: usize,
 }
 
+/// Offsets of the current time and the next deadline in [`Scheduler`], for native code.
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub(crate) const SCHEDULER_TIME: usize = std::mem::offset_of!(Scheduler, time);
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub(crate) const SCHEDULER_NEXT_DEADLINE: usize = std::mem::offset_of!(Scheduler, next_deadline);
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
     /// Stop running the emulator.
@@ -31,6 +41,9 @@ pub enum Event {
 
     /// APU PSG channel sequencer step.
     AudioSequencerTick,
+
+    /// Cartridge Flash finished a program or erase operation.
+    FlashOperationComplete,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -53,6 +66,7 @@ impl Scheduler {
         Scheduler {
             queue: BinaryHeap::new(),
             time: 0,
+            next_deadline: usize::MAX,
         }
     }
 
@@ -72,6 +86,22 @@ impl Scheduler {
         self.time = self.time.max(next_deadline);
     }
 
+    /// Advance the internal timestamp by `delta` cycles while the system is frozen (e.g. Stop mode).
+    ///
+    /// Every event except [`Event::StopRunning`] is postponed by the same amount, so nothing
+    /// else fires. Takes O(N) time.
+    pub fn advance_frozen(&mut self, delta: usize) {
+        let mut queue = std::mem::take(&mut self.queue).into_vec();
+        for scheduled in queue.iter_mut() {
+            if scheduled.event != Event::StopRunning {
+                scheduled.deadline += delta;
+            }
+        }
+        self.queue = queue.into();
+        self.queue_changed();
+        self.time += delta;
+    }
+
     /// Get the timestamp of the next event's deadline (or None if there are no events).
     pub fn peek_deadline(&self) -> Option<usize> {
         self.queue.peek().map(|x| x.deadline)
@@ -82,6 +112,7 @@ impl Scheduler {
         if let Some(next_event) = self.queue.peek() {
             if next_event.deadline <= self.time {
                 let event = unsafe { self.queue.pop().unwrap_unchecked() };
+                self.queue_changed();
                 let lateness = self.time - event.deadline;
                 return Some((event.event, lateness));
             }
@@ -97,6 +128,7 @@ impl Scheduler {
         let mut queue = std::mem::take(&mut self.queue).into_vec();
         queue.retain(|f| f.event != event);
         self.queue = queue.into();
+        self.queue_changed();
     }
 
     /// Schedule an event at a moment in time (now + given cycles).
@@ -106,6 +138,12 @@ impl Scheduler {
             deadline: self.time + when,
         };
         self.queue.push(scheduled);
+        self.queue_changed();
+    }
+
+    /// Update the next deadline after the queue changed.
+    fn queue_changed(&mut self) {
+        self.next_deadline = self.peek_deadline().unwrap_or(usize::MAX);
     }
 }
 
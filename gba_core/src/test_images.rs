@@ -0,0 +1,96 @@
+//! Reference images for tests, stored as PNGs in `gba_core/testdata`.
+//!
+//! Run the tests with `UPDATE_REFERENCE_IMAGES=1` to (re)generate the references from
+//! the current output, after checking that it's correct.
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+fn reference_path(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "testdata", name]
+        .iter()
+        .collect()
+}
+
+/// Write an image (ARGB pixels, ignoring alpha) to a PNG file.
+pub(crate) fn write_png(path: &Path, pixels: &[u32], width: usize, height: usize) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = pixels
+        .iter()
+        .flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8])
+        .collect();
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&data).unwrap();
+}
+
+/// Read an 8-bit RGB or RGBA PNG file as ARGB pixels (with alpha set to 0xFF), and its
+/// size.
+pub(crate) fn read_png(path: &Path) -> (Vec<u32>, usize, usize) {
+    let file = File::open(path).unwrap_or_else(|e| {
+        panic!(
+            "can't open reference image {} ({}); run with UPDATE_REFERENCE_IMAGES=1 to create it",
+            path.display(),
+            e
+        )
+    });
+    let mut reader = png::Decoder::new(file).read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).unwrap();
+    assert_eq!(info.bit_depth, png::BitDepth::Eight);
+    let channels = match info.color_type {
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        other => panic!("unsupported PNG color type {:?}", other),
+    };
+    let pixels = data[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|c| 0xFF00_0000 | (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32)
+        .collect();
+    (pixels, info.width as usize, info.height as usize)
+}
+
+/// Check that an image (ARGB pixels, row major) matches the reference image
+/// `testdata/<name>.png` exactly.
+///
+/// On a mismatch, the actual image is written next to the reference as
+/// `<name>.actual.png`, to compare them.
+pub(crate) fn assert_reference_image(name: &str, pixels: &[u32], width: usize, height: usize) {
+    assert_eq!(pixels.len(), width * height);
+    let path = reference_path(&format!("{}.png", name));
+    if std::env::var_os("UPDATE_REFERENCE_IMAGES").is_some() {
+        write_png(&path, pixels, width, height);
+        return;
+    }
+
+    let (expected, expected_width, expected_height) = read_png(&path);
+    assert_eq!(
+        (width, height),
+        (expected_width, expected_height),
+        "size doesn't match reference image {}",
+        name
+    );
+    let mismatches: Vec<usize> = (0..pixels.len())
+        .filter(|&i| (pixels[i] | 0xFF00_0000) != expected[i])
+        .collect();
+    if let Some(&first) = mismatches.first() {
+        let actual_path = reference_path(&format!("{}.actual.png", name));
+        write_png(&actual_path, pixels, width, height);
+        panic!(
+            "{} pixels don't match reference image {} (first at ({}, {}): expected {:06X}, got {:06X}); see {}",
+            mismatches.len(),
+            name,
+            first % width,
+            first / width,
+            expected[first] & 0xFF_FFFF,
+            pixels[first] & 0xFF_FFFF,
+            actual_path.display()
+        );
+    }
+}
@@ -146,6 +146,11 @@ impl Gba {
         }
     }
 
+    /// Skip the time since the last update without ticking (e.g. the timers were stopped).
+    pub(crate) fn timer_skip_frozen(&mut self) {
+        self.timer.last_update = self.scheduler.timestamp();
+    }
+
     /// Calculate how many cycles until the next time we may have to fire an IRQ
     /// (or have the APU update a DMA channel).
     fn calculate_next_irq(&mut self) -> Option<usize> {
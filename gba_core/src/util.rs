@@ -1,57 +1,226 @@
 use std::{
+    cell::RefCell,
     fs,
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, Write},
+    rc::Rc,
 };
 
 use crate::BackupFile;
 
+/// Number of old versions of the backup file to keep (`.bak`, `.bak.1`, ...).
+const BACKUP_GENERATIONS: usize = 3;
+
 /// Create a filesystem-backed backup file.
 ///
-/// # Panics
+/// The file is read into memory up front. Writes replace the whole file atomically
+/// (by writing a temporary file and renaming it over the old one), so a crash or
+/// full disk can't leave a half-written save. The first write of a session also
+/// rotates the previous file into `<path>.bak`, keeping a few older generations.
 ///
-/// This will panic if the file can't be created (or opened if it already exists).
-pub fn make_backup_file(path: String) -> Box<dyn BackupFile> {
+/// The file isn't created until the first write. Returns an error if it exists but
+/// can't be read.
+pub fn make_backup_file(path: String) -> io::Result<Box<dyn BackupFile>> {
     /// File-backed BackupFile.
     struct DiskBackup {
         path: String,
-        file: Option<fs::File>,
+        contents: Vec<u8>,
+        /// Whether we've made backups of the original file yet.
+        rotated: bool,
+    }
+
+    impl DiskBackup {
+        /// Path of the given backup generation.
+        fn backup_path(&self, generation: usize) -> String {
+            match generation {
+                0 => format!("{}.bak", self.path),
+                n => format!("{}.bak.{}", self.path, n),
+            }
+        }
+
+        /// Shift the old backups down a generation, and copy the current file to `.bak`.
+        fn rotate_backups(&self) -> io::Result<()> {
+            if fs::metadata(&self.path).is_err() {
+                // Nothing to back up.
+                return Ok(());
+            }
+            for generation in (1..BACKUP_GENERATIONS).rev() {
+                let from = self.backup_path(generation - 1);
+                if fs::metadata(&from).is_ok() {
+                    fs::rename(&from, self.backup_path(generation))?;
+                }
+            }
+            fs::copy(&self.path, self.backup_path(0))?;
+            Ok(())
+        }
+
+        /// Atomically replace the file with the current contents.
+        fn persist(&self) -> io::Result<()> {
+            let temp_path = format!("{}.tmp", self.path);
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(&self.contents)?;
+            file.sync_all()?;
+            drop(file);
+            fs::rename(&temp_path, &self.path)
+        }
     }
 
     impl BackupFile for DiskBackup {
         fn read(&mut self, offset: usize, buffer: &mut [u8]) {
-            if buffer.len() > 0 {
-                let file = self
-                    .file
-                    .as_mut()
-                    .expect("Failed to read from non-existant file");
-                file.seek(SeekFrom::Start(offset as u64)).unwrap();
-                file.read_exact(buffer).unwrap();
-            }
+            read_from_slice(&self.contents, offset, buffer);
         }
 
-        fn write(&mut self, offset: usize, data: &[u8]) {
-            let file = self.file.get_or_insert_with(|| {
-                // Lazily create the file.
-                fs::File::options()
-                    .create(true)
-                    .read(true)
-                    .write(true)
-                    .open(&self.path)
-                    .expect("Failed to create backup file")
-            });
-
-            file.seek(SeekFrom::Start(offset as u64)).unwrap();
-            file.write_all(data).unwrap();
+        fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+            write_to_vec(&mut self.contents, offset, data);
+
+            if !self.rotated {
+                self.rotate_backups()?;
+                self.rotated = true;
+            }
+            self.persist()
         }
 
         fn size(&self) -> usize {
-            self.file
-                .as_ref()
-                .map_or(0, |mut file| file.seek(SeekFrom::End(0)).unwrap() as usize)
+            self.contents.len()
         }
     }
 
-    // Don't create the file immediately, and silently fail if it can't be opened.
-    let file = fs::File::options().read(true).write(true).open(&path).ok();
-    Box::new(DiskBackup { path, file })
+    // Don't create the file immediately, and treat a missing file as empty.
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    Ok(Box::new(DiskBackup {
+        path,
+        contents,
+        rotated: false,
+    }))
+}
+
+/// Copy from `data` at `offset` into `buffer`, filling anything past the end with 0xFF.
+fn read_from_slice(data: &[u8], offset: usize, buffer: &mut [u8]) {
+    let start = offset.min(data.len());
+    let end = (offset + buffer.len()).min(data.len());
+    let available = end - start;
+    buffer[..available].copy_from_slice(&data[start..end]);
+    buffer[available..].fill(0xFF);
+}
+
+/// Copy `data` into `storage` at `offset`, growing it if needed.
+fn write_to_vec(storage: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if storage.len() < offset + data.len() {
+        storage.resize(offset + data.len(), 0xFF);
+    }
+    storage[offset..(offset + data.len())].copy_from_slice(data);
+}
+
+/// In-memory BackupFile.
+///
+/// Clones share the same storage, so keep a clone around to get (or replace)
+/// the data after passing one to the builder.
+#[derive(Clone, Default)]
+pub struct MemoryBackup {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryBackup {
+    /// Create an in-memory backup with the given initial contents (empty for a new save).
+    pub fn new(data: Vec<u8>) -> MemoryBackup {
+        MemoryBackup {
+            data: Rc::new(RefCell::new(data)),
+        }
+    }
+
+    /// Get a copy of the current contents.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.borrow().clone()
+    }
+
+    /// Replace the contents.
+    ///
+    /// This is only seen by the emulator when the backup is loaded (i.e. when it's built).
+    pub fn set_bytes(&self, data: Vec<u8>) {
+        *self.data.borrow_mut() = data;
+    }
+}
+
+impl BackupFile for MemoryBackup {
+    fn size(&self) -> usize {
+        self.data.borrow().len()
+    }
+
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) {
+        read_from_slice(&self.data.borrow(), offset, buffer);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        write_to_vec(&mut self.data.borrow_mut(), offset, data);
+        Ok(())
+    }
+}
+
+/// Read-only BackupFile: loads the given data, and silently discards writes.
+///
+/// Useful for tests, where runs shouldn't modify the starting save.
+pub struct ReadOnlyBackup {
+    data: Vec<u8>,
+}
+
+impl ReadOnlyBackup {
+    /// Create a read-only backup with the given contents.
+    pub fn new(data: Vec<u8>) -> ReadOnlyBackup {
+        ReadOnlyBackup { data }
+    }
+}
+
+impl BackupFile for ReadOnlyBackup {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) {
+        read_from_slice(&self.data, offset, buffer);
+    }
+
+    fn write(&mut self, _offset: usize, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// BackupFile that forwards to closures provided by the host.
+pub struct CallbackBackup<S, R, W> {
+    size: S,
+    read: R,
+    write: W,
+}
+
+impl<S, R, W> CallbackBackup<S, R, W>
+where
+    S: Fn() -> usize,
+    R: FnMut(usize, &mut [u8]),
+    W: FnMut(usize, &[u8]) -> io::Result<()>,
+{
+    /// Create a backup from closures implementing each of the [`BackupFile`] methods.
+    pub fn new(size: S, read: R, write: W) -> CallbackBackup<S, R, W> {
+        CallbackBackup { size, read, write }
+    }
+}
+
+impl<S, R, W> BackupFile for CallbackBackup<S, R, W>
+where
+    S: Fn() -> usize,
+    R: FnMut(usize, &mut [u8]),
+    W: FnMut(usize, &[u8]) -> io::Result<()>,
+{
+    fn size(&self) -> usize {
+        (self.size)()
+    }
+
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) {
+        (self.read)(offset, buffer)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        (self.write)(offset, data)
+    }
 }
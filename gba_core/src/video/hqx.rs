@@ -0,0 +1,167 @@
+//! hq2x, by Maxim Stepin: a pattern-based scaler for pixel art (2x).
+//!
+//! Each pixel is compared with its 8 neighbours (in YUV, with thresholds), giving an 8-bit
+//! pattern of which ones differ from it. Each of the 4 output pixels is a blend of the
+//! center with nearby neighbours, chosen by looking the pattern up in a rule table. The
+//! table is for the top left output pixel: the others rotate the neighbourhood first. Some
+//! rules also compare two neighbours with each other.
+//!
+//! The table is a compact form of the original's 256-case switch statement (like the one in
+//! bsnes), with the same dihedral symmetry. It was put together without the upstream source
+//! at hand, from the cases checked in the tests, so it may differ from it in some cases.
+//! hq3x and hq4x aren't implemented.
+
+use super::channels;
+
+/// Maximum differences (in Y, U, and V) for colors to be considered similar.
+const THRESHOLD_Y: i32 = 0x30;
+const THRESHOLD_U: i32 = 0x07;
+const THRESHOLD_V: i32 = 0x06;
+
+/// For each pattern, the rule for the top left output pixel (see [`apply_rule`]).
+///
+/// Bits of the pattern are set for neighbours that differ from the center. From the lowest
+/// bit: top left, top, top right, left, right, bottom left, bottom, bottom right.
+#[rustfmt::skip]
+const RULES: [u8; 256] = [
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  8,  6,  4,  2,  9,  7,
+    3,  3,  5, 10,  3,  3,  5, 10,  4,  2,  6,  6,  4,  2,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  9,  7,  4,  2,  9,  7,
+    3,  3,  5, 10,  3,  3,  5, 10,  4,  2,  6,  6,  4,  2,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4, 11,  6,  6,  4, 11,  6,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  6,  6,  4,  2,  6,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4, 11,  0,  6,  4, 11,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  6,  6,  4,  2,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  8,  6,  4,  2,  9,  7,
+    3,  3,  5, 10,  3,  3,  5, 10,  4,  2,  6,  6,  4,  2,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  9,  7,  4,  2,  9,  7,
+    3,  3,  5, 10,  3,  3,  5, 10,  4,  2,  6,  6,  4,  2,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4, 11,  6,  6,  4, 11,  6,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  6,  6,  4,  2,  6,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4, 11,  0,  6,  4, 11,  0,  6,
+    3,  3,  5,  1,  3,  3,  5,  1,  4,  2,  6,  6,  4,  2,  0,  6,
+];
+
+/// For each output pixel (top left, top right, bottom left, bottom right), the rotation of
+/// the neighbourhood: the index (in the 3x3 neighbourhood, row major) of the neighbour that
+/// takes the place of each neighbour of the top left output pixel.
+const ROTATIONS: [[usize; 9]; 4] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 5, 8, 1, 4, 7, 0, 3, 6],
+    [6, 3, 0, 7, 4, 1, 8, 5, 2],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+];
+
+/// Neighbourhood indices for each bit of the pattern.
+const PATTERN_BITS: [usize; 8] = [0, 1, 2, 3, 5, 6, 7, 8];
+
+/// Convert a color to YUV.
+fn yuv(color: u32) -> (i32, i32, i32) {
+    let (r, g, b) = channels(color);
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = (r * 299 + g * 587 + b * 114) / 1000;
+    let u = (-r * 169 - g * 331 + b * 500) / 1000 + 128;
+    let v = (r * 500 - g * 419 - b * 81) / 1000 + 128;
+    (y, u, v)
+}
+
+fn differ(a: u32, b: u32) -> bool {
+    if a == b {
+        return false;
+    }
+    let (y_a, u_a, v_a) = yuv(a);
+    let (y_b, u_b, v_b) = yuv(b);
+    (y_a - y_b).abs() > THRESHOLD_Y
+        || (u_a - u_b).abs() > THRESHOLD_U
+        || (v_a - v_b).abs() > THRESHOLD_V
+}
+
+/// Mix colors with integer weights, which add up to `1 << shift`.
+#[inline(always)]
+fn interpolate(colors: &[(u32, u32)], shift: u32) -> u32 {
+    let (mut r, mut g, mut b) = (0, 0, 0);
+    for &(color, weight) in colors {
+        let (r_c, g_c, b_c) = channels(color);
+        r += r_c * weight;
+        g += g_c * weight;
+        b += b_c * weight;
+    }
+    0xFF00_0000 | ((r >> shift) << 16) | ((g >> shift) << 8) | (b >> shift)
+}
+
+/// Compute the top left output pixel with a rule. `n` is the (rotated) neighbourhood.
+///
+/// The rules, with the names of the original's macros for the top left pixel (`E` is the
+/// center; `A`, `B`, and `D` are its top left, top, and left neighbours):
+/// - 0: `(3E + A) / 4` (10)
+/// - 1: `(3E + D) / 4` (11)
+/// - 2: `(3E + B) / 4` (12)
+/// - 3: `(2E + D + B) / 4` (20)
+/// - 4: `(2E + A + B) / 4` (21)
+/// - 5: `(2E + A + D) / 4` (22)
+/// - 6: if `B` and `D` are similar, rule 3, otherwise `E` (20 or 0)
+/// - 7: if `B` and `D` are similar, `(14E + D + B) / 16`, otherwise `E` (100 or 0)
+/// - 8: if `B` and `D` are similar, rule 3, otherwise rule 0 (20 or 10)
+/// - 9: if `B` and `D` are similar, `(2E + 3D + 3B) / 8`, otherwise rule 0 (90 or 10)
+/// - 10: if `B` and the right neighbour are similar, `(5E + 2B + D) / 8`, otherwise rule 1
+///   (60 or 11)
+/// - 11: if `D` and the bottom neighbour are similar, `(5E + 2D + B) / 8`, otherwise rule 2
+///   (61 or 12)
+fn apply_rule(rule: u8, n: &[u32; 9]) -> u32 {
+    let (a, b, d, e, f, h) = (n[0], n[1], n[3], n[4], n[5], n[7]);
+    match rule {
+        0 => interpolate(&[(e, 3), (a, 1)], 2),
+        1 => interpolate(&[(e, 3), (d, 1)], 2),
+        2 => interpolate(&[(e, 3), (b, 1)], 2),
+        3 => interpolate(&[(e, 2), (d, 1), (b, 1)], 2),
+        4 => interpolate(&[(e, 2), (a, 1), (b, 1)], 2),
+        5 => interpolate(&[(e, 2), (a, 1), (d, 1)], 2),
+        6 if differ(b, d) => e,
+        6 => apply_rule(3, n),
+        7 if differ(b, d) => e,
+        7 => interpolate(&[(e, 14), (d, 1), (b, 1)], 4),
+        8 if differ(b, d) => apply_rule(0, n),
+        8 => apply_rule(3, n),
+        9 if differ(b, d) => apply_rule(0, n),
+        9 => interpolate(&[(e, 2), (d, 3), (b, 3)], 3),
+        10 if differ(b, f) => apply_rule(1, n),
+        10 => interpolate(&[(e, 5), (b, 2), (d, 1)], 3),
+        11 if differ(d, h) => apply_rule(2, n),
+        11 => interpolate(&[(e, 5), (d, 2), (b, 1)], 3),
+        _ => unreachable!("invalid hq2x rule {}", rule),
+    }
+}
+
+/// Scale an image by 2x with hq2x.
+pub fn scale(input: &[u32], width: usize, height: usize, output: &mut [u32]) {
+    let pixel = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        input[y * width + x]
+    };
+    let out_width = width * 2;
+
+    for y in 0..height {
+        for x in 0..width {
+            let (xi, yi) = (x as isize, y as isize);
+            let mut neighbours = [0u32; 9];
+            for (i, neighbour) in neighbours.iter_mut().enumerate() {
+                *neighbour = pixel(xi + (i % 3) as isize - 1, yi + (i / 3) as isize - 1);
+            }
+            let center = neighbours[4];
+            let different = neighbours.map(|neighbour| differ(center, neighbour));
+
+            for (index, rotation) in ROTATIONS.iter().enumerate() {
+                let rotated = rotation.map(|i| neighbours[i]);
+                let pattern = PATTERN_BITS
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &i)| different[rotation[i]])
+                    .fold(0, |pattern, (bit, _)| pattern | (1 << bit));
+                let color = apply_rule(RULES[pattern], &rotated);
+                let (row, column) = (index / 2, index % 2);
+                output[(y * 2 + row) * out_width + x * 2 + column] = color;
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Software scaling filters for the frame buffer (for frontends without a GPU to run
+//! shaders on).
+//!
+//! Images are in the same format as [`crate::Gba::framebuffer`]: ARGB, row major.
+
+use std::ops::RangeInclusive;
+
+mod hqx;
+#[cfg(test)]
+mod tests;
+mod xbrz;
+
+/// A scaling filter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Repeat each pixel.
+    Nearest,
+    /// Zenju's xBRZ: smooths edges, while keeping pixel art sharp.
+    Xbrz,
+    /// Maxim Stepin's hq2x: smooths edges, blending them more than xBRZ.
+    Hqx,
+    /// Darken every other line, like a CRT.
+    Scanlines,
+    /// Darken the gaps between pixels, like the GBA's LCD.
+    LcdGrid,
+}
+
+impl Filter {
+    /// The scale factors this filter supports.
+    pub fn scales(self) -> RangeInclusive<usize> {
+        match self {
+            Filter::Nearest => 1..=8,
+            Filter::Xbrz => 2..=6,
+            Filter::Hqx => 2..=2,
+            Filter::Scanlines | Filter::LcdGrid => 2..=8,
+        }
+    }
+}
+
+/// Brightness (out of 256) of the darkened lines of [`Filter::Scanlines`] and
+/// [`Filter::LcdGrid`].
+const GRID_BRIGHTNESS: u32 = 160;
+
+/// Scales images with a filter.
+pub struct Scaler {
+    filter: Filter,
+    scale: usize,
+    output: Vec<u32>,
+}
+
+impl Scaler {
+    /// Create a scaler. Panics if the filter doesn't support the scale factor.
+    pub fn new(filter: Filter, scale: usize) -> Scaler {
+        assert!(
+            filter.scales().contains(&scale),
+            "{:?} doesn't support {}x scaling",
+            filter,
+            scale
+        );
+        Scaler {
+            filter,
+            scale,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn scale_factor(&self) -> usize {
+        self.scale
+    }
+
+    /// Scale an image. The output is (`width` * scale) by (`height` * scale).
+    pub fn scale(&mut self, input: &[u32], width: usize, height: usize) -> &[u32] {
+        assert_eq!(input.len(), width * height, "input is the wrong size");
+        let scale = self.scale;
+        self.output.resize(input.len() * scale * scale, 0);
+        let output = &mut self.output;
+        match self.filter {
+            Filter::Nearest => nearest(input, width, height, scale, output),
+            Filter::Xbrz => xbrz::scale(input, width, height, scale, output),
+            Filter::Hqx => hqx::scale(input, width, height, output),
+            Filter::Scanlines => {
+                nearest(input, width, height, scale, output);
+                darken(output, width * scale, scale, false);
+            }
+            Filter::LcdGrid => {
+                nearest(input, width, height, scale, output);
+                darken(output, width * scale, scale, true);
+            }
+        }
+        &self.output
+    }
+}
+
+/// Scale an image by repeating each pixel.
+fn nearest(input: &[u32], width: usize, height: usize, scale: usize, output: &mut [u32]) {
+    let out_width = width * scale;
+    for y in 0..height {
+        let row = &mut output[(y * scale * out_width)..((y * scale + 1) * out_width)];
+        for (x, &color) in input[(y * width)..((y + 1) * width)].iter().enumerate() {
+            row[(x * scale)..((x + 1) * scale)].fill(color);
+        }
+        for repeat in 1..scale {
+            output.copy_within(
+                (y * scale * out_width)..((y * scale + 1) * out_width),
+                (y * scale + repeat) * out_width,
+            );
+        }
+    }
+}
+
+/// Darken the last row (and column, for a grid) of each scaled pixel.
+fn darken(output: &mut [u32], out_width: usize, scale: usize, columns: bool) {
+    for (index, color) in output.iter_mut().enumerate() {
+        let (x, y) = (index % out_width, index / out_width);
+        if y % scale == scale - 1 || (columns && x % scale == scale - 1) {
+            *color = blend(*color, 0xFF00_0000, GRID_BRIGHTNESS, 256);
+        }
+    }
+}
+
+/// Split an ARGB color into its red, green, and blue channels.
+#[inline(always)]
+fn channels(color: u32) -> (u32, u32, u32) {
+    ((color >> 16) & 0xFF, (color >> 8) & 0xFF, color & 0xFF)
+}
+
+/// Blend two ARGB colors: `front` has a weight of `numerator` / `denominator`.
+#[inline(always)]
+fn blend(front: u32, back: u32, numerator: u32, denominator: u32) -> u32 {
+    let (r_f, g_f, b_f) = channels(front);
+    let (r_b, g_b, b_b) = channels(back);
+    let back_weight = denominator - numerator;
+    let r = (r_f * numerator + r_b * back_weight) / denominator;
+    let g = (g_f * numerator + g_b * back_weight) / denominator;
+    let b = (b_f * numerator + b_b * back_weight) / denominator;
+    0xFF00_0000 | (r << 16) | (g << 8) | b
+}
@@ -0,0 +1,155 @@
+//! Scaling filter tests: each filter's output at each scale it supports is checked
+//! against reference images in `testdata/video`.
+//!
+//! The reference images are snapshots of these filters' output, not of the original xBRZ
+//! and hq2x implementations (which weren't available to compare with), so they only catch
+//! regressions. hq2x is also checked against some cases of the original's switch statement.
+
+use super::{hqx, Filter, Scaler};
+use crate::test_images::assert_reference_image;
+
+const WIDTH: usize = 24;
+const HEIGHT: usize = 16;
+
+/// A small test image, with the sorts of things the filters treat differently: curved
+/// and diagonal edges, single-pixel lines, a checkerboard, and a gradient.
+fn test_image() -> Vec<u32> {
+    let mut image = vec![0xFF88_CCFF; WIDTH * HEIGHT];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let (dx, dy) = (x as i32 - 7, y as i32 - 7);
+            let color = if dx * dx + dy * dy <= 36 {
+                // Disc, with a highlight.
+                if dx < -1 && dy < -1 {
+                    0xFFFF_A0A0
+                } else {
+                    0xFFD0_2020
+                }
+            } else if x >= 14 && x - 14 == y {
+                // Diagonal line.
+                0xFF00_0000
+            } else if x >= 16 && y >= 10 {
+                // Checkerboard.
+                if (x + y) % 2 == 0 {
+                    0xFFFF_FFFF
+                } else {
+                    0xFF20_A040
+                }
+            } else if y == HEIGHT - 1 {
+                // Gray gradient.
+                0xFF00_0000 | 0x0001_0101 * (x as u32 * 16)
+            } else {
+                continue;
+            };
+            image[y * WIDTH + x] = color;
+        }
+    }
+    image
+}
+
+/// Check a filter at every scale it supports.
+fn check_filter(filter: Filter, name: &str) {
+    let input = test_image();
+    for scale in filter.scales() {
+        let mut scaler = Scaler::new(filter, scale);
+        let output = scaler.scale(&input, WIDTH, HEIGHT);
+        let name = format!("video/{}_{}x", name, scale);
+        assert_reference_image(&name, output, WIDTH * scale, HEIGHT * scale);
+    }
+}
+
+#[test]
+fn nearest() {
+    let input = test_image();
+    let mut scaler = Scaler::new(Filter::Nearest, 1);
+    assert_eq!(scaler.scale(&input, WIDTH, HEIGHT), &input[..]);
+    check_filter(Filter::Nearest, "nearest");
+}
+
+#[test]
+fn xbrz() {
+    check_filter(Filter::Xbrz, "xbrz");
+}
+
+#[test]
+fn hqx() {
+    check_filter(Filter::Hqx, "hqx");
+}
+
+#[test]
+fn hqx_cases() {
+    const E: u32 = 0xFF00_0000;
+    const X: u32 = 0xFFFF_FFFF;
+    const RED: u32 = 0xFFFF_0000;
+    let gray = |level: u32| Some(0xFF00_0000 | 0x0001_0101 * level);
+    // Neighbourhoods (numbered from 1 to 9, row major, with the center at 5), and the
+    // original's output for them (None for pixels that aren't checked). E, X, and RED all
+    // differ from each other.
+    let cases = [
+        // Case 19: PIXEL00_60, PIXEL01_90, PIXEL10_20, PIXEL11_21.
+        (
+            [X, X, E, E, E, X, E, E, E],
+            [gray(63), gray(191), Some(E), Some(E)],
+        ),
+        // Case 26: PIXEL00_20, PIXEL01_20, PIXEL10_22, PIXEL11_21.
+        (
+            [E, X, E, X, E, X, E, E, E],
+            [gray(127), gray(127), Some(E), Some(E)],
+        ),
+        // Case 26, with 2 and 4 different: PIXEL00_0.
+        (
+            [E, X, E, RED, E, X, E, E, E],
+            [Some(E), gray(127), Some(E), Some(E)],
+        ),
+        // Case 30: PIXEL00_10, PIXEL01_20, PIXEL10_22, PIXEL11_21.
+        (
+            [E, X, X, X, E, X, E, E, E],
+            [Some(E), gray(127), Some(E), Some(E)],
+        ),
+        // Case 47: PIXEL00_100.
+        ([X, X, X, X, E, E, X, E, E], [gray(31), None, None, None]),
+        // Case 90: PIXEL00_20, PIXEL01_20, PIXEL10_20, PIXEL11_20.
+        ([E, X, E, X, E, X, E, X, E], [gray(127); 4]),
+        // Case 126 (a diagonal line): PIXEL00_10, PIXEL01_20, PIXEL10_20, PIXEL11_10.
+        (
+            [E, X, X, X, E, X, X, X, E],
+            [Some(E), gray(127), gray(127), Some(E)],
+        ),
+    ];
+    for (input, expected) in cases {
+        let mut output = [0; 36];
+        hqx::scale(&input, 3, 3, &mut output);
+        let block = [output[14], output[15], output[20], output[21]];
+        for (&actual, expected) in block.iter().zip(expected) {
+            if let Some(expected) = expected {
+                assert_eq!(actual, expected, "neighbourhood {:08X?}", input);
+            }
+        }
+    }
+}
+
+#[test]
+fn scanlines() {
+    check_filter(Filter::Scanlines, "scanlines");
+}
+
+#[test]
+fn lcd_grid() {
+    check_filter(Filter::LcdGrid, "lcd_grid");
+}
+
+#[test]
+fn scaler_reused_for_another_image() {
+    // The output buffer is reused, so a second image shouldn't be affected by the first.
+    let input = test_image();
+    let mut scaler = Scaler::new(Filter::Xbrz, 3);
+    scaler.scale(&[0xFF00_0000; 4], 2, 2);
+    let output = scaler.scale(&input, WIDTH, HEIGHT);
+    assert_reference_image("video/xbrz_3x", output, WIDTH * 3, HEIGHT * 3);
+}
+
+#[test]
+#[should_panic(expected = "doesn't support 3x scaling")]
+fn unsupported_scale() {
+    Scaler::new(Filter::Hqx, 3);
+}
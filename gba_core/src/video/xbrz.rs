@@ -0,0 +1,392 @@
+//! xBRZ, by Zenju: an edge-directed scaler for pixel art (2x to 6x).
+//!
+//! First, each 2x2 group of pixels is checked for a diagonal edge through it, and which of
+//! its corners should be blended. Then every pixel is scaled up, and each of its four
+//! corners (by rotating the neighbourhood) is blended with the most similar neighbour,
+//! along a shallow, steep, or diagonal line, or as a rounded corner.
+
+use super::{blend, channels};
+
+/// Colors closer than this are considered equal.
+const EQUAL_COLOR_TOLERANCE: f64 = 30.0;
+
+/// Weight of the center gradient when finding edges.
+const CENTER_DIRECTION_BIAS: f64 = 4.0;
+
+/// How much stronger one gradient must be than the other for a "dominant" edge.
+const DOMINANT_DIRECTION_THRESHOLD: f64 = 3.6;
+
+/// How much stronger one line must be than the other to be steep (or shallow).
+const STEEP_DIRECTION_THRESHOLD: f64 = 2.2;
+
+const BLEND_NONE: u8 = 0;
+const BLEND_NORMAL: u8 = 1;
+const BLEND_DOMINANT: u8 = 2;
+
+/// A blend operation on an output pixel: (row, column, weight numerator, weight denominator).
+/// The pixel is blended with the new color, with the given weight.
+type Op = (usize, usize, u32, u32);
+
+/// The blends for a corner (in the bottom right, when not rotated) for one scale.
+struct Blends {
+    /// A shallow line (blend along the bottom rows). Steep lines are the transpose.
+    shallow: &'static [Op],
+    steep_and_shallow: &'static [Op],
+    diagonal: &'static [Op],
+    corner: &'static [Op],
+}
+
+const BLENDS_2X: Blends = Blends {
+    shallow: &[(1, 0, 1, 4), (1, 1, 3, 4)],
+    steep_and_shallow: &[(1, 0, 1, 4), (0, 1, 1, 4), (1, 1, 5, 6)],
+    diagonal: &[(1, 1, 1, 2)],
+    corner: &[(1, 1, 21, 100)],
+};
+
+const BLENDS_3X: Blends = Blends {
+    shallow: &[(2, 0, 1, 4), (1, 2, 1, 4), (2, 1, 3, 4), (2, 2, 1, 1)],
+    steep_and_shallow: &[
+        (2, 0, 1, 4),
+        (0, 2, 1, 4),
+        (2, 1, 3, 4),
+        (1, 2, 3, 4),
+        (2, 2, 1, 1),
+    ],
+    diagonal: &[(1, 2, 1, 8), (2, 1, 1, 8), (2, 2, 7, 8)],
+    corner: &[(2, 2, 45, 100)],
+};
+
+const BLENDS_4X: Blends = Blends {
+    shallow: &[
+        (3, 0, 1, 4),
+        (2, 2, 1, 4),
+        (3, 1, 3, 4),
+        (2, 3, 3, 4),
+        (3, 2, 1, 1),
+        (3, 3, 1, 1),
+    ],
+    steep_and_shallow: &[
+        (3, 1, 3, 4),
+        (1, 3, 3, 4),
+        (3, 0, 1, 4),
+        (0, 3, 1, 4),
+        (2, 2, 1, 3),
+        (3, 3, 1, 1),
+        (3, 2, 1, 1),
+        (2, 3, 1, 1),
+    ],
+    diagonal: &[(3, 2, 1, 2), (2, 3, 1, 2), (3, 3, 1, 1)],
+    corner: &[(3, 3, 68, 100), (3, 2, 9, 100), (2, 3, 9, 100)],
+};
+
+const BLENDS_5X: Blends = Blends {
+    shallow: &[
+        (4, 0, 1, 4),
+        (3, 2, 1, 4),
+        (2, 4, 1, 4),
+        (4, 1, 3, 4),
+        (3, 3, 3, 4),
+        (4, 2, 1, 1),
+        (4, 3, 1, 1),
+        (4, 4, 1, 1),
+        (3, 4, 1, 1),
+    ],
+    steep_and_shallow: &[
+        (0, 4, 1, 4),
+        (2, 3, 1, 4),
+        (1, 4, 3, 4),
+        (4, 0, 1, 4),
+        (3, 2, 1, 4),
+        (4, 1, 3, 4),
+        (3, 3, 2, 3),
+        (2, 4, 1, 1),
+        (3, 4, 1, 1),
+        (4, 4, 1, 1),
+        (4, 2, 1, 1),
+        (4, 3, 1, 1),
+    ],
+    diagonal: &[
+        (4, 2, 1, 8),
+        (3, 3, 1, 8),
+        (2, 4, 1, 8),
+        (4, 3, 7, 8),
+        (3, 4, 7, 8),
+        (4, 4, 1, 1),
+    ],
+    corner: &[(4, 4, 86, 100), (4, 3, 23, 100), (3, 4, 23, 100)],
+};
+
+const BLENDS_6X: Blends = Blends {
+    shallow: &[
+        (5, 0, 1, 4),
+        (4, 2, 1, 4),
+        (3, 4, 1, 4),
+        (5, 1, 3, 4),
+        (4, 3, 3, 4),
+        (3, 5, 3, 4),
+        (5, 2, 1, 1),
+        (5, 3, 1, 1),
+        (5, 4, 1, 1),
+        (5, 5, 1, 1),
+        (4, 4, 1, 1),
+        (4, 5, 1, 1),
+    ],
+    steep_and_shallow: &[
+        (0, 5, 1, 4),
+        (2, 4, 1, 4),
+        (1, 5, 3, 4),
+        (3, 4, 3, 4),
+        (5, 0, 1, 4),
+        (4, 2, 1, 4),
+        (5, 1, 3, 4),
+        (4, 3, 3, 4),
+        (2, 5, 1, 1),
+        (3, 5, 1, 1),
+        (4, 5, 1, 1),
+        (5, 5, 1, 1),
+        (4, 4, 1, 1),
+        (5, 4, 1, 1),
+        (5, 2, 1, 1),
+        (5, 3, 1, 1),
+    ],
+    diagonal: &[
+        (5, 3, 1, 2),
+        (4, 4, 1, 2),
+        (3, 5, 1, 2),
+        (4, 5, 1, 1),
+        (5, 5, 1, 1),
+        (5, 4, 1, 1),
+    ],
+    corner: &[
+        (5, 5, 97, 100),
+        (4, 5, 42, 100),
+        (5, 4, 42, 100),
+        (5, 3, 6, 100),
+        (3, 5, 6, 100),
+    ],
+};
+
+/// Distance between two colors, in YCbCr.
+fn distance(a: u32, b: u32) -> f64 {
+    let (r_a, g_a, b_a) = channels(a);
+    let (r_b, g_b, b_b) = channels(b);
+    let r = r_a as f64 - r_b as f64;
+    let g = g_a as f64 - g_b as f64;
+    let b = b_a as f64 - b_b as f64;
+
+    const K_B: f64 = 0.0593;
+    const K_R: f64 = 0.2627;
+    const K_G: f64 = 1.0 - K_B - K_R;
+    let y = K_R * r + K_G * g + K_B * b;
+    let c_b = 0.5 / (1.0 - K_B) * (b - y);
+    let c_r = 0.5 / (1.0 - K_R) * (r - y);
+    (y * y + c_b * c_b + c_r * c_r).sqrt()
+}
+
+fn equal(a: u32, b: u32) -> bool {
+    distance(a, b) < EQUAL_COLOR_TOLERANCE
+}
+
+/// Corner blending state for each pixel: 2 bits each for the top left, top right,
+/// bottom right, and bottom left corners (from the low bits).
+fn blend_info(input: &[u32], width: usize, height: usize) -> Vec<u8> {
+    let pixel = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        input[y * width + x]
+    };
+
+    let mut info = vec![0u8; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            // Check the corners between F, G, J, and K (F is the current pixel).
+            //  A B C D
+            //  E F G H
+            //  I J K L
+            //  M N O P
+            let (x, y) = (x as isize, y as isize);
+            let [b, c] = [pixel(x, y - 1), pixel(x + 1, y - 1)];
+            let [e, f, g, h] = [0, 1, 2, 3].map(|dx| pixel(x - 1 + dx, y));
+            let [i, j, k, l] = [0, 1, 2, 3].map(|dx| pixel(x - 1 + dx, y + 1));
+            let [n, o] = [pixel(x, y + 2), pixel(x + 1, y + 2)];
+
+            if (f == g && j == k) || (f == j && g == k) {
+                continue;
+            }
+            let jg = distance(i, f)
+                + distance(f, c)
+                + distance(n, k)
+                + distance(k, h)
+                + CENTER_DIRECTION_BIAS * distance(j, g);
+            let fk = distance(e, j)
+                + distance(j, o)
+                + distance(b, g)
+                + distance(g, l)
+                + CENTER_DIRECTION_BIAS * distance(f, k);
+
+            let (mut blend_f, mut blend_g, mut blend_j, mut blend_k) = (0, 0, 0, 0);
+            if jg < fk {
+                let strength = if DOMINANT_DIRECTION_THRESHOLD * jg < fk {
+                    BLEND_DOMINANT
+                } else {
+                    BLEND_NORMAL
+                };
+                if f != g && f != j {
+                    blend_f = strength;
+                }
+                if k != j && k != g {
+                    blend_k = strength;
+                }
+            } else if fk < jg {
+                let strength = if DOMINANT_DIRECTION_THRESHOLD * fk < jg {
+                    BLEND_DOMINANT
+                } else {
+                    BLEND_NORMAL
+                };
+                if j != f && j != k {
+                    blend_j = strength;
+                }
+                if g != f && g != k {
+                    blend_g = strength;
+                }
+            }
+
+            let (x, y) = (x as usize, y as usize);
+            info[y * width + x] |= blend_f << 4;
+            if x + 1 < width {
+                info[y * width + x + 1] |= blend_g << 6;
+            }
+            if y + 1 < height {
+                info[(y + 1) * width + x] |= blend_j << 2;
+                if x + 1 < width {
+                    info[(y + 1) * width + x + 1] |= blend_k;
+                }
+            }
+        }
+    }
+    info
+}
+
+/// Scale an image with xBRZ. `scale` must be 2 to 6.
+pub fn scale(input: &[u32], width: usize, height: usize, scale: usize, output: &mut [u32]) {
+    let blends = match scale {
+        2 => &BLENDS_2X,
+        3 => &BLENDS_3X,
+        4 => &BLENDS_4X,
+        5 => &BLENDS_5X,
+        6 => &BLENDS_6X,
+        _ => panic!("xBRZ doesn't support {}x scaling", scale),
+    };
+    let info = blend_info(input, width, height);
+    let pixel = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        input[y * width + x]
+    };
+    let out_width = width * scale;
+    let mut block = vec![0u32; scale * scale];
+
+    for y in 0..height {
+        for x in 0..width {
+            let (xi, yi) = (x as isize, y as isize);
+            let mut kernel = [0u32; 9];
+            for (index, value) in kernel.iter_mut().enumerate() {
+                *value = pixel(xi - 1 + (index % 3) as isize, yi - 1 + (index / 3) as isize);
+            }
+            block.fill(kernel[4]);
+
+            // Blend each corner, by rotating the kernel (and the output) clockwise.
+            let mut blend = info[y * width + x];
+            for rotation in 0..4 {
+                blend_corner(&kernel, blend, blends, scale, rotation, &mut block);
+                kernel = rotate(&kernel);
+                blend = blend.rotate_left(2);
+            }
+
+            for row in 0..scale {
+                let start = (y * scale + row) * out_width + x * scale;
+                output[start..(start + scale)].copy_from_slice(&block[(row * scale)..][..scale]);
+            }
+        }
+    }
+}
+
+/// Rotate a 3x3 kernel 90 degrees clockwise.
+fn rotate(kernel: &[u32; 9]) -> [u32; 9] {
+    let [a, b, c, d, e, f, g, h, i] = *kernel;
+    [g, d, a, h, e, b, i, f, c]
+}
+
+/// Blend the bottom right corner of a pixel (after rotating by 90 degrees `rotation` times).
+fn blend_corner(
+    kernel: &[u32; 9],
+    blend_info: u8,
+    blends: &Blends,
+    scale: usize,
+    rotation: usize,
+    block: &mut [u32],
+) {
+    //  A B C
+    //  D E F
+    //  G H I
+    let [_, b, c, d, e, f, g, h, i] = *kernel;
+    let bottom_right = (blend_info >> 4) & 3;
+    let top_right = (blend_info >> 2) & 3;
+    let bottom_left = (blend_info >> 6) & 3;
+    if bottom_right < BLEND_NORMAL {
+        return;
+    }
+
+    let line_blend = if bottom_right >= BLEND_DOMINANT {
+        true
+    } else if top_right != BLEND_NONE && !equal(e, g) {
+        // Don't blend a line when an adjacent corner is blended too (e.g. single pixels).
+        false
+    } else if bottom_left != BLEND_NONE && !equal(e, c) {
+        false
+    } else {
+        // No line blending for L-shapes: just the corner.
+        !(!equal(e, i) && equal(g, h) && equal(h, i) && equal(i, f) && equal(f, c))
+    };
+
+    // Blend with the most similar color.
+    let color = if distance(e, f) <= distance(e, h) {
+        f
+    } else {
+        h
+    };
+
+    let mut apply = |ops: &[Op], transpose: bool| {
+        for &(row, column, numerator, denominator) in ops {
+            let (row, column) = if transpose {
+                (column, row)
+            } else {
+                (row, column)
+            };
+            // Undo the rotation to find the output pixel.
+            let (mut row, mut column) = (row, column);
+            for _ in 0..rotation {
+                let old_row = scale - 1 - column;
+                column = row;
+                row = old_row;
+            }
+            let out = &mut block[row * scale + column];
+            *out = blend(color, *out, numerator, denominator);
+        }
+    };
+
+    if line_blend {
+        let fg = distance(f, g);
+        let hc = distance(h, c);
+        let shallow = STEEP_DIRECTION_THRESHOLD * fg <= hc && e != g && d != g;
+        let steep = STEEP_DIRECTION_THRESHOLD * hc <= fg && e != c && b != c;
+        match (shallow, steep) {
+            (true, true) => apply(blends.steep_and_shallow, false),
+            (true, false) => apply(blends.shallow, false),
+            (false, true) => apply(blends.shallow, true),
+            (false, false) => apply(blends.diagonal, false),
+        }
+    } else {
+        apply(blends.corner, false);
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs;
+
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use clap::{ArgEnum, Parser, Subcommand};
+use gba_core::{BackupType, Rom};
+
+/// Size of the RTC footer that mGBA (and others) append to save files.
+const RTC_FOOTER_SIZE: usize = 16;
+
+/// GBA save file tool: inspect and convert cartridge saves between emulators.
+#[derive(Parser, Debug)]
+#[clap(about, long_about = None)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print information about a save file.
+    Info {
+        /// Path of the save file.
+        save_path: String,
+
+        /// Path of the ROM the save belongs to (used to detect the backup type).
+        #[clap(long)]
+        rom_path: Option<String>,
+    },
+
+    /// Convert a save file to another format.
+    Convert {
+        /// Path of the save file to read.
+        input_path: String,
+
+        /// Path of the save file to write.
+        output_path: String,
+
+        /// Backup type of the output. Detected from the input if not given.
+        #[clap(long, arg_enum)]
+        backup_type: Option<SaveType>,
+
+        /// Path of the ROM the save belongs to (used to detect the backup type).
+        #[clap(long)]
+        rom_path: Option<String>,
+
+        /// Swap the byte order of each 64-bit EEPROM block.
+        #[clap(long)]
+        swap_eeprom: bool,
+
+        /// What to do with the RTC footer.
+        #[clap(long, arg_enum, default_value = "keep")]
+        rtc_footer: RtcFooter,
+    },
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug)]
+enum SaveType {
+    Eeprom512,
+    Eeprom8k,
+    Sram,
+    Flash64k,
+    Flash128k,
+}
+
+impl From<SaveType> for BackupType {
+    fn from(save_type: SaveType) -> BackupType {
+        match save_type {
+            SaveType::Eeprom512 => BackupType::Eeprom512,
+            SaveType::Eeprom8k => BackupType::Eeprom8K,
+            SaveType::Sram => BackupType::Sram,
+            SaveType::Flash64k => BackupType::Flash64K,
+            SaveType::Flash128k => BackupType::Flash128K,
+        }
+    }
+}
+
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq)]
+enum RtcFooter {
+    /// Keep the footer if there is one.
+    Keep,
+    /// Remove the footer.
+    Strip,
+    /// Add a footer with the current time (replacing an existing one).
+    Append,
+}
+
+/// A save file, split into the backup data and the (optional) RTC footer.
+struct SaveFile {
+    data: Vec<u8>,
+    footer: Option<Vec<u8>>,
+}
+
+impl SaveFile {
+    /// Split the raw contents of a save file.
+    fn parse(mut data: Vec<u8>) -> SaveFile {
+        let footer = match data.len().checked_sub(RTC_FOOTER_SIZE) {
+            Some(len) if BackupType::from_save_size(len).is_some() => Some(data.split_off(len)),
+            _ => None,
+        };
+        SaveFile { data, footer }
+    }
+
+    /// The backup type suggested by the size of the data.
+    fn guess_type(&self) -> Option<BackupType> {
+        BackupType::from_save_size(self.data.len())
+    }
+}
+
+/// Detect the backup type of a ROM.
+fn rom_backup_type(rom_path: &str) -> Result<BackupType, String> {
+    let rom_data = fs::read(rom_path).map_err(|e| format!("failed to read ROM: {}", e))?;
+    let rom = Rom::new(&rom_data);
+    Ok(BackupType::detect(&rom))
+}
+
+/// Convert a packed BCD byte to binary.
+fn from_bcd(value: u8) -> u32 {
+    ((value >> 4) as u32) * 10 + ((value & 0xF) as u32)
+}
+
+/// Convert a binary value (0-99) to a packed BCD byte.
+fn to_bcd(value: u32) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+/// Build an RTC footer for the current time.
+///
+/// Layout (mGBA): 7 BCD bytes (year, month, day, day of week, hour, minute, second),
+/// the RTC control register, and the 64-bit Unix time the clock was latched.
+fn make_rtc_footer() -> Vec<u8> {
+    let now = Local::now();
+    let mut footer = vec![
+        to_bcd((now.year() % 100) as u32),
+        to_bcd(now.month()),
+        to_bcd(now.day()),
+        to_bcd(now.weekday().num_days_from_sunday()),
+        to_bcd(now.hour()),
+        to_bcd(now.minute()),
+        to_bcd(now.second()),
+        // 24-hour mode.
+        0x40,
+    ];
+    footer.extend_from_slice(&(now.timestamp() as u64).to_le_bytes());
+    footer
+}
+
+/// Describe an RTC footer.
+fn describe_rtc_footer(footer: &[u8]) -> String {
+    let latched = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+    let latched = match Local.timestamp_opt(latched as i64, 0).single() {
+        Some(time) => time.to_string(),
+        None => format!("invalid ({})", latched),
+    };
+    format!(
+        "20{:02}-{:02}-{:02} {:02}:{:02}:{:02} (control={:02X}, saved at {})",
+        from_bcd(footer[0]),
+        from_bcd(footer[1]),
+        from_bcd(footer[2]),
+        from_bcd(footer[4]),
+        from_bcd(footer[5]),
+        from_bcd(footer[6]),
+        footer[7],
+        latched
+    )
+}
+
+/// Reverse the byte order of each 64-bit EEPROM block.
+///
+/// We (like mGBA and VBA-M) store each block in the order it's sent over the serial bus,
+/// but some tools and flash carts store them as little-endian 64-bit words.
+fn swap_eeprom_blocks(data: &mut [u8]) {
+    for block in data.chunks_mut(8) {
+        block.reverse();
+    }
+}
+
+fn info(save_path: &str, rom_path: Option<&str>) -> Result<(), String> {
+    let data = fs::read(save_path).map_err(|e| format!("failed to read save: {}", e))?;
+    let total_len = data.len();
+    let save = SaveFile::parse(data);
+
+    println!("File size: {} bytes (0x{:X})", total_len, total_len);
+    match &save.footer {
+        Some(footer) => println!("RTC footer: {}", describe_rtc_footer(footer)),
+        None => println!("RTC footer: none"),
+    }
+
+    let rom_type = rom_path.map(rom_backup_type).transpose()?;
+    match save.guess_type() {
+        Some(backup_type) => println!("Likely backup type: {:?}", backup_type),
+        None => println!("Likely backup type: unknown (unusual size)"),
+    }
+    if let Some(rom_type) = rom_type {
+        println!("ROM backup type: {:?}", rom_type);
+        let expected = rom_type.save_size();
+        if expected.is_some() && expected != Some(save.data.len()) {
+            println!("Warning: save size doesn't match the ROM's backup type");
+        }
+    }
+
+    if save.data.iter().all(|&b| b == 0xFF) {
+        println!("Contents: blank (all 0xFF)");
+    } else if save.data.iter().all(|&b| b == 0x00) {
+        println!("Contents: blank (all 0x00)");
+    }
+    Ok(())
+}
+
+fn convert(
+    input_path: &str,
+    output_path: &str,
+    backup_type: Option<BackupType>,
+    rom_path: Option<&str>,
+    swap_eeprom: bool,
+    rtc_footer: RtcFooter,
+) -> Result<(), String> {
+    let data = fs::read(input_path).map_err(|e| format!("failed to read save: {}", e))?;
+    let mut save = SaveFile::parse(data);
+
+    let rom_type = rom_path
+        .map(rom_backup_type)
+        .transpose()?
+        .filter(|t| t.save_size().is_some());
+    let backup_type = backup_type
+        .or(rom_type)
+        .or_else(|| save.guess_type())
+        .ok_or("couldn't detect the backup type, use --backup-type")?;
+    let size = backup_type.save_size().unwrap();
+
+    if swap_eeprom {
+        if !backup_type.is_eeprom() {
+            return Err(format!("--swap-eeprom used with {:?}", backup_type));
+        }
+        swap_eeprom_blocks(&mut save.data);
+    }
+
+    // Pad with erased bytes (or truncate) to the size of the backup.
+    if save.data.len() != size {
+        eprintln!(
+            "Resizing from {} to {} bytes for {:?}",
+            save.data.len(),
+            size,
+            backup_type
+        );
+        save.data.resize(size, 0xFF);
+    }
+
+    match rtc_footer {
+        RtcFooter::Keep => {}
+        RtcFooter::Strip => save.footer = None,
+        RtcFooter::Append => save.footer = Some(make_rtc_footer()),
+    }
+
+    let mut output = save.data;
+    if let Some(footer) = save.footer {
+        output.extend_from_slice(&footer);
+    }
+    fs::write(output_path, &output).map_err(|e| format!("failed to write save: {}", e))?;
+    println!("Wrote {} bytes to {}", output.len(), output_path);
+    Ok(())
+}
+
+fn main() -> Result<(), String> {
+    let args = Args::parse();
+    match args.command {
+        Command::Info {
+            save_path,
+            rom_path,
+        } => info(&save_path, rom_path.as_deref()),
+        Command::Convert {
+            input_path,
+            output_path,
+            backup_type,
+            rom_path,
+            swap_eeprom,
+            rtc_footer,
+        } => convert(
+            &input_path,
+            &output_path,
+            backup_type.map(BackupType::from),
+            rom_path.as_deref(),
+            swap_eeprom,
+            rtc_footer,
+        ),
+    }
+}
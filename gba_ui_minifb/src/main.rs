@@ -25,7 +25,8 @@ fn make_gba() -> Gba {
     println!("Loaded {:?}", rom);
 
     let backup_path = format!("{}.sav", rom_path);
-    let backup_file = gba_core::util::make_backup_file(backup_path);
+    let backup_file =
+        gba_core::util::make_backup_file(backup_path).expect("failed to read cartridge save");
 
     gba_core::Gba::builder(bios.into(), rom)
         .skip_bios(true)
@@ -59,6 +60,9 @@ fn main() {
         if !window.is_open() || window.is_key_down(Key::Escape) {
             // User wants to exit.
             println!("Exiting.");
+            if let Err(e) = gba.flush_backup() {
+                eprintln!("Failed to write cartridge save: {}", e);
+            }
             break;
         }
         if window.is_key_pressed(Key::Space, KeyRepeat::No) {
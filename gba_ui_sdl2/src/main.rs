@@ -5,7 +5,10 @@ use std::{
 };
 
 use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
-use gba_core::{Gba, KeypadState, AUDIO_CHANNELS, AUDIO_SAMPLE_RATE};
+use gba_core::{
+    video::{Filter, Scaler},
+    ColorProfile, FrameBlending, Gba, KeypadState, AUDIO_CHANNELS, AUDIO_SAMPLE_RATE,
+};
 
 use sdl2::keyboard::{Keycode, Mod, Scancode};
 use sdl2::pixels::Color;
@@ -14,7 +17,7 @@ const WIDTH: u32 = gba_core::WIDTH as u32;
 const HEIGHT: u32 = gba_core::HEIGHT as u32;
 const SCALE: u32 = 2;
 
-use clap::Parser;
+use clap::{ArgEnum, Parser};
 
 /// GBA Emulator
 #[derive(Parser, Debug)]
@@ -30,6 +33,58 @@ struct Args {
     /// Whether to skip the BIOS boot animation
     #[clap(long)]
     skip_bios: bool,
+
+    /// Color correction, to look like a particular screen.
+    #[clap(long, arg_enum, default_value = "raw")]
+    color_profile: ColorProfileArg,
+
+    /// Software scaling filter (otherwise, the screen is just stretched).
+    #[clap(long, arg_enum)]
+    filter: Option<FilterArg>,
+
+    /// Scale factor for the filter.
+    #[clap(long, default_value_t = SCALE as usize)]
+    scale: usize,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum FilterArg {
+    Nearest,
+    Xbrz,
+    Hqx,
+    Scanlines,
+    LcdGrid,
+}
+
+impl From<FilterArg> for Filter {
+    fn from(arg: FilterArg) -> Self {
+        match arg {
+            FilterArg::Nearest => Filter::Nearest,
+            FilterArg::Xbrz => Filter::Xbrz,
+            FilterArg::Hqx => Filter::Hqx,
+            FilterArg::Scanlines => Filter::Scanlines,
+            FilterArg::LcdGrid => Filter::LcdGrid,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum ColorProfileArg {
+    Raw,
+    Gba,
+    GbaSp,
+    GameBoyPlayer,
+}
+
+impl From<ColorProfileArg> for ColorProfile {
+    fn from(arg: ColorProfileArg) -> Self {
+        match arg {
+            ColorProfileArg::Raw => ColorProfile::Raw,
+            ColorProfileArg::Gba => ColorProfile::Gba,
+            ColorProfileArg::GbaSp => ColorProfile::GbaSp101,
+            ColorProfileArg::GameBoyPlayer => ColorProfile::GameBoyPlayer,
+        }
+    }
 }
 
 fn get_keypad_state(event_pump: &sdl2::EventPump) -> KeypadState {
@@ -56,15 +111,38 @@ fn get_keypad_state(event_pump: &sdl2::EventPump) -> KeypadState {
     keypad
 }
 
-fn run_emulator(mut gba: Gba, base_path: &str) -> Result<(), String> {
+/// Toggle the layer bound to a number key: 1-4 for BG0-3, 5 for objects,
+/// 6 for windows, and 7 for blending.
+fn toggle_layer(gba: &mut Gba, code: Keycode) {
+    let mut mask = gba.layer_mask();
+    let (name, shown) = match code {
+        Keycode::Num1 => ("BG0", &mut mask.bg[0]),
+        Keycode::Num2 => ("BG1", &mut mask.bg[1]),
+        Keycode::Num3 => ("BG2", &mut mask.bg[2]),
+        Keycode::Num4 => ("BG3", &mut mask.bg[3]),
+        Keycode::Num5 => ("OBJ", &mut mask.obj),
+        Keycode::Num6 => ("Windows", &mut mask.windows),
+        Keycode::Num7 => ("Blending", &mut mask.blending),
+        _ => return,
+    };
+    *shown = !*shown;
+    println!("{}: {}", name, if *shown { "shown" } else { "hidden" });
+    gba.set_layer_mask(mask);
+}
+
+fn run_emulator(mut gba: Gba, base_path: &str, mut scaler: Option<Scaler>) -> Result<(), String> {
     let save_state_path = format!("{}.save_state", base_path);
+    let (scale, texture_scale) = match &scaler {
+        Some(scaler) => (scaler.scale_factor() as u32, scaler.scale_factor() as u32),
+        None => (SCALE, 1),
+    };
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
     let audio_subsystem = sdl_context.audio()?;
 
     let window = video_subsystem
-        .window("GBA", WIDTH * SCALE, HEIGHT * SCALE)
+        .window("GBA", WIDTH * scale, HEIGHT * scale)
         .opengl()
         .position_centered()
         .allow_highdpi()
@@ -79,7 +157,11 @@ fn run_emulator(mut gba: Gba, base_path: &str) -> Result<(), String> {
         .map_err(|e| e.to_string())?;
     let texture_creator = canvas.texture_creator();
     let mut texture = texture_creator
-        .create_texture_streaming(sdl2::pixels::PixelFormatEnum::ARGB8888, WIDTH, HEIGHT)
+        .create_texture_streaming(
+            sdl2::pixels::PixelFormatEnum::ARGB8888,
+            WIDTH * texture_scale,
+            HEIGHT * texture_scale,
+        )
         .map_err(|e| e.to_string())?;
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
@@ -165,6 +247,23 @@ fn run_emulator(mut gba: Gba, base_path: &str) -> Result<(), String> {
                                 Err(_) => println!("Nothing to load."),
                             }
                         }
+                        Keycode::B if command => {
+                            let blending = match gba.frame_blending() {
+                                FrameBlending::Off => FrameBlending::Average,
+                                _ => FrameBlending::Off,
+                            };
+                            gba.set_frame_blending(blending);
+                            println!("Frame blending: {:?}", blending);
+                        }
+                        Keycode::Num1
+                        | Keycode::Num2
+                        | Keycode::Num3
+                        | Keycode::Num4
+                        | Keycode::Num5
+                        | Keycode::Num6
+                        | Keycode::Num7 => {
+                            toggle_layer(&mut gba, code);
+                        }
                         _ => {}
                     }
                 }
@@ -187,10 +286,13 @@ fn run_emulator(mut gba: Gba, base_path: &str) -> Result<(), String> {
                 frame_counter += 1;
             }
 
-            let buffer = gba.framebuffer();
+            let mut buffer = gba.framebuffer();
+            if let Some(scaler) = scaler.as_mut() {
+                buffer = scaler.scale(buffer, WIDTH as usize, HEIGHT as usize);
+            }
             let buffer = unsafe { std::mem::transmute::<&[u32], &[u8]>(buffer) };
             texture
-                .update(None, buffer, (WIDTH * 4) as usize)
+                .update(None, buffer, (WIDTH * texture_scale * 4) as usize)
                 .map_err(|e| e.to_string())?;
             canvas.copy(&texture, None, None)?;
             canvas.present();
@@ -224,6 +326,8 @@ fn run_emulator(mut gba: Gba, base_path: &str) -> Result<(), String> {
         }
     }
 
+    gba.flush_backup()
+        .map_err(|e| format!("failed to write cartridge save: {}", e))?;
     Ok(())
 }
 
@@ -244,12 +348,29 @@ fn main() {
     };
     let backup_path = format!("{}.sav", base_path);
     println!("Using cartridge save path {}", backup_path);
-    let backup_file = gba_core::util::make_backup_file(backup_path);
+    let backup_file =
+        gba_core::util::make_backup_file(backup_path).expect("failed to read cartridge save");
+
+    let scaler = args.filter.map(|filter| {
+        let filter = Filter::from(filter);
+        let scales = filter.scales();
+        if !scales.contains(&args.scale) {
+            eprintln!(
+                "The {:?} filter only supports scales {} to {}",
+                filter,
+                scales.start(),
+                scales.end()
+            );
+            std::process::exit(1);
+        }
+        Scaler::new(filter, args.scale)
+    });
 
     let gba = gba_core::Gba::builder(bios.into(), rom)
         .skip_bios(args.skip_bios)
+        .color_profile(args.color_profile.into())
         .backup_file(backup_file)
         .build();
 
-    run_emulator(gba, base_path).unwrap();
+    run_emulator(gba, base_path, scaler).unwrap();
 }